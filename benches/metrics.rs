@@ -0,0 +1,59 @@
+//! Compares recording a frame through `PrometheusSink`, which caches its
+//! child gauges, against looking every child up by its labels.
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use pms7003::aqi::AqiStandard;
+use pms7003::metrics::{
+    set_gauge_checked, PrometheusSink, AIR_QUALITY_INDEX, PARTICLE_CONCENTRATION_ENVIRONMENT,
+    PARTICLE_CONCENTRATION_STANDARD, PARTICLE_COUNT,
+};
+use pms7003::sink::{record_reading, ConcentrationBasis, MetricsSink};
+use pms7003::{parse, PmsData};
+
+const GOLDEN_PACKET: &[u8] = &[
+    0x42, 0x4d, 0x00, 0x1c, 0x00, 0x03, 0x00, 0x04, 0x00, 0x07, 0x00, 0x03, 0x00, 0x04, 0x00, 0x07,
+    0x02, 0xd0, 0x00, 0xb8, 0x00, 0x19, 0x00, 0x08, 0x00, 0x04, 0x00, 0x02, 0x97, 0x00, 0x03, 0x0f,
+];
+
+/// What `PrometheusSink` did before caching.
+struct UncachedSink;
+
+impl MetricsSink for UncachedSink {
+    fn record_concentration(&self, basis: ConcentrationBasis, size: &str, value: f64) {
+        let vec = match basis {
+            ConcentrationBasis::Standard => &*PARTICLE_CONCENTRATION_STANDARD,
+            ConcentrationBasis::Environment => &*PARTICLE_CONCENTRATION_ENVIRONMENT,
+        };
+        set_gauge_checked(&vec.with_label_values(&[size]), value);
+    }
+
+    fn record_count(&self, size: &str, value: f64) {
+        set_gauge_checked(&PARTICLE_COUNT.with_label_values(&[size]), value);
+    }
+
+    fn record_aqi(&self, basis: ConcentrationBasis, size: &str, standard: &str, value: u32) {
+        AIR_QUALITY_INDEX
+            .with_label_values(&[size, standard, basis.label()])
+            .set(value.into());
+    }
+}
+
+fn golden_data() -> PmsData {
+    parse(GOLDEN_PACKET).unwrap().1.unwrap()
+}
+
+fn bench_record_reading(c: &mut Criterion) {
+    let data = golden_data();
+    let standards = [AqiStandard::UsEpa, AqiStandard::EuCaqi];
+    let mut group = c.benchmark_group("record_reading");
+    group.bench_function("uncached", |b| {
+        b.iter(|| record_reading(&UncachedSink, black_box(&data), &standards))
+    });
+    group.bench_function("cached", |b| {
+        b.iter(|| record_reading(&PrometheusSink, black_box(&data), &standards))
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_record_reading);
+criterion_main!(benches);
@@ -0,0 +1,88 @@
+//! Throughput of the read loop's parsing, fed from memory: back-to-back
+//! frames, and frames with as much noise in between, which has to be
+//! skipped to find the next start marker.
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use pms7003::{Config, Pms, Port};
+use std::io::{self, Read, Write};
+
+const GOLDEN_PACKET: &[u8] = &[
+    0x42, 0x4d, 0x00, 0x1c, 0x00, 0x03, 0x00, 0x04, 0x00, 0x07, 0x00, 0x03, 0x00, 0x04, 0x00, 0x07,
+    0x02, 0xd0, 0x00, 0xb8, 0x00, 0x19, 0x00, 0x08, 0x00, 0x04, 0x00, 0x02, 0x97, 0x00, 0x03, 0x0f,
+];
+const FRAMES: usize = 1000;
+
+/// Serves `input` a read buffer at a time, then times out.
+struct MemoryPort {
+    input: Vec<u8>,
+    position: usize,
+}
+
+impl Read for MemoryPort {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let remaining = &self.input[self.position..];
+        if remaining.is_empty() {
+            return Err(io::ErrorKind::TimedOut.into());
+        }
+        let n = buf.len().min(remaining.len());
+        buf[..n].copy_from_slice(&remaining[..n]);
+        self.position += n;
+        Ok(n)
+    }
+}
+
+impl Write for MemoryPort {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Port for MemoryPort {
+    fn bytes_to_read(&self) -> io::Result<u32> {
+        Ok((self.input.len() - self.position) as u32)
+    }
+}
+
+/// `FRAMES` golden packets, each followed by `noise` pseudo-random bytes.
+fn stream(noise: usize) -> Vec<u8> {
+    let mut state: u32 = 1;
+    let mut input = Vec::with_capacity(FRAMES * (GOLDEN_PACKET.len() + noise));
+    for _ in 0..FRAMES {
+        input.extend_from_slice(GOLDEN_PACKET);
+        for _ in 0..noise {
+            state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            input.push((state >> 16) as u8);
+        }
+    }
+    input
+}
+
+fn read_all(input: Vec<u8>) -> usize {
+    let port = MemoryPort { input, position: 0 };
+    let mut pms = Pms::with_port(Box::new(port), Config::default());
+    let mut frames = 0;
+    while pms.read_frame().unwrap().is_some() {
+        frames += 1;
+    }
+    frames
+}
+
+fn bench_parser(c: &mut Criterion) {
+    let mut group = c.benchmark_group("read_frame");
+    group.throughput(Throughput::Elements(FRAMES as u64));
+    for (name, noise) in [("aligned", 0), ("noisy", GOLDEN_PACKET.len())] {
+        let input = stream(noise);
+        assert!(read_all(input.clone()) >= FRAMES);
+        group.bench_function(name, |b| {
+            b.iter_batched(|| input.clone(), read_all, BatchSize::SmallInput)
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_parser);
+criterion_main!(benches);
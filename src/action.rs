@@ -0,0 +1,204 @@
+//! Switching external devices, e.g. an air purifier, on and off as the PM2.5
+//! concentration crosses thresholds.
+
+use crate::clock::{real_clock, Clock};
+use crate::PmsData;
+use log::{error, info};
+#[cfg(feature = "gpio")]
+use rppal::gpio::{Gpio, OutputPin};
+use std::process;
+use std::sync::Arc;
+#[cfg(feature = "gpio")]
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+/// When to switch, based on the PM2.5 concentration (CF=1 standard
+/// particle).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Thresholds {
+    /// Switch on once PM2.5 is at or above this (µg/m³).
+    pub on_at: f64,
+    /// Switch off once PM2.5 is at or below this. The gap to `on_at` is the
+    /// hysteresis, so it should be lower.
+    pub off_at: f64,
+    /// Stay on or off for at least this long before switching again, so that
+    /// a device isn't cycled rapidly when readings hover around a threshold.
+    pub min_dwell: Duration,
+}
+
+/// What to do when switching on or off.
+pub enum Action {
+    /// Call a closure with the reading that caused the switch.
+    Call(Box<dyn FnMut(&PmsData)>),
+    /// Run a command and wait for it to exit. The read loop is blocked
+    /// meanwhile, so it should be quick.
+    Run(process::Command),
+    /// Drive a GPIO output, e.g. a relay. See `gpio_actions`.
+    #[cfg(feature = "gpio")]
+    Gpio {
+        pin: Arc<Mutex<OutputPin>>,
+        high: bool,
+    },
+}
+
+impl Action {
+    pub fn call<F>(f: F) -> Self
+    where
+        F: FnMut(&PmsData) + 'static,
+    {
+        Action::Call(Box::new(f))
+    }
+
+    /// Failures are logged, since there is no one to return them to.
+    fn fire(&mut self, data: &PmsData) {
+        match self {
+            Action::Call(f) => f(data),
+            Action::Run(command) => match command.status() {
+                Ok(status) if status.success() => {}
+                Ok(status) => error!("{:?} failed: {}", command, status),
+                Err(e) => error!("Could not run {:?}: {}", command, e),
+            },
+            #[cfg(feature = "gpio")]
+            Action::Gpio { pin, high } => {
+                let mut pin = pin.lock().unwrap();
+                if *high {
+                    pin.set_high();
+                } else {
+                    pin.set_low();
+                }
+            }
+        }
+    }
+}
+
+/// Actions that set GPIO `pin` (BCM numbering) high to switch on and low to
+/// switch off.
+#[cfg(feature = "gpio")]
+pub fn gpio_actions(pin: u8) -> Result<(Action, Action), rppal::gpio::Error> {
+    let pin = Arc::new(Mutex::new(Gpio::new()?.get(pin)?.into_output_low()));
+    Ok((
+        Action::Gpio {
+            pin: Arc::clone(&pin),
+            high: true,
+        },
+        Action::Gpio { pin, high: false },
+    ))
+}
+
+/// The on/off state driven by `Thresholds`. Starts off.
+#[derive(Debug, Clone)]
+pub struct Switch {
+    thresholds: Thresholds,
+    on: bool,
+    last_change: Option<SystemTime>,
+}
+
+impl Switch {
+    pub fn new(thresholds: Thresholds) -> Self {
+        Switch {
+            thresholds,
+            on: false,
+            last_change: None,
+        }
+    }
+
+    pub fn is_on(&self) -> bool {
+        self.on
+    }
+
+    /// Adds a reading of `pm2_5` taken at `at`. Returns the new state if it
+    /// changed.
+    pub fn update(&mut self, at: SystemTime, pm2_5: f64) -> Option<bool> {
+        let crossed = if self.on {
+            pm2_5 <= self.thresholds.off_at
+        } else {
+            pm2_5 >= self.thresholds.on_at
+        };
+        let dwelled = self.last_change.is_none_or(|last_change| {
+            at.duration_since(last_change).unwrap_or_default() >= self.thresholds.min_dwell
+        });
+        if !crossed || !dwelled {
+            return None;
+        }
+        self.on = !self.on;
+        self.last_change = Some(at);
+        Some(self.on)
+    }
+}
+
+/// Returns a callback that fires `on` when PM2.5 rises to
+/// `thresholds.on_at`, and `off` when it falls back to `thresholds.off_at`.
+pub fn action_callback(thresholds: Thresholds, on: Action, off: Action) -> Box<dyn FnMut(PmsData)> {
+    action_callback_with_clock(thresholds, real_clock(), on, off)
+}
+
+/// Like `action_callback`, with `thresholds.min_dwell` measured by `clock`.
+pub fn action_callback_with_clock(
+    thresholds: Thresholds,
+    clock: Arc<dyn Clock>,
+    mut on: Action,
+    mut off: Action,
+) -> Box<dyn FnMut(PmsData)> {
+    let mut switch = Switch::new(thresholds);
+    Box::new(
+        move |data| match switch.update(clock.now(), data.pm2_5_cf1.into()) {
+            Some(true) => {
+                info!("PM2.5 is {}, switching on", data.pm2_5_cf1);
+                on.fire(&data);
+            }
+            Some(false) => {
+                info!("PM2.5 is {}, switching off", data.pm2_5_cf1);
+                off.fire(&data);
+            }
+            None => {}
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::at;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[test]
+    fn test_switch() {
+        let mut switch = Switch::new(Thresholds {
+            on_at: 35.0,
+            off_at: 20.0,
+            min_dwell: Duration::from_secs(60),
+        });
+        assert_eq!(switch.update(at(0), 30.0), None);
+        assert_eq!(switch.update(at(1), 35.0), Some(true));
+        // Within the hysteresis band.
+        assert_eq!(switch.update(at(100), 25.0), None);
+        assert!(switch.is_on());
+        assert_eq!(switch.update(at(200), 20.0), Some(false));
+        // Too soon after switching off.
+        assert_eq!(switch.update(at(230), 50.0), None);
+        assert_eq!(switch.update(at(260), 50.0), Some(true));
+    }
+
+    #[test]
+    fn test_action_callback() {
+        let events = Rc::new(RefCell::new(Vec::new()));
+        let (on_events, off_events) = (Rc::clone(&events), Rc::clone(&events));
+        let thresholds = Thresholds {
+            on_at: 10.0,
+            off_at: 5.0,
+            min_dwell: Duration::from_secs(0),
+        };
+        let mut callback = action_callback(
+            thresholds,
+            Action::call(move |data| on_events.borrow_mut().push(("on", data.pm2_5_cf1))),
+            Action::call(move |data| off_events.borrow_mut().push(("off", data.pm2_5_cf1))),
+        );
+        for pm2_5 in [4, 12, 15, 7, 5, 11] {
+            let mut words = [0; 13];
+            words[1] = pm2_5;
+            callback(PmsData::from(words));
+        }
+        assert_eq!(*events.borrow(), [("on", 12), ("off", 5), ("on", 11)]);
+    }
+}
@@ -0,0 +1,451 @@
+//! Air quality index (AQI) calculation as defined by the United States
+//! Environmental Protection Agency (US EPA), and the European Common Air
+//! Quality Index (CAQI).
+
+use std::borrow::Cow;
+use std::str::FromStr;
+
+/// AQI values for the seven US EPA categories, from "Good" (0–50) to the
+/// upper "Hazardous" band (401–500).
+pub const AQI_RANGES: [(f64, f64); 7] = [
+    (0.0, 50.0),
+    (51.0, 100.0),
+    (101.0, 150.0),
+    (151.0, 200.0),
+    (201.0, 300.0),
+    (301.0, 400.0),
+    (401.0, 500.0),
+];
+
+/// PM2.5 concentration breakpoints in µg/m³, one per entry in `AQI_RANGES`.
+pub const PM2_5_BREAKPOINTS: [(f64, f64); 7] = [
+    (0.0, 12.0),
+    (12.1, 35.4),
+    (35.5, 55.4),
+    (55.5, 150.4),
+    (150.5, 250.4),
+    (250.5, 350.4),
+    (350.5, 500.4),
+];
+
+/// PM10 concentration breakpoints in µg/m³, one per entry in `AQI_RANGES`.
+pub const PM10_BREAKPOINTS: [(f64, f64); 7] = [
+    (0.0, 54.0),
+    (55.0, 154.0),
+    (155.0, 254.0),
+    (255.0, 354.0),
+    (355.0, 424.0),
+    (425.0, 504.0),
+    (505.0, 604.0),
+];
+
+/// CAQI index bands, from "Very low" to "High". Values above 100 ("Very
+/// high") are not covered.
+pub const CAQI_RANGES: [(f64, f64); 4] = [(0.0, 25.0), (25.0, 50.0), (50.0, 75.0), (75.0, 100.0)];
+
+/// Hourly CAQI PM2.5 concentration breakpoints in µg/m³.
+pub const CAQI_PM2_5_BREAKPOINTS: [(f64, f64); 4] =
+    [(0.0, 15.0), (15.0, 30.0), (30.0, 55.0), (55.0, 110.0)];
+
+/// Hourly CAQI PM10 concentration breakpoints in µg/m³.
+pub const CAQI_PM10_BREAKPOINTS: [(f64, f64); 4] =
+    [(0.0, 25.0), (25.0, 50.0), (50.0, 90.0), (90.0, 180.0)];
+
+/// How `calculate_aqi` reduces a concentration to the precision of the
+/// breakpoints before looking up its band.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Precision {
+    /// Round to the nearest multiple of 10^-`decimals`.
+    Round { decimals: u32 },
+    /// Drop the digits after `decimals`, as the US EPA specifies.
+    Truncate { decimals: u32 },
+}
+
+impl Precision {
+    pub fn apply(self, concentration: f64) -> f64 {
+        match self {
+            Precision::Round { decimals } => {
+                let scale = 10f64.powi(decimals as i32);
+                (concentration * scale).round() / scale
+            }
+            Precision::Truncate { decimals } => {
+                let scale = 10f64.powi(decimals as i32);
+                // Allow for representation error, e.g. 0.29 * 100 is just
+                // below 29. Negative concentrations stay negative, and are
+                // out of range.
+                (concentration * scale + 1e-9).floor() / scale
+            }
+        }
+    }
+
+    /// The smallest difference between two concentrations after `apply`.
+    pub fn step(self) -> f64 {
+        match self {
+            Precision::Round { decimals } | Precision::Truncate { decimals } => {
+                10f64.powi(-(decimals as i32))
+            }
+        }
+    }
+}
+
+/// A piecewise-linear mapping from concentration bands onto index bands.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Breakpoints {
+    /// Concentration bands in ascending order.
+    pub concentration: Cow<'static, [(f64, f64)]>,
+    /// Index bands, one per concentration band.
+    pub index: Cow<'static, [(f64, f64)]>,
+    pub precision: Precision,
+}
+
+/// PM2.5 is truncated to one decimal.
+pub const US_EPA_PM2_5: Breakpoints = Breakpoints {
+    concentration: Cow::Borrowed(&PM2_5_BREAKPOINTS),
+    index: Cow::Borrowed(&AQI_RANGES),
+    precision: Precision::Truncate { decimals: 1 },
+};
+
+/// PM10 is truncated to an integer.
+pub const US_EPA_PM10: Breakpoints = Breakpoints {
+    concentration: Cow::Borrowed(&PM10_BREAKPOINTS),
+    index: Cow::Borrowed(&AQI_RANGES),
+    precision: Precision::Truncate { decimals: 0 },
+};
+
+pub const EU_CAQI_PM2_5: Breakpoints = Breakpoints {
+    concentration: Cow::Borrowed(&CAQI_PM2_5_BREAKPOINTS),
+    index: Cow::Borrowed(&CAQI_RANGES),
+    precision: Precision::Round { decimals: 1 },
+};
+
+pub const EU_CAQI_PM10: Breakpoints = Breakpoints {
+    concentration: Cow::Borrowed(&CAQI_PM10_BREAKPOINTS),
+    index: Cow::Borrowed(&CAQI_RANGES),
+    precision: Precision::Round { decimals: 1 },
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "snake_case")
+)]
+pub enum AqiStandard {
+    UsEpa,
+    EuCaqi,
+}
+
+impl AqiStandard {
+    /// Value of the `standard` metric label.
+    pub fn label(self) -> &'static str {
+        match self {
+            AqiStandard::UsEpa => "us_epa",
+            AqiStandard::EuCaqi => "eu_caqi",
+        }
+    }
+
+    pub fn pm2_5(self) -> &'static Breakpoints {
+        match self {
+            AqiStandard::UsEpa => &US_EPA_PM2_5,
+            AqiStandard::EuCaqi => &EU_CAQI_PM2_5,
+        }
+    }
+
+    pub fn pm10(self) -> &'static Breakpoints {
+        match self {
+            AqiStandard::UsEpa => &US_EPA_PM10,
+            AqiStandard::EuCaqi => &EU_CAQI_PM10,
+        }
+    }
+}
+
+impl FromStr for AqiStandard {
+    type Err = String;
+
+    /// Parses a `standard` label value, e.g. `us_epa`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        [AqiStandard::UsEpa, AqiStandard::EuCaqi]
+            .iter()
+            .copied()
+            .find(|standard| standard.label() == s)
+            .ok_or_else(|| format!("unknown AQI standard: {}", s))
+    }
+}
+
+/// A color as red, green and blue components.
+pub type Rgb = (u8, u8, u8);
+
+/// Official US EPA colors for the six AQI categories, from "Good" (green) to
+/// "Hazardous" (maroon), with the AQI range each applies to.
+pub const AQI_COLORS: [((f64, f64), Rgb); 6] = [
+    ((0.0, 50.0), (0, 228, 0)),
+    ((50.0, 100.0), (255, 255, 0)),
+    ((100.0, 150.0), (255, 126, 0)),
+    ((150.0, 200.0), (255, 0, 0)),
+    ((200.0, 300.0), (143, 63, 151)),
+    ((300.0, 500.0), (126, 0, 35)),
+];
+
+/// A continuous color for `aqi`, e.g. for a gradient bar or an LED. The
+/// official category color is used at the middle of each category and
+/// interpolated linearly in between. Values below the middle of "Good" or
+/// above the middle of "Hazardous" (including out-of-range values) get the
+/// endpoint color. NaN is treated as 0.
+pub fn aqi_color_rgb(aqi: f64) -> Rgb {
+    let midpoint = |&((low, high), _): &((f64, f64), Rgb)| (low + high) / 2.0;
+    let aqi = if aqi.is_nan() { 0.0 } else { aqi };
+    let next = AQI_COLORS.partition_point(|band| midpoint(band) <= aqi);
+    if next == 0 {
+        return AQI_COLORS[0].1;
+    }
+    if next == AQI_COLORS.len() {
+        return AQI_COLORS[AQI_COLORS.len() - 1].1;
+    }
+    let (from, to) = (&AQI_COLORS[next - 1], &AQI_COLORS[next]);
+    let t = (aqi - midpoint(from)) / (midpoint(to) - midpoint(from));
+    let lerp = |a: u8, b: u8| (f64::from(a) + (f64::from(b) - f64::from(a)) * t).round() as u8;
+    let ((r0, g0, b0), (r1, g1, b1)) = (from.1, to.1);
+    (lerp(r0, r1), lerp(g0, g1), lerp(b0, b1))
+}
+
+/// The six US EPA AQI categories.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum AqiCategory {
+    Good,
+    Moderate,
+    UnhealthyForSensitiveGroups,
+    Unhealthy,
+    VeryUnhealthy,
+    Hazardous,
+}
+
+impl AqiCategory {
+    pub fn name(self) -> &'static str {
+        match self {
+            AqiCategory::Good => "Good",
+            AqiCategory::Moderate => "Moderate",
+            AqiCategory::UnhealthyForSensitiveGroups => "Unhealthy for Sensitive Groups",
+            AqiCategory::Unhealthy => "Unhealthy",
+            AqiCategory::VeryUnhealthy => "Very Unhealthy",
+            AqiCategory::Hazardous => "Hazardous",
+        }
+    }
+}
+
+/// The US EPA category of `aqi`. Values beyond 500 are "Hazardous".
+pub fn aqi_category(aqi: u32) -> AqiCategory {
+    match aqi {
+        0..=50 => AqiCategory::Good,
+        51..=100 => AqiCategory::Moderate,
+        101..=150 => AqiCategory::UnhealthyForSensitiveGroups,
+        151..=200 => AqiCategory::Unhealthy,
+        201..=300 => AqiCategory::VeryUnhealthy,
+        _ => AqiCategory::Hazardous,
+    }
+}
+
+/// Computes the index for `concentration` (µg/m³), after reducing it to
+/// `breakpoints.precision`. Returns `None` if the concentration is outside
+/// the range covered by `breakpoints`.
+pub fn calculate_aqi(breakpoints: &Breakpoints, concentration: f64) -> Option<u32> {
+    let aqi = aqi_scale(breakpoints, breakpoints.precision.apply(concentration))?;
+    Some(aqi.round() as u32)
+}
+
+/// The inverse of `calculate_aqi`: the concentration (µg/m³) at which the
+/// index reaches `aqi`, e.g. to turn a threshold given as an AQI into a
+/// concentration threshold. Returns `None` if `aqi` is outside the index
+/// range of `breakpoints`.
+pub fn concentration_for_aqi(breakpoints: &Breakpoints, aqi: f64) -> Option<f64> {
+    if aqi.is_nan() || aqi < 0.0 {
+        return None;
+    }
+    let band = breakpoints.index.partition_point(|&(_, high)| high < aqi);
+    let (i_low, i_high) = *breakpoints.index.get(band)?;
+    let (c_low, c_high) = *breakpoints.concentration.get(band)?;
+    Some((c_high - c_low) / (i_high - i_low) * (aqi - i_low) + c_low)
+}
+
+/// Maps `concentration` (µg/m³) onto the index scale of `breakpoints`
+/// without rounding, so that concentrations of different pollutants can be
+/// compared on one axis. Returns `None` outside the range covered by
+/// `breakpoints`.
+pub fn aqi_scale(breakpoints: &Breakpoints, concentration: f64) -> Option<f64> {
+    if concentration.is_nan() || concentration < 0.0 {
+        return None;
+    }
+    let band = breakpoints
+        .concentration
+        .partition_point(|&(_, high)| high < concentration);
+    let (c_low, c_high) = *breakpoints.concentration.get(band)?;
+    let (i_low, i_high) = *breakpoints.index.get(band)?;
+    Some((i_high - i_low) / (c_high - c_low) * (concentration - c_low) + i_low)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_calculate_aqi() {
+        assert_eq!(calculate_aqi(&US_EPA_PM2_5, 4.0), Some(17));
+        assert_eq!(calculate_aqi(&US_EPA_PM2_5, 37.0), Some(105));
+        assert_eq!(calculate_aqi(&US_EPA_PM10, 7.0), Some(6));
+        assert_eq!(calculate_aqi(&US_EPA_PM2_5, 500.4), Some(500));
+    }
+
+    #[test]
+    fn test_aqi_scale() {
+        assert_eq!(aqi_scale(&US_EPA_PM2_5, 0.0), Some(0.0));
+        assert_eq!(aqi_scale(&US_EPA_PM2_5, 6.0), Some(25.0));
+        let aqi = aqi_scale(&US_EPA_PM2_5, 4.04).unwrap();
+        assert!((aqi - 16.833).abs() < 1e-3);
+        assert_eq!(calculate_aqi(&US_EPA_PM2_5, 4.04), Some(17));
+        assert_eq!(aqi_scale(&US_EPA_PM10, 605.0), None);
+        assert_eq!(aqi_scale(&US_EPA_PM10, -1.0), None);
+    }
+
+    #[test]
+    fn test_concentration_for_aqi() {
+        assert_eq!(concentration_for_aqi(&US_EPA_PM2_5, 50.0), Some(12.0));
+        assert_eq!(concentration_for_aqi(&US_EPA_PM2_5, 101.0), Some(35.5));
+        assert_eq!(concentration_for_aqi(&US_EPA_PM10, 0.0), Some(0.0));
+        assert_eq!(concentration_for_aqi(&US_EPA_PM2_5, 501.0), None);
+        assert_eq!(concentration_for_aqi(&US_EPA_PM2_5, -1.0), None);
+
+        for breakpoints in [&US_EPA_PM2_5, &US_EPA_PM10, AqiStandard::EuCaqi.pm2_5()] {
+            let bands = breakpoints
+                .concentration
+                .iter()
+                .zip(breakpoints.index.iter());
+            for (&(c_low, c_high), &(i_low, i_high)) in bands {
+                // calculate_aqi reduces the concentration to the precision
+                // of the breakpoints and rounds to a whole index, which is
+                // off by at most half an index step.
+                let tolerance =
+                    0.5 * (c_high - c_low) / (i_high - i_low) + breakpoints.precision.step();
+                for t in [0.1, 0.5, 0.9] {
+                    let x = c_low + t * (c_high - c_low);
+                    let aqi = calculate_aqi(breakpoints, x).unwrap();
+                    let inverse = concentration_for_aqi(breakpoints, aqi.into()).unwrap();
+                    assert!(
+                        (inverse - x).abs() <= tolerance,
+                        "{} -> {} -> {}",
+                        x,
+                        aqi,
+                        inverse
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_aqi_category() {
+        assert_eq!(aqi_category(0), AqiCategory::Good);
+        assert_eq!(aqi_category(50), AqiCategory::Good);
+        assert_eq!(aqi_category(51), AqiCategory::Moderate);
+        assert_eq!(aqi_category(150), AqiCategory::UnhealthyForSensitiveGroups);
+        assert_eq!(aqi_category(301), AqiCategory::Hazardous);
+        assert_eq!(aqi_category(900).name(), "Hazardous");
+    }
+
+    #[test]
+    fn test_calculate_caqi() {
+        assert_eq!(calculate_aqi(AqiStandard::EuCaqi.pm2_5(), 4.0), Some(7));
+        assert_eq!(calculate_aqi(AqiStandard::EuCaqi.pm2_5(), 42.5), Some(63));
+        assert_eq!(calculate_aqi(AqiStandard::EuCaqi.pm10(), 135.0), Some(88));
+        assert_eq!(calculate_aqi(AqiStandard::EuCaqi.pm10(), 181.0), None);
+    }
+
+    #[test]
+    fn test_aqi_color_rgb() {
+        assert_eq!(aqi_color_rgb(25.0), (0, 228, 0));
+        assert_eq!(aqi_color_rgb(75.0), (255, 255, 0));
+        assert_eq!(aqi_color_rgb(50.0), (128, 242, 0));
+        assert_eq!(aqi_color_rgb(400.0), (126, 0, 35));
+        assert_eq!(aqi_color_rgb(-10.0), (0, 228, 0));
+        assert_eq!(aqi_color_rgb(f64::NAN), (0, 228, 0));
+        assert_eq!(aqi_color_rgb(1000.0), (126, 0, 35));
+    }
+
+    #[test]
+    fn test_calculate_aqi_band_midpoints() {
+        for breakpoints in [&US_EPA_PM2_5, &US_EPA_PM10] {
+            for (&(c_low, c_high), &(i_low, i_high)) in
+                breakpoints.concentration.iter().zip(&*breakpoints.index)
+            {
+                let aqi = calculate_aqi(breakpoints, (c_low + c_high) / 2.0).unwrap();
+                let aqi = f64::from(aqi);
+                assert!(
+                    i_low < aqi && aqi < i_high,
+                    "AQI {} at the middle of {}–{} µg/m³ is outside {}–{}",
+                    aqi,
+                    c_low,
+                    c_high,
+                    i_low,
+                    i_high
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_calculate_aqi_band_boundaries() {
+        for breakpoints in [&US_EPA_PM2_5, &US_EPA_PM10] {
+            let bands = breakpoints.concentration.iter().zip(&*breakpoints.index);
+            for (&(c_low, c_high), &(i_low, i_high)) in bands {
+                assert_eq!(calculate_aqi(breakpoints, c_low), Some(i_low as u32));
+                assert_eq!(calculate_aqi(breakpoints, c_high), Some(i_high as u32));
+            }
+            // Consecutive bands meet without a gap: the upper end of one band
+            // is one index point below the lower end of the next.
+            for band in 1..breakpoints.concentration.len() {
+                let high = calculate_aqi(breakpoints, breakpoints.concentration[band - 1].1);
+                let low = calculate_aqi(breakpoints, breakpoints.concentration[band].0);
+                assert_eq!(high.unwrap() + 1, low.unwrap());
+            }
+        }
+        // Concentrations between two bands' breakpoints belong to the lower
+        // band once truncated, and don't fall through the gap.
+        assert_eq!(calculate_aqi(&US_EPA_PM2_5, 12.04), Some(50));
+        assert_eq!(calculate_aqi(&US_EPA_PM2_5, 12.09), Some(50));
+        assert_eq!(calculate_aqi(&US_EPA_PM10, 54.1), Some(50));
+        assert_eq!(calculate_aqi(&US_EPA_PM10, 54.9), Some(50));
+        // With rounding, they belong to the nearer one.
+        let rounded = Breakpoints {
+            precision: Precision::Round { decimals: 1 },
+            ..US_EPA_PM2_5
+        };
+        assert_eq!(calculate_aqi(&rounded, 12.04), Some(50));
+        assert_eq!(calculate_aqi(&rounded, 12.05), Some(51));
+    }
+
+    #[test]
+    fn test_calculate_aqi_epa_examples() {
+        // Values as given by the AirNow AQI calculator.
+        assert_eq!(calculate_aqi(&US_EPA_PM2_5, 35.9), Some(102));
+        assert_eq!(calculate_aqi(&US_EPA_PM2_5, 35.49), Some(100));
+        assert_eq!(calculate_aqi(&US_EPA_PM2_5, 150.45), Some(200));
+        assert_eq!(calculate_aqi(&US_EPA_PM10, 154.9), Some(100));
+        assert_eq!(calculate_aqi(&US_EPA_PM10, 180.0), Some(113));
+        assert_eq!(calculate_aqi(&US_EPA_PM10, 424.7), Some(300));
+    }
+
+    #[test]
+    fn test_precision() {
+        assert_eq!(Precision::Truncate { decimals: 2 }.apply(0.29), 0.29);
+        assert_eq!(Precision::Truncate { decimals: 1 }.apply(4.99), 4.9);
+        assert_eq!(Precision::Truncate { decimals: 0 }.apply(54.9), 54.0);
+        assert_eq!(Precision::Round { decimals: 0 }.apply(54.5), 55.0);
+        assert_eq!(Precision::Round { decimals: 1 }.step(), 0.1);
+    }
+
+    #[test]
+    fn test_calculate_aqi_out_of_range() {
+        assert_eq!(calculate_aqi(&US_EPA_PM2_5, 500.5), None);
+        assert_eq!(calculate_aqi(&US_EPA_PM10, f64::from(u16::MAX)), None);
+        assert_eq!(calculate_aqi(&US_EPA_PM10, -1.0), None);
+        assert_eq!(calculate_aqi(&US_EPA_PM10, f64::NAN), None);
+    }
+}
@@ -0,0 +1,854 @@
+use crate::aqi::{aqi_category, AqiCategory};
+#[cfg(feature = "serde")]
+use crate::aqi::{calculate_aqi, US_EPA_PM10, US_EPA_PM2_5};
+use crate::clock::{real_clock, Clock};
+#[cfg(feature = "serde")]
+use crate::logfile::RotatingFile;
+#[cfg(feature = "prometheus")]
+use crate::metrics::{
+    update_metrics, update_sensor_version, DUPLICATE_FRAMES, METRICS_PERIOD_PARTIAL,
+};
+#[cfg(feature = "serde")]
+use crate::position::{Position, PositionSource};
+use crate::sink::ConcentrationBasis;
+use crate::stats::{ReadingAverager, RingBuffer};
+use crate::PmsData;
+#[cfg(feature = "serde")]
+use log::error;
+use log::info;
+#[cfg(feature = "serde")]
+use serde::Serialize;
+use std::sync::Arc;
+use std::thread;
+#[cfg(feature = "serde")]
+use std::time::UNIX_EPOCH;
+use std::time::{Duration, SystemTime};
+
+/// Returns a callback that passes readings on to `callback` once
+/// `settle_time` has passed since the first reading.
+pub fn trusted_callback<F>(
+    settle_time: Duration,
+    echo: bool,
+    callback: F,
+) -> Box<dyn FnMut(PmsData)>
+where
+    F: FnMut(PmsData) + 'static,
+{
+    CallbackBuilder::new()
+        .settle_time(settle_time)
+        .echo(echo)
+        .build(callback)
+}
+
+type Hook = Box<dyn FnOnce(&PmsData)>;
+
+/// Builds a per-frame callback that skips the sensor's warm-up period.
+#[derive(Default)]
+pub struct CallbackBuilder {
+    settle_time: Duration,
+    skip_frames: u64,
+    min_valid_frames: u64,
+    echo: bool,
+    on_first_frame: Option<Hook>,
+    clock: Option<Arc<dyn Clock>>,
+}
+
+impl CallbackBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Readings are ignored until this long after the first one.
+    pub fn settle_time(mut self, settle_time: Duration) -> Self {
+        self.settle_time = settle_time;
+        self
+    }
+
+    /// The first `skip_frames` readings are ignored. The sensor sends about
+    /// one frame per second, so this is a clock-independent alternative to
+    /// `settle_time`. If both are set, readings are ignored until both have
+    /// passed.
+    pub fn skip_frames(mut self, skip_frames: u64) -> Self {
+        self.skip_frames = skip_frames;
+        self
+    }
+
+    /// Readings are ignored until `min_valid_frames` consecutive ones have
+    /// passed their checksum, so that a single fluke frame is never passed
+    /// on first. A failed checksum starts the count over. Only useful with
+    /// `ValidationMode::Lenient`, since invalid frames are dropped otherwise.
+    pub fn min_valid_frames(mut self, min_valid_frames: u64) -> Self {
+        self.min_valid_frames = min_valid_frames;
+        self
+    }
+
+    /// The clock `settle_time` is measured with, `RealClock` by default.
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = Some(clock);
+        self
+    }
+
+    /// Print a message while waiting for readings to settle.
+    pub fn echo(mut self, echo: bool) -> Self {
+        self.echo = echo;
+        self
+    }
+
+    /// Called once with the first trusted reading, before it is passed to
+    /// the per-frame callback.
+    pub fn on_first_frame<H>(mut self, hook: H) -> Self
+    where
+        H: FnOnce(&PmsData) + 'static,
+    {
+        self.on_first_frame = Some(Box::new(hook));
+        self
+    }
+
+    pub fn build<F>(self, mut callback: F) -> Box<dyn FnMut(PmsData)>
+    where
+        F: FnMut(PmsData) + 'static,
+    {
+        let CallbackBuilder {
+            settle_time,
+            skip_frames,
+            min_valid_frames,
+            echo,
+            mut on_first_frame,
+            clock,
+        } = self;
+        let clock = clock.unwrap_or_else(real_clock);
+        let mut start_time = None;
+        let mut skipped = 0;
+        let mut valid = 0;
+        Box::new(move |data| {
+            if start_time.is_none() {
+                start_time = Some(clock.now());
+                #[cfg(feature = "prometheus")]
+                update_sensor_version(&data);
+                if echo && settle_time > Duration::from_secs(0) {
+                    println!("Waiting {:?} until data is trusted...", settle_time);
+                }
+                if echo && skip_frames > 0 {
+                    println!("Skipping {} frames until data is trusted...", skip_frames);
+                }
+            }
+            if skipped < skip_frames {
+                skipped += 1;
+                info!(
+                    "{} frames until data is trusted, ignoring: {:?}",
+                    skip_frames - skipped + 1,
+                    data
+                );
+                return;
+            }
+            if let Ok(duration) = clock.now().duration_since(start_time.unwrap()) {
+                if duration < settle_time {
+                    info!(
+                        "{:?} until data is trusted, ignoring: {:?}",
+                        settle_time - duration,
+                        data
+                    );
+                    return;
+                }
+            }
+            if valid < min_valid_frames {
+                if data.has_valid_checksum() {
+                    valid += 1;
+                } else {
+                    valid = 0;
+                }
+                if valid < min_valid_frames {
+                    info!(
+                        "{} valid frames until data is trusted, ignoring: {:?}",
+                        min_valid_frames - valid,
+                        data
+                    );
+                    return;
+                }
+            }
+            if let Some(hook) = on_first_frame.take() {
+                hook(&data);
+            }
+            callback(data);
+        })
+    }
+}
+
+/// Passes every reading to several consumers, each behind a warm-up policy
+/// of its own, e.g. to export metrics right away but only raise alerts once
+/// readings have settled.
+///
+/// ```
+/// use pms7003::{CallbackBuilder, FanOut};
+/// use std::time::Duration;
+///
+/// let callback = FanOut::new()
+///     .add(CallbackBuilder::new(), |data| println!("{:?}", data))
+///     .add(
+///         CallbackBuilder::new().settle_time(Duration::from_secs(120)),
+///         |data| println!("alert on {:?}", data),
+///     )
+///     .build();
+/// ```
+#[derive(Default)]
+pub struct FanOut {
+    consumers: Vec<Box<dyn FnMut(PmsData)>>,
+}
+
+impl FanOut {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds `callback`, passed the readings that `trust` lets through. Each
+    /// consumer's warm-up starts with the first reading, like that of the
+    /// others.
+    pub fn add<F>(mut self, trust: CallbackBuilder, callback: F) -> Self
+    where
+        F: FnMut(PmsData) + 'static,
+    {
+        self.consumers.push(trust.build(callback));
+        self
+    }
+
+    pub fn build(self) -> Box<dyn FnMut(PmsData)> {
+        let mut consumers = self.consumers;
+        Box::new(move |data| {
+            if let Some((last, rest)) = consumers.split_last_mut() {
+                for consumer in rest {
+                    consumer(data.clone());
+                }
+                last(data);
+            }
+        })
+    }
+}
+
+/// Returns a callback that passes each reading on to `callback` as the mean
+/// over a clock-aligned `period`, once that period has passed. Unlike
+/// throttling, no readings are dropped: when the returned callback is
+/// dropped, e.g. as the read loop stops on a signal, the mean of the period
+/// in progress is passed on too.
+pub fn downsample<F>(period: Duration, callback: F) -> Box<dyn FnMut(PmsData)>
+where
+    F: FnMut(PmsData) + 'static,
+{
+    downsample_with_clock(period, real_clock(), callback)
+}
+
+/// Like `downsample`, with periods taken from `clock`.
+pub fn downsample_with_clock<F>(
+    period: Duration,
+    clock: Arc<dyn Clock>,
+    mut callback: F,
+) -> Box<dyn FnMut(PmsData)>
+where
+    F: FnMut(PmsData) + 'static,
+{
+    downsample_flagged(period, clock, move |mean, _| callback(mean))
+}
+
+/// Like `downsample_with_clock`, but also passes on whether the mean covers
+/// only part of its period, as for the mean passed on when the callback is
+/// dropped. See `ReadingAverager::push_flagged`.
+pub fn downsample_flagged<F>(
+    period: Duration,
+    clock: Arc<dyn Clock>,
+    callback: F,
+) -> Box<dyn FnMut(PmsData)>
+where
+    F: FnMut(PmsData, bool) + 'static,
+{
+    let mut downsampler = Downsampler {
+        averager: ReadingAverager::new(period),
+        clock,
+        callback,
+    };
+    Box::new(move |data| downsampler.push(data))
+}
+
+struct Downsampler<F: FnMut(PmsData, bool)> {
+    averager: ReadingAverager,
+    clock: Arc<dyn Clock>,
+    callback: F,
+}
+
+impl<F: FnMut(PmsData, bool)> Downsampler<F> {
+    fn push(&mut self, data: PmsData) {
+        if let Some((mean, partial)) = self.averager.push_flagged(self.clock.now(), &data) {
+            (self.callback)(mean, partial);
+        }
+    }
+}
+
+impl<F: FnMut(PmsData, bool)> Drop for Downsampler<F> {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            return;
+        }
+        if let Some(mean) = self.averager.flush() {
+            info!("Passing on the mean of a partial period");
+            (self.callback)(mean, true);
+        }
+    }
+}
+
+/// Returns a callback that passes readings on to `callback`, except for
+/// readings identical to the one before. Suppressed readings are counted in
+/// `duplicate_frames_total`, which shows that the sensor is alive while its
+/// output doesn't change.
+pub fn dedup<F>(mut callback: F) -> Box<dyn FnMut(PmsData)>
+where
+    F: FnMut(PmsData) + 'static,
+{
+    let mut previous = None;
+    Box::new(move |data| {
+        if previous.as_ref() == Some(&data) {
+            info!("Suppressing duplicate reading");
+            #[cfg(feature = "prometheus")]
+            DUPLICATE_FRAMES.inc();
+            return;
+        }
+        previous = Some(data.clone());
+        callback(data);
+    })
+}
+
+/// Returns a callback that adds each reading to `buffer` before passing it
+/// on to `callback`.
+pub fn ring_buffer_callback<F>(buffer: RingBuffer, callback: F) -> Box<dyn FnMut(PmsData)>
+where
+    F: FnMut(PmsData) + 'static,
+{
+    ring_buffer_callback_with_clock(buffer, real_clock(), callback)
+}
+
+/// Like `ring_buffer_callback`, with readings timestamped by `clock`.
+pub fn ring_buffer_callback_with_clock<F>(
+    buffer: RingBuffer,
+    clock: Arc<dyn Clock>,
+    mut callback: F,
+) -> Box<dyn FnMut(PmsData)>
+where
+    F: FnMut(PmsData) + 'static,
+{
+    Box::new(move |data| {
+        buffer.push(clock.now(), data.clone());
+        callback(data);
+    })
+}
+
+/// Reports a category once readings have stayed in it for `min_dwell`, so
+/// that readings hovering around a band boundary don't report every
+/// crossing.
+struct CategoryTracker {
+    min_dwell: Duration,
+    current: Option<AqiCategory>,
+    pending: Option<(AqiCategory, SystemTime)>,
+}
+
+impl CategoryTracker {
+    fn new(min_dwell: Duration) -> Self {
+        CategoryTracker {
+            min_dwell,
+            current: None,
+            pending: None,
+        }
+    }
+
+    /// Returns the old and new category if `category` at `at` completes a
+    /// transition. The first category is taken as the starting point.
+    fn update(
+        &mut self,
+        at: SystemTime,
+        category: AqiCategory,
+    ) -> Option<(AqiCategory, AqiCategory)> {
+        let current = match self.current {
+            None => {
+                self.current = Some(category);
+                return None;
+            }
+            Some(current) if current == category => {
+                self.pending = None;
+                return None;
+            }
+            Some(current) => current,
+        };
+        let since = match self.pending {
+            Some((pending, since)) if pending == category => since,
+            _ => {
+                self.pending = Some((category, at));
+                at
+            }
+        };
+        if at.duration_since(since).unwrap_or_default() < self.min_dwell {
+            return None;
+        }
+        self.current = Some(category);
+        self.pending = None;
+        Some((current, category))
+    }
+}
+
+/// Returns a callback that calls `on_change` with the old and new US EPA
+/// category whenever the AQI changes category, e.g. for push
+/// notifications. The AQI is the higher of the PM2.5 and PM10 sub-indices of
+/// the concentrations in `basis`. A new category is only reported once
+/// readings have stayed in it for `min_dwell`.
+///
+/// To react to smoothed readings, pass the callback to `downsample`.
+pub fn category_callback<F>(
+    basis: ConcentrationBasis,
+    min_dwell: Duration,
+    on_change: F,
+) -> Box<dyn FnMut(PmsData)>
+where
+    F: FnMut(AqiCategory, AqiCategory, &PmsData) + 'static,
+{
+    category_callback_with_clock(basis, min_dwell, real_clock(), on_change)
+}
+
+/// Like `category_callback`, with `min_dwell` measured by `clock`.
+pub fn category_callback_with_clock<F>(
+    basis: ConcentrationBasis,
+    min_dwell: Duration,
+    clock: Arc<dyn Clock>,
+    mut on_change: F,
+) -> Box<dyn FnMut(PmsData)>
+where
+    F: FnMut(AqiCategory, AqiCategory, &PmsData) + 'static,
+{
+    let mut tracker = CategoryTracker::new(min_dwell);
+    Box::new(move |data| {
+        let category = data.aqi(basis).map_or(AqiCategory::Hazardous, aqi_category);
+        if let Some((old, new)) = tracker.update(clock.now(), category) {
+            info!("AQI category changed from {} to {}", old.name(), new.name());
+            on_change(old, new, &data);
+        }
+    })
+}
+
+#[cfg(feature = "prometheus")]
+pub fn default_callback(settle_time: Duration, echo: bool) -> Box<dyn FnMut(PmsData)> {
+    echo_callback(settle_time, echo, |data| update_metrics(&data))
+}
+
+/// Like `default_callback`, but only updates the metrics once per `period`,
+/// with the mean of the readings in it. Every reading is still echoed.
+#[cfg(feature = "prometheus")]
+pub fn downsampled_callback(
+    settle_time: Duration,
+    echo: bool,
+    period: Duration,
+) -> Box<dyn FnMut(PmsData)> {
+    echo_callback(settle_time, echo, downsampled_metrics(period))
+}
+
+/// Returns a callback that updates the metrics once per `period`, with the
+/// mean of the readings in it. `metrics_period_partial` is set while the
+/// metrics hold the mean of a period cut short.
+#[cfg(feature = "prometheus")]
+pub fn downsampled_metrics(period: Duration) -> Box<dyn FnMut(PmsData)> {
+    downsample_flagged(period, real_clock(), |mean, partial| {
+        METRICS_PERIOD_PARTIAL.set(if partial { 1.0 } else { 0.0 });
+        update_metrics(&mean);
+    })
+}
+
+#[cfg(feature = "prometheus")]
+fn echo_callback<M>(settle_time: Duration, echo: bool, mut metrics: M) -> Box<dyn FnMut(PmsData)>
+where
+    M: FnMut(PmsData) + 'static,
+{
+    trusted_callback(settle_time, echo, move |data| {
+        metrics(data.clone());
+        if echo {
+            println!("------------------------------------------------");
+            println!("Concentration units (standard)");
+            println!(
+                "pm1.0: {}\tpm2.5: {}\tpm10.0: {}",
+                data.pm1_cf1, data.pm2_5_cf1, data.pm10_cf1
+            );
+            println!();
+            println!("Concentration units (environmental)");
+            println!(
+                "pm1.0: {}\tpm2.5: {}\tpm10.0: {}",
+                data.pm1_atmo, data.pm2_5_atmo, data.pm10_atmo
+            );
+            println!();
+            println!("Particle counts");
+            println!(
+                "pm0.3: {}\tpm0.5: {}\tpm1.0: {}",
+                data.pm0_3_count, data.pm0_5_count, data.pm1_0_count
+            );
+            println!(
+                "pm2.5: {}\tpm5.0: {}\tpm10.0: {}",
+                data.pm2_5_count, data.pm5_0_count, data.pm10_0_count
+            );
+            println!("------------------------------------------------");
+        }
+    })
+}
+
+#[cfg(feature = "serde")]
+#[derive(Serialize)]
+struct JsonReading<'a> {
+    /// Seconds since the Unix epoch.
+    timestamp: f64,
+    #[serde(flatten)]
+    data: &'a PmsData,
+    aqi_pm2_5: Option<u32>,
+    aqi_pm10: Option<u32>,
+    /// `latitude` and `longitude`, left out without a fix.
+    #[serde(flatten, skip_serializing_if = "Option::is_none")]
+    position: Option<Position>,
+}
+
+#[cfg(feature = "serde")]
+fn json_line(timestamp: SystemTime, data: &PmsData, position: Option<Position>) -> String {
+    let reading = JsonReading {
+        timestamp: timestamp
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs_f64(),
+        data,
+        aqi_pm2_5: calculate_aqi(&US_EPA_PM2_5, data.pm2_5_cf1.into()),
+        aqi_pm10: calculate_aqi(&US_EPA_PM10, data.pm10_cf1.into()),
+        position,
+    };
+    serde_json::to_string(&reading).expect("readings are always serializable")
+}
+
+/// Returns a callback that prints each reading to stdout as one compact JSON
+/// object per line. Wrap it in `trusted_callback` to skip the warm-up period.
+#[cfg(feature = "serde")]
+pub fn jsonl_callback() -> Box<dyn FnMut(PmsData)> {
+    jsonl_callback_with_position(PositionSource::new(|| None))
+}
+
+/// Like `jsonl_callback`, with each reading tagged with the position from
+/// `source` at the time.
+#[cfg(feature = "serde")]
+pub fn jsonl_callback_with_position(source: PositionSource) -> Box<dyn FnMut(PmsData)> {
+    jsonl_callback_with_clock(source, real_clock())
+}
+
+/// Like `jsonl_callback_with_position`, with each reading timestamped by
+/// `clock`.
+#[cfg(feature = "serde")]
+pub fn jsonl_callback_with_clock(
+    source: PositionSource,
+    clock: Arc<dyn Clock>,
+) -> Box<dyn FnMut(PmsData)> {
+    Box::new(move |data| {
+        let line = json_line(clock.now(), &data, source.position());
+        println!("{}", line)
+    })
+}
+
+/// Returns a callback that appends each reading to `file` as a JSON line.
+/// Write errors are logged and the reading is dropped.
+#[cfg(feature = "serde")]
+pub fn jsonl_file_callback(file: RotatingFile) -> Box<dyn FnMut(PmsData)> {
+    jsonl_file_callback_with_position(file, PositionSource::new(|| None))
+}
+
+/// Like `jsonl_file_callback`, with each reading tagged with the position
+/// from `source` at the time.
+#[cfg(feature = "serde")]
+pub fn jsonl_file_callback_with_position(
+    file: RotatingFile,
+    source: PositionSource,
+) -> Box<dyn FnMut(PmsData)> {
+    jsonl_file_callback_with_clock(file, source, real_clock())
+}
+
+/// Like `jsonl_file_callback_with_position`, with each reading timestamped
+/// by `clock`, which also decides when the file is rotated daily.
+#[cfg(feature = "serde")]
+pub fn jsonl_file_callback_with_clock(
+    mut file: RotatingFile,
+    source: PositionSource,
+    clock: Arc<dyn Clock>,
+) -> Box<dyn FnMut(PmsData)> {
+    Box::new(move |data| {
+        let now = clock.now();
+        let line = json_line(now, &data, source.position());
+        if let Err(e) = file.write_line_at(now, &line) {
+            error!("Could not write reading to log file: {}", e);
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+    use crate::parse;
+    use crate::{at, GOLDEN_PACKET};
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[test]
+    fn test_on_first_frame() {
+        let (_, data) = parse(GOLDEN_PACKET).unwrap();
+        let events = Rc::new(RefCell::new(Vec::new()));
+        let first = Rc::clone(&events);
+        let frames = Rc::clone(&events);
+        let mut callback = CallbackBuilder::new()
+            .on_first_frame(move |data| {
+                first
+                    .borrow_mut()
+                    .push(format!("first {:?}", data.version()))
+            })
+            .build(move |_| frames.borrow_mut().push("frame".to_string()));
+        for _ in 0..3 {
+            callback(data.clone().unwrap());
+        }
+        assert_eq!(
+            *events.borrow(),
+            ["first Some(151)", "frame", "frame", "frame"]
+        );
+    }
+
+    #[test]
+    fn test_skip_frames() {
+        let (_, data) = parse(GOLDEN_PACKET).unwrap();
+        let delivered = Rc::new(RefCell::new(0));
+        let count = Rc::clone(&delivered);
+        let mut callback = CallbackBuilder::new()
+            .skip_frames(3)
+            .build(move |_| *count.borrow_mut() += 1);
+        for _ in 0..5 {
+            callback(data.clone().unwrap());
+        }
+        assert_eq!(*delivered.borrow(), 2);
+    }
+
+    #[test]
+    fn test_min_valid_frames() {
+        let (_, data) = parse(GOLDEN_PACKET).unwrap();
+        let data = data.unwrap();
+        let mut invalid = data.clone();
+        invalid.checksum += 1;
+        let delivered = Rc::new(RefCell::new(Vec::new()));
+        let frames = Rc::clone(&delivered);
+        let mut callback = CallbackBuilder::new()
+            .min_valid_frames(2)
+            .build(move |data| frames.borrow_mut().push(data.has_valid_checksum()));
+        for reading in [&data, &invalid, &data, &data, &invalid, &data] {
+            callback(reading.clone());
+        }
+        // Once trusted, invalid frames are passed on as before.
+        assert_eq!(*delivered.borrow(), [true, false, true]);
+    }
+
+    #[test]
+    fn test_settle_time() {
+        let data = parse(GOLDEN_PACKET).unwrap().1.unwrap();
+        let clock = MockClock::new(SystemTime::UNIX_EPOCH);
+        let delivered = Rc::new(RefCell::new(0));
+        let count = Rc::clone(&delivered);
+        let mut callback = CallbackBuilder::new()
+            .settle_time(Duration::from_secs(30))
+            .clock(Arc::new(clock.clone()))
+            .build(move |_| *count.borrow_mut() += 1);
+        callback(data.clone());
+        clock.advance(Duration::from_secs(29));
+        callback(data.clone());
+        assert_eq!(*delivered.borrow(), 0);
+        clock.advance(Duration::from_secs(1));
+        callback(data);
+        assert_eq!(*delivered.borrow(), 1);
+    }
+
+    #[test]
+    fn test_downsample_with_clock() {
+        let data = parse(GOLDEN_PACKET).unwrap().1.unwrap();
+        let clock = MockClock::new(SystemTime::UNIX_EPOCH);
+        let delivered = Rc::new(RefCell::new(Vec::new()));
+        let means = Rc::clone(&delivered);
+        let mut callback = downsample_with_clock(
+            Duration::from_secs(60),
+            Arc::new(clock.clone()),
+            move |mean| means.borrow_mut().push(mean.pm2_5_cf1),
+        );
+        for _ in 0..3 {
+            callback(data.clone());
+            clock.advance(Duration::from_secs(30));
+        }
+        assert_eq!(*delivered.borrow(), [4]);
+        drop(callback);
+        assert_eq!(*delivered.borrow(), [4, 4]);
+
+        let flags = Rc::new(RefCell::new(Vec::new()));
+        let partial = Rc::clone(&flags);
+        let mut callback = downsample_flagged(
+            Duration::from_secs(60),
+            Arc::new(clock.clone()),
+            move |_, flag| partial.borrow_mut().push(flag),
+        );
+        callback(data.clone());
+        clock.advance(Duration::from_secs(60));
+        callback(data);
+        drop(callback);
+        assert_eq!(*flags.borrow(), [false, true]);
+    }
+
+    #[test]
+    fn test_category_tracker() {
+        let mut tracker = CategoryTracker::new(Duration::from_secs(60));
+        assert_eq!(tracker.update(at(0), AqiCategory::Good), None);
+        // A brief excursion is not reported.
+        assert_eq!(tracker.update(at(10), AqiCategory::Moderate), None);
+        assert_eq!(tracker.update(at(20), AqiCategory::Good), None);
+        assert_eq!(tracker.update(at(30), AqiCategory::Moderate), None);
+        assert_eq!(tracker.update(at(89), AqiCategory::Moderate), None);
+        assert_eq!(
+            tracker.update(at(90), AqiCategory::Moderate),
+            Some((AqiCategory::Good, AqiCategory::Moderate))
+        );
+        assert_eq!(tracker.update(at(100), AqiCategory::Moderate), None);
+    }
+
+    #[test]
+    fn test_category_callback() {
+        let (_, data) = parse(GOLDEN_PACKET).unwrap();
+        let data = data.unwrap();
+        let mut unhealthy = data.clone();
+        unhealthy.pm2_5_atmo = 100;
+        let changes = Rc::new(RefCell::new(Vec::new()));
+        let recorded = Rc::clone(&changes);
+        let mut callback = category_callback(
+            ConcentrationBasis::Environment,
+            Duration::from_secs(0),
+            move |old, new, _| recorded.borrow_mut().push((old, new)),
+        );
+        for reading in [&data, &data, &unhealthy, &data] {
+            callback(reading.clone());
+        }
+        assert_eq!(
+            *changes.borrow(),
+            [
+                (AqiCategory::Good, AqiCategory::Unhealthy),
+                (AqiCategory::Unhealthy, AqiCategory::Good)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_dedup() {
+        let (_, data) = parse(GOLDEN_PACKET).unwrap();
+        let data = data.unwrap();
+        let mut changed = data.clone();
+        changed.pm2_5_cf1 += 1;
+        let delivered = Rc::new(RefCell::new(Vec::new()));
+        let frames = Rc::clone(&delivered);
+        let mut callback = dedup(move |data: PmsData| frames.borrow_mut().push(data.pm2_5_cf1));
+        for reading in [&data, &data, &changed, &changed, &data] {
+            callback(reading.clone());
+        }
+        assert_eq!(*delivered.borrow(), [4, 5, 4]);
+    }
+
+    #[test]
+    fn test_fan_out() {
+        let data = parse(GOLDEN_PACKET).unwrap().1.unwrap();
+        let clock = MockClock::new(SystemTime::UNIX_EPOCH);
+        let delivered = Rc::new(RefCell::new(Vec::new()));
+        let consumer = |name: &'static str| {
+            let delivered = Rc::clone(&delivered);
+            move |_| delivered.borrow_mut().push(name)
+        };
+        let trust = |settle_time| {
+            CallbackBuilder::new()
+                .settle_time(settle_time)
+                .clock(Arc::new(clock.clone()))
+        };
+        let mut callback = FanOut::new()
+            .add(trust(Duration::from_secs(0)), consumer("metrics"))
+            .add(trust(Duration::from_secs(60)), consumer("alerts"))
+            .build();
+        callback(data.clone());
+        clock.advance(Duration::from_secs(30));
+        callback(data.clone());
+        clock.advance(Duration::from_secs(30));
+        callback(data);
+        assert_eq!(
+            *delivered.borrow(),
+            ["metrics", "metrics", "metrics", "alerts"]
+        );
+    }
+
+    #[test]
+    fn test_ring_buffer_callback() {
+        let data = parse(GOLDEN_PACKET).unwrap().1.unwrap();
+        let start = SystemTime::UNIX_EPOCH;
+        let clock = MockClock::new(start);
+        let buffer = RingBuffer::new(2);
+        let delivered = Rc::new(RefCell::new(0));
+        let frames = Rc::clone(&delivered);
+        let mut callback =
+            ring_buffer_callback_with_clock(buffer.clone(), Arc::new(clock.clone()), move |_| {
+                *frames.borrow_mut() += 1
+            });
+        callback(data.clone());
+        clock.advance(Duration::from_secs(1));
+        callback(data.clone());
+        assert_eq!(*delivered.borrow(), 2);
+        let snapshot = buffer.snapshot();
+        assert_eq!(snapshot[1], (start + Duration::from_secs(1), data));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_json_line() {
+        let (_, data) = parse(GOLDEN_PACKET).unwrap();
+        let data = data.unwrap();
+        let at = UNIX_EPOCH + Duration::from_secs(10);
+        let line = json_line(at, &data, None);
+        assert!(!line.contains('\n'));
+        let value: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(value["timestamp"], 10.0);
+        assert_eq!(value["pm2_5_cf1"], 4);
+        assert_eq!(value["pm0_3_count"], 720);
+        assert_eq!(value["aqi_pm2_5"], 17);
+        assert!(value.get("latitude").is_none());
+
+        let line = json_line(at, &data, Position::new(63.43, 10.39));
+        let value: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(value["latitude"], 63.43);
+        assert_eq!(value["longitude"], 10.39);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_jsonl_file_callback_with_clock() {
+        let path = std::env::temp_dir().join(format!("pms7003-jsonl-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let file = RotatingFile::open(&path, Default::default()).unwrap();
+        let clock = MockClock::new(UNIX_EPOCH + Duration::from_secs(10));
+        let mut callback = jsonl_file_callback_with_clock(
+            file,
+            PositionSource::new(|| None),
+            Arc::new(clock.clone()),
+        );
+        let (_, data) = parse(GOLDEN_PACKET).unwrap();
+        callback(data.clone().unwrap());
+        clock.advance(Duration::from_secs(5));
+        callback(data.unwrap());
+        drop(callback);
+
+        let timestamps: Vec<f64> = std::fs::read_to_string(&path)
+            .unwrap()
+            .lines()
+            .map(|line| {
+                serde_json::from_str::<serde_json::Value>(line).unwrap()["timestamp"]
+                    .as_f64()
+                    .unwrap()
+            })
+            .collect();
+        assert_eq!(timestamps, [10.0, 15.0]);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,91 @@
+//! The clock used by the callbacks, metrics and read loop that depend on the
+//! time of a reading or the time between them, so that they can be tested
+//! without waiting.
+//!
+//! ```
+//! use pms7003::clock::{Clock, MockClock};
+//! use std::time::{Duration, UNIX_EPOCH};
+//!
+//! let clock = MockClock::new(UNIX_EPOCH);
+//! clock.advance(Duration::from_secs(30));
+//! assert_eq!(clock.now(), UNIX_EPOCH + Duration::from_secs(30));
+//! ```
+//!
+//! The read loop measures intervals with `Clock::instant`, from
+//! `reader::Config::clock`, and waits between polls with `Clock::sleep`.
+//! Waiting for the port itself still takes real time.
+
+use std::fmt::Debug;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+pub trait Clock: Debug + Send + Sync {
+    fn now(&self) -> SystemTime;
+
+    /// Monotonic time, for measuring intervals. Defaults to `Instant::now`.
+    fn instant(&self) -> Instant {
+        Instant::now()
+    }
+
+    /// Waits for `duration`. Defaults to `thread::sleep`.
+    fn sleep(&self, duration: Duration) {
+        thread::sleep(duration)
+    }
+}
+
+/// `SystemTime::now`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RealClock;
+
+impl Clock for RealClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+/// A clock that only moves when told to. Clones share the same time, so a
+/// test can keep one and pass the other on.
+#[derive(Debug, Clone)]
+pub struct MockClock {
+    now: Arc<Mutex<(SystemTime, Instant)>>,
+}
+
+impl MockClock {
+    pub fn new(start: SystemTime) -> Self {
+        MockClock {
+            now: Arc::new(Mutex::new((start, Instant::now()))),
+        }
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        let mut now = self.now.lock().unwrap();
+        now.0 += duration;
+        now.1 += duration;
+    }
+
+    /// Sets the wall clock, as when it is adjusted. `instant` doesn't move.
+    pub fn set(&self, now: SystemTime) {
+        self.now.lock().unwrap().0 = now;
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> SystemTime {
+        self.now.lock().unwrap().0
+    }
+
+    fn instant(&self) -> Instant {
+        self.now.lock().unwrap().1
+    }
+
+    /// Advances the clock instead of waiting.
+    fn sleep(&self, duration: Duration) {
+        self.advance(duration)
+    }
+}
+
+/// The clock used where none is given.
+pub fn real_clock() -> Arc<dyn Clock> {
+    Arc::new(RealClock)
+}
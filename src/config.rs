@@ -0,0 +1,148 @@
+//! Settings for the `pms7003` binary that can be read from a JSON file, so
+//! that deployments don't need long command lines. Flags given on the
+//! command line override the file.
+//!
+//! ```json
+//! {
+//!     "port": "/dev/ttyUSB0",
+//!     "settle_time_seconds": 30,
+//!     "prometheus_bind_addr": "0.0.0.0:9954",
+//!     "aqi_standards": ["us_epa", "eu_caqi"],
+//!     "thresholds": {
+//!         "on_at": 35,
+//!         "off_at": 20,
+//!         "min_dwell_seconds": 600,
+//!         "settle_time_seconds": 300,
+//!         "on_command": ["purifier", "on"],
+//!         "off_command": ["purifier", "off"]
+//!     }
+//! }
+//! ```
+
+use crate::action::{Action, Thresholds};
+use crate::aqi::AqiStandard;
+#[cfg(feature = "serde")]
+use serde::Deserialize;
+#[cfg(feature = "serde")]
+use std::fs;
+#[cfg(feature = "serde")]
+use std::io;
+#[cfg(feature = "serde")]
+use std::path::Path;
+use std::process;
+use std::time::Duration;
+
+/// Every setting is optional, falling back to the command line default.
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Deserialize))]
+#[cfg_attr(feature = "serde", serde(default, deny_unknown_fields))]
+pub struct FileConfig {
+    pub port: Option<String>,
+    pub baud_rate: Option<u32>,
+    pub settle_time_seconds: Option<f64>,
+    /// Also export the `sensor_*` series under this `location` label.
+    pub location: Option<String>,
+    pub prometheus_bind_addr: Option<String>,
+    pub aqi_standards: Vec<AqiStandard>,
+    /// Run commands when PM2.5 crosses a threshold, see `action_callback`.
+    pub thresholds: Option<ThresholdConfig>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Deserialize))]
+#[cfg_attr(feature = "serde", serde(deny_unknown_fields))]
+pub struct ThresholdConfig {
+    pub on_at: f64,
+    pub off_at: f64,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub min_dwell_seconds: f64,
+    /// Overrides the top-level `settle_time_seconds` for the actions, e.g.
+    /// so that warm-up noise doesn't switch anything on.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub settle_time_seconds: Option<f64>,
+    /// Program and arguments to run when switching on. Nothing is run if
+    /// empty.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub on_command: Vec<String>,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub off_command: Vec<String>,
+}
+
+impl ThresholdConfig {
+    pub fn thresholds(&self) -> Thresholds {
+        Thresholds {
+            on_at: self.on_at,
+            off_at: self.off_at,
+            min_dwell: Duration::from_secs_f64(self.min_dwell_seconds.max(0.0)),
+        }
+    }
+
+    pub fn on_action(&self) -> Action {
+        command_action(&self.on_command)
+    }
+
+    pub fn off_action(&self) -> Action {
+        command_action(&self.off_command)
+    }
+}
+
+fn command_action(argv: &[String]) -> Action {
+    match argv.split_first() {
+        Some((program, args)) => {
+            let mut command = process::Command::new(program);
+            command.args(args);
+            Action::Run(command)
+        }
+        None => Action::call(|_| {}),
+    }
+}
+
+/// Reads a `FileConfig` from the JSON file at `path`. Unknown settings are
+/// rejected, so that typos don't go unnoticed.
+#[cfg(feature = "serde")]
+pub fn load_config<P: AsRef<Path>>(path: P) -> io::Result<FileConfig> {
+    let contents = fs::read_to_string(path)?;
+    Ok(serde_json::from_str(&contents)?)
+}
+
+#[cfg(all(test, feature = "serde"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_load_config() {
+        let path = std::env::temp_dir().join(format!("pms7003-config-{}.json", process::id()));
+        fs::write(
+            &path,
+            r#"{
+                "port": "/dev/ttyAMA0",
+                "aqi_standards": ["us_epa", "eu_caqi"],
+                "thresholds": {"on_at": 35, "off_at": 20, "on_command": ["true"]}
+            }"#,
+        )
+        .unwrap();
+        let config = load_config(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(config.port.as_deref(), Some("/dev/ttyAMA0"));
+        assert_eq!(config.settle_time_seconds, None);
+        assert_eq!(
+            config.aqi_standards,
+            [AqiStandard::UsEpa, AqiStandard::EuCaqi]
+        );
+        let thresholds = config.thresholds.unwrap();
+        assert_eq!(thresholds.thresholds().min_dwell, Duration::from_secs(0));
+        assert_eq!(thresholds.settle_time_seconds, None);
+        assert!(matches!(thresholds.on_action(), Action::Run(_)));
+        assert!(matches!(thresholds.off_action(), Action::Call(_)));
+    }
+
+    #[test]
+    fn test_load_config_unknown_field() {
+        let path = std::env::temp_dir().join(format!("pms7003-typo-{}.json", process::id()));
+        fs::write(&path, r#"{"prot": "/dev/ttyUSB0"}"#).unwrap();
+        let error = load_config(&path).unwrap_err();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+}
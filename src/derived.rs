@@ -0,0 +1,239 @@
+//! Human-friendly quantities derived from concentration readings.
+
+use crate::{ConcentrationBasis, PmsData};
+
+/// PM2.5 concentration (µg/m³) equivalent to smoking one cigarette per day,
+/// per Berkeley Earth's approximation.
+pub const UG_M3_PER_CIGARETTE: f64 = 22.0;
+
+/// Converts a PM2.5 concentration (µg/m³) into equivalent cigarettes smoked
+/// per day.
+pub fn cigarette_equivalent(pm2_5: f64) -> f64 {
+    pm2_5 / UG_M3_PER_CIGARETTE
+}
+
+/// Koschmieder's constant: visibility is the distance at which extinction
+/// reduces contrast to 2%, i.e. `-ln(0.02) / b_ext`.
+pub const KOSCHMIEDER_CONSTANT: f64 = 3.912;
+
+/// Light extinction by clean air (Rayleigh scattering) in Mm⁻¹.
+pub const RAYLEIGH_EXTINCTION: f64 = 10.0;
+
+/// Light extinction per PM2.5 concentration in m²/g, i.e. Mm⁻¹ per µg/m³,
+/// as in the IMPROVE visibility equation for dry fine particles.
+pub const PM2_5_MASS_EXTINCTION_EFFICIENCY: f64 = 3.75;
+
+/// Estimates the visual range in km for a PM2.5 concentration (µg/m³), using
+/// Koschmieder's relation with extinction from clean air and dry fine
+/// particles. Humidity makes particles grow and scatter more, so in humid air
+/// the actual visibility is lower. About 390 km for clean air, 28 km at
+/// 35 µg/m³ and 10 km at 100 µg/m³.
+pub fn estimate_visibility_km(pm2_5: f64) -> f64 {
+    let pm2_5 = if pm2_5 < 0.0 { 0.0 } else { pm2_5 };
+    let extinction = RAYLEIGH_EXTINCTION + PM2_5_MASS_EXTINCTION_EFFICIENCY * pm2_5;
+    KOSCHMIEDER_CONSTANT * 1000.0 / extinction
+}
+
+/// Standard temperature in K (0 °C).
+pub const STP_TEMPERATURE_K: f64 = 273.15;
+
+/// Standard pressure in hPa.
+pub const STP_PRESSURE_HPA: f64 = 1013.25;
+
+/// Ambient conditions of the air a reading was taken in.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Ambient {
+    pub temperature_celsius: f64,
+    pub pressure_hpa: f64,
+}
+
+/// Scales a particle count (per 0.1 L of ambient air) to the same volume of
+/// air at standard temperature and pressure, using the ideal gas law:
+///
+/// `count_stp = count × (P_stp / P) × (T / T_stp)`
+///
+/// with temperatures in K. Thin or warm air holds fewer particles per volume
+/// for the same mixing ratio, so counts are scaled up at altitude.
+pub fn normalize_to_stp(count: f64, ambient: Ambient) -> f64 {
+    let temperature_k = ambient.temperature_celsius + 273.15;
+    count * (STP_PRESSURE_HPA / ambient.pressure_hpa) * (temperature_k / STP_TEMPERATURE_K)
+}
+
+/// US EPA AQI values and the indoor air quality score at each, in between
+/// which the score is interpolated linearly. Each AQI category spans 20
+/// points: "Good" is 80–100, "Moderate" 60–80, "Unhealthy for Sensitive
+/// Groups" 40–60, "Unhealthy" 20–40 and "Very Unhealthy" 0–20.
+pub const IAQ_SCORE_BREAKPOINTS: [(f64, f64); 6] = [
+    (0.0, 100.0),
+    (50.0, 80.0),
+    (100.0, 60.0),
+    (150.0, 40.0),
+    (200.0, 20.0),
+    (300.0, 0.0),
+];
+
+/// A comfort score from 0 to 100, higher being better, for the dominant US
+/// EPA AQI of the ambient PM2.5 and PM10 concentrations, see
+/// `IAQ_SCORE_BREAKPOINTS`. "Hazardous" air and readings beyond the index
+/// score 0.
+pub fn iaq_score(data: &PmsData) -> u8 {
+    let aqi = match data.aqi(ConcentrationBasis::Environment) {
+        Some(aqi) => f64::from(aqi),
+        None => return 0,
+    };
+    let score = IAQ_SCORE_BREAKPOINTS
+        .windows(2)
+        .find(|band| aqi <= band[1].0)
+        .map_or(0.0, |band| {
+            let ((aqi_low, score_low), (aqi_high, score_high)) = (band[0], band[1]);
+            score_low + (score_high - score_low) * (aqi - aqi_low) / (aqi_high - aqi_low)
+        });
+    score.round() as u8
+}
+
+/// Density in g/cm³ commonly assumed for ambient fine particles.
+pub const DEFAULT_PARTICLE_DENSITY: f64 = 1.65;
+
+/// Below this, in µg/m³, the estimated and reported PM2.5 are too close to
+/// the sensor's resolution to compare.
+pub const MIN_CONSISTENCY_MASS: f64 = 1.0;
+
+/// Estimates the PM2.5 mass concentration (µg/m³) from the particle counts,
+/// taking each particle in the 0.3–0.5, 0.5–1.0 and 1.0–2.5 µm bins to be a
+/// sphere of the geometric mean diameter of its bin, of `density` g/cm³.
+pub fn mass_from_counts(data: &PmsData, density: f64) -> f64 {
+    let bins = [
+        (data.pm0_3_count, data.pm0_5_count, 0.3_f64, 0.5),
+        (data.pm0_5_count, data.pm1_0_count, 0.5, 1.0),
+        (data.pm1_0_count, data.pm2_5_count, 1.0, 2.5),
+    ];
+    bins.iter()
+        .map(|&(above, next, low, high)| {
+            let count = f64::from(above.saturating_sub(next));
+            let diameter = (low * high).sqrt();
+            // µm³ to cm³ is 1e-12, g to µg 1e6, and 0.1 L to m³ 1e4.
+            count * density * std::f64::consts::PI / 6.0 * diameter.powi(3) * 1e-2
+        })
+        .sum()
+}
+
+/// The ambient PM2.5 concentration the sensor reported divided by the one
+/// estimated from its particle counts with `mass_from_counts`. The sensor's
+/// own conversion makes this roughly constant for a healthy sensor, so a
+/// reading far off the usual ratio points to a fault or a corrupted frame.
+///
+/// `None` if both are below `MIN_CONSISTENCY_MASS`, and infinite if there is
+/// mass but no particles to account for it.
+pub fn mass_consistency_ratio(data: &PmsData, density: f64) -> Option<f64> {
+    let expected = mass_from_counts(data, density);
+    let reported = f64::from(data.pm2_5_atmo);
+    if expected < MIN_CONSISTENCY_MASS && reported < MIN_CONSISTENCY_MASS {
+        return None;
+    }
+    if expected == 0.0 {
+        return Some(f64::INFINITY);
+    }
+    Some(reported / expected)
+}
+
+/// How much of the `outdoor` PM2.5 concentration an air purifier keeps out,
+/// in percent, given the `indoor` concentration. 0 if the air inside is no
+/// cleaner than outside, and `None` without a positive outdoor reference.
+pub fn purifier_effectiveness(indoor: f64, outdoor: f64) -> Option<f64> {
+    if outdoor.is_nan() || outdoor <= 0.0 || indoor.is_nan() {
+        return None;
+    }
+    Some((100.0 * (1.0 - indoor / outdoor)).clamp(0.0, 100.0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cigarette_equivalent() {
+        assert_eq!(cigarette_equivalent(0.0), 0.0);
+        assert_eq!(cigarette_equivalent(22.0), 1.0);
+        assert_eq!(cigarette_equivalent(110.0), 5.0);
+    }
+
+    #[test]
+    fn test_estimate_visibility_km() {
+        assert_eq!(estimate_visibility_km(0.0), 391.2);
+        assert_eq!(estimate_visibility_km(-5.0), 391.2);
+        assert!((estimate_visibility_km(100.0) - 10.16).abs() < 0.01);
+        assert!(estimate_visibility_km(500.0) < estimate_visibility_km(35.0));
+        assert!(estimate_visibility_km(f64::NAN).is_nan());
+    }
+
+    #[test]
+    fn test_iaq_score() {
+        let reading = |pm2_5: u16, pm10: u16| {
+            let mut words = [0; 13];
+            words[4] = pm2_5;
+            words[5] = pm10;
+            PmsData::from(words)
+        };
+        assert_eq!(iaq_score(&reading(0, 0)), 100);
+        // PM2.5 of 12 and PM10 of 54 are both at AQI 50.
+        assert_eq!(iaq_score(&reading(12, 0)), 80);
+        assert_eq!(iaq_score(&reading(0, 54)), 80);
+        // The worse of the two counts: AQI 105 for PM2.5.
+        assert_eq!(iaq_score(&reading(37, 10)), 58);
+        assert_eq!(iaq_score(&reading(300, 0)), 0);
+        assert_eq!(iaq_score(&reading(900, 0)), 0);
+    }
+
+    #[test]
+    fn test_mass_consistency() {
+        let mut words = [3, 4, 7, 3, 4, 7, 720, 184, 25, 8, 4, 2, 0];
+        let data = PmsData::from(words);
+        let expected = mass_from_counts(&data, DEFAULT_PARTICLE_DENSITY);
+        assert!((expected - 1.335).abs() < 0.001, "{}", expected);
+        let ratio = mass_consistency_ratio(&data, DEFAULT_PARTICLE_DENSITY).unwrap();
+        assert!((ratio - 3.0).abs() < 0.01, "{}", ratio);
+        // Denser particles weigh more.
+        assert!(mass_from_counts(&data, 2.0 * DEFAULT_PARTICLE_DENSITY) > expected);
+
+        words[4] = 0;
+        words[6..12].copy_from_slice(&[100, 50, 0, 0, 0, 0]);
+        assert_eq!(
+            mass_consistency_ratio(&PmsData::from(words), DEFAULT_PARTICLE_DENSITY),
+            None
+        );
+        words[4] = 50;
+        words[6..12].copy_from_slice(&[0; 6]);
+        assert_eq!(
+            mass_consistency_ratio(&PmsData::from(words), DEFAULT_PARTICLE_DENSITY),
+            Some(f64::INFINITY)
+        );
+    }
+
+    #[test]
+    fn test_purifier_effectiveness() {
+        assert_eq!(purifier_effectiveness(5.0, 20.0), Some(75.0));
+        assert_eq!(purifier_effectiveness(0.0, 20.0), Some(100.0));
+        assert_eq!(purifier_effectiveness(30.0, 20.0), Some(0.0));
+        assert_eq!(purifier_effectiveness(5.0, 0.0), None);
+        assert_eq!(purifier_effectiveness(5.0, f64::NAN), None);
+    }
+
+    #[test]
+    fn test_normalize_to_stp() {
+        let stp = Ambient {
+            temperature_celsius: 0.0,
+            pressure_hpa: STP_PRESSURE_HPA,
+        };
+        assert_eq!(normalize_to_stp(720.0, stp), 720.0);
+        let denver = Ambient {
+            temperature_celsius: 0.0,
+            pressure_hpa: STP_PRESSURE_HPA / 1.2,
+        };
+        assert!((normalize_to_stp(100.0, denver) - 120.0).abs() < 1e-9);
+        let hot = Ambient {
+            temperature_celsius: 273.15,
+            pressure_hpa: STP_PRESSURE_HPA,
+        };
+        assert_eq!(normalize_to_stp(100.0, hot), 200.0);
+    }
+}
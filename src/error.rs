@@ -0,0 +1,221 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+/// How many bytes on either side of a parse failure `PmsError::Parse` keeps.
+const PARSE_CONTEXT: usize = 16;
+
+#[derive(Debug)]
+pub enum PmsError {
+    /// The sensor handle was used before `open` or after `close`.
+    NotOpen,
+    /// A command was sent to a port that can't be written to.
+    ReadOnly,
+    /// The port at `path` couldn't be opened for lack of permissions.
+    PermissionDenied {
+        path: String,
+    },
+    /// The port at `path` is open in the processes `holders`, e.g. a getty
+    /// on a serial console.
+    PortBusy {
+        path: String,
+        holders: Vec<String>,
+    },
+    /// Buffered input grew beyond `Config::max_buffer_size` without forming
+    /// a frame, and the oldest `discarded` bytes were dropped.
+    Desync {
+        discarded: usize,
+    },
+    /// The setting `field` has a value the reader can't work with.
+    InvalidConfig {
+        field: &'static str,
+        reason: &'static str,
+    },
+    /// The fraction `rate` of recent frames had a bad checksum, more than
+    /// `ChecksumPolicy::max_error_rate` allows.
+    ChecksumErrors {
+        rate: f64,
+    },
+    /// The parser rejected the input at byte `offset` of the buffer being
+    /// parsed. `context` holds the bytes around it, starting at
+    /// `context_start`, to include in bug reports.
+    Parse {
+        offset: usize,
+        message: String,
+        context_start: usize,
+        context: Vec<u8>,
+    },
+    Io(io::Error),
+    #[cfg(feature = "serial")]
+    Serial(serialport::Error),
+}
+
+impl PmsError {
+    /// A `Parse` error for a failure at `offset` within `input`, e.g. for
+    /// reporting errors of `parse_frame` in a custom read loop. An `offset`
+    /// past the end of `input` is kept, with the context ending at the end.
+    pub fn parse(input: &[u8], offset: usize, message: impl fmt::Display) -> Self {
+        let context_start = offset.min(input.len()).saturating_sub(PARSE_CONTEXT);
+        let context_end = input.len().min(offset + PARSE_CONTEXT);
+        PmsError::Parse {
+            offset,
+            message: message.to_string(),
+            context_start,
+            context: input[context_start..context_end].to_vec(),
+        }
+    }
+}
+
+/// Formats `bytes` as space-separated hex, e.g. `42 4d 00 1c`.
+fn hex_dump(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+impl fmt::Display for PmsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PmsError::NotOpen => write!(f, "port is not open"),
+            PmsError::ReadOnly => write!(f, "port is read-only, cannot send commands"),
+            PmsError::PermissionDenied { path } => write!(
+                f,
+                "permission denied opening {}; on Linux, add your user to the dialout group \
+                 (sudo usermod -aG dialout $USER) and log in again",
+                path
+            ),
+            PmsError::PortBusy { path, holders } => write!(
+                f,
+                "{} is in use by {}; on a Raspberry Pi, disable the login shell on the serial \
+                 port with raspi-config",
+                path,
+                holders.join(", ")
+            ),
+            PmsError::Desync { discarded } => write!(
+                f,
+                "no frame found in buffered input, discarded {} bytes",
+                discarded
+            ),
+            PmsError::InvalidConfig { field, reason } => write!(f, "invalid {}: {}", field, reason),
+            PmsError::ChecksumErrors { rate } => write!(
+                f,
+                "{:.0}% of recent frames had a bad checksum, the link or sensor may be failing",
+                rate * 100.0
+            ),
+            PmsError::Parse {
+                offset,
+                message,
+                context_start,
+                context,
+            } => write!(
+                f,
+                "parse error at byte {}: {}; bytes from {}: {}",
+                offset,
+                message,
+                context_start,
+                hex_dump(context)
+            ),
+            PmsError::Io(e) => write!(f, "{}", e),
+            #[cfg(feature = "serial")]
+            PmsError::Serial(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl Error for PmsError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            PmsError::NotOpen
+            | PmsError::ReadOnly
+            | PmsError::PermissionDenied { .. }
+            | PmsError::PortBusy { .. }
+            | PmsError::Desync { .. }
+            | PmsError::InvalidConfig { .. }
+            | PmsError::ChecksumErrors { .. }
+            | PmsError::Parse { .. } => None,
+            PmsError::Io(e) => Some(e),
+            #[cfg(feature = "serial")]
+            PmsError::Serial(e) => Some(e),
+        }
+    }
+}
+
+impl From<io::Error> for PmsError {
+    fn from(e: io::Error) -> Self {
+        PmsError::Io(e)
+    }
+}
+
+#[cfg(feature = "serial")]
+impl From<serialport::Error> for PmsError {
+    fn from(e: serialport::Error) -> Self {
+        PmsError::Serial(e)
+    }
+}
+
+/// Why `PmsData::validate` rejected a reading.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ValidationError {
+    FrameLength {
+        frame_length: u16,
+    },
+    Checksum {
+        expected: u16,
+        actual: u16,
+    },
+    /// A concentration is beyond the sensor's measurement range.
+    OutOfRange {
+        field: &'static str,
+        value: u16,
+    },
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ValidationError::FrameLength { frame_length } => {
+                write!(f, "unexpected frame length {}", frame_length)
+            }
+            ValidationError::Checksum { expected, actual } => write!(
+                f,
+                "checksum mismatch: expected {:#06x}, got {:#06x}",
+                expected, actual
+            ),
+            ValidationError::OutOfRange { field, value } => {
+                write!(f, "{} is out of range: {}", field, value)
+            }
+        }
+    }
+}
+
+impl Error for ValidationError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_error() {
+        let input: Vec<u8> = (0..64).collect();
+        let error = PmsError::parse(&input, 20, "tag");
+        assert!(matches!(
+            &error,
+            PmsError::Parse { context_start: 4, context, .. } if context[..] == input[4..36]
+        ));
+
+        let error = PmsError::parse(&[0x42, 0x4d, 0x00], 1, "tag");
+        assert_eq!(
+            error.to_string(),
+            "parse error at byte 1: tag; bytes from 0: 42 4d 00"
+        );
+
+        let error = PmsError::parse(&input, 100, "tag");
+        assert!(matches!(
+            &error,
+            PmsError::Parse { offset: 100, context_start: 48, context, .. }
+                if context[..] == input[48..]
+        ));
+    }
+}
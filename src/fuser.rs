@@ -0,0 +1,173 @@
+//! Fusing the readings of several co-located sensors into one, so that a
+//! single failing unit doesn't skew the result.
+
+use crate::PmsData;
+use std::collections::BTreeMap;
+use std::time::{Duration, SystemTime};
+#[cfg(feature = "prometheus")]
+use {
+    crate::aqi::AqiStandard,
+    crate::clock::{real_clock, Clock},
+    crate::metrics::LocationSink,
+    crate::sink::record_reading,
+    std::sync::{Arc, Mutex},
+};
+
+/// The `location` label of fused readings.
+pub const FUSED_LOCATION: &str = "fused";
+
+/// Keeps the latest reading of each sensor, by location.
+#[derive(Debug, Clone)]
+pub struct Fuser {
+    max_age: Duration,
+    latest: BTreeMap<String, (SystemTime, PmsData)>,
+}
+
+impl Fuser {
+    /// Readings older than `max_age` are left out of the fused value, e.g.
+    /// those of a sensor that stopped sending.
+    pub fn new(max_age: Duration) -> Self {
+        Fuser {
+            max_age,
+            latest: BTreeMap::new(),
+        }
+    }
+
+    /// Replaces the latest reading of the sensor at `location`.
+    pub fn push(&mut self, location: &str, at: SystemTime, data: PmsData) {
+        self.latest.insert(location.to_string(), (at, data));
+    }
+
+    /// Locations of the sensors whose latest reading is still fresh at `now`.
+    pub fn fresh(&self, now: SystemTime) -> Vec<&str> {
+        self.fresh_readings(now)
+            .map(|(location, _, _)| location)
+            .collect()
+    }
+
+    fn fresh_readings(
+        &self,
+        now: SystemTime,
+    ) -> impl Iterator<Item = (&str, SystemTime, &PmsData)> {
+        self.latest
+            .iter()
+            .filter_map(move |(location, (at, data))| {
+                let age = now.duration_since(*at).unwrap_or_default();
+                (age <= self.max_age).then_some((location.as_str(), *at, data))
+            })
+    }
+
+    /// The per-field median of the fresh readings, or `None` if there are
+    /// none. With an even number of readings, the two middle values are
+    /// averaged. The frame length and reserved word are taken from the most
+    /// recent reading.
+    pub fn fused(&self, now: SystemTime) -> Option<PmsData> {
+        let readings: Vec<(SystemTime, &PmsData)> = self
+            .fresh_readings(now)
+            .map(|(_, at, data)| (at, data))
+            .collect();
+        let (_, newest) = readings.iter().max_by_key(|(at, _)| *at)?;
+        let mut words = newest.words();
+        for (i, word) in words.iter_mut().enumerate().take(13).skip(1) {
+            let mut values: Vec<u16> = readings.iter().map(|(_, data)| data.words()[i]).collect();
+            values.sort_unstable();
+            let mid = values.len() / 2;
+            *word = if values.len().is_multiple_of(2) {
+                ((u32::from(values[mid - 1]) + u32::from(values[mid])) / 2) as u16
+            } else {
+                values[mid]
+            };
+        }
+        Some(PmsData::from_words(words))
+    }
+}
+
+/// A callback for the sensor at `location`, e.g. for `read_multi`. Each
+/// reading is exported under its `location` as with `LocationSink`, and the
+/// fused reading of all sensors sharing `fuser` under `FUSED_LOCATION`.
+#[cfg(feature = "prometheus")]
+pub fn fused_callback(
+    fuser: Arc<Mutex<Fuser>>,
+    location: &str,
+    standards: Vec<AqiStandard>,
+) -> impl FnMut(PmsData) {
+    fused_callback_with_clock(fuser, location, standards, real_clock())
+}
+
+/// Like `fused_callback`, with the time of each reading taken from `clock`.
+#[cfg(feature = "prometheus")]
+pub fn fused_callback_with_clock(
+    fuser: Arc<Mutex<Fuser>>,
+    location: &str,
+    standards: Vec<AqiStandard>,
+    clock: Arc<dyn Clock>,
+) -> impl FnMut(PmsData) {
+    let sink = LocationSink::new(location);
+    let fused_sink = LocationSink::new(FUSED_LOCATION);
+    let location = location.to_string();
+    move |data| {
+        record_reading(&sink, &data, &standards);
+        let now = clock.now();
+        let fused = {
+            let mut fuser = fuser.lock().unwrap();
+            fuser.push(&location, now, data);
+            fuser.fused(now)
+        };
+        if let Some(fused) = fused {
+            record_reading(&fused_sink, &fused, &standards);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::at;
+
+    fn reading(pm2_5: u16) -> PmsData {
+        let mut words = [0; 13];
+        words[1] = pm2_5;
+        words[7] = pm2_5 * 10;
+        PmsData::from(words)
+    }
+
+    #[test]
+    fn test_fused() {
+        let mut fuser = Fuser::new(Duration::from_secs(10));
+        assert_eq!(fuser.fused(at(0)), None);
+
+        fuser.push("a", at(100), reading(10));
+        fuser.push("b", at(101), reading(12));
+        // Broken, and ignored by the median.
+        fuser.push("c", at(102), reading(900));
+        let fused = fuser.fused(at(105)).unwrap();
+        assert_eq!(fused.pm2_5_cf1, 12);
+        assert_eq!(fused.pm0_5_count, 120);
+        assert!(fused.has_valid_checksum());
+
+        // "a" is stale, leaving an even number of readings.
+        assert_eq!(fuser.fresh(at(111)), ["b", "c"]);
+        assert_eq!(fuser.fused(at(111)).unwrap().pm2_5_cf1, 456);
+        assert_eq!(fuser.fused(at(200)), None);
+    }
+
+    #[cfg(feature = "prometheus")]
+    #[test]
+    fn test_fused_callback_with_clock() {
+        let clock = crate::clock::MockClock::new(at(100));
+        let fuser = Arc::new(Mutex::new(Fuser::new(Duration::from_secs(10))));
+        let callback = |location| {
+            fused_callback_with_clock(
+                Arc::clone(&fuser),
+                location,
+                Vec::new(),
+                Arc::new(clock.clone()),
+            )
+        };
+        let (mut a, mut b) = (callback("a"), callback("b"));
+        a(reading(10));
+        clock.advance(Duration::from_secs(20));
+        b(reading(12));
+        assert_eq!(fuser.lock().unwrap().fresh(at(120)), ["b"]);
+    }
+}
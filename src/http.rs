@@ -0,0 +1,345 @@
+//! A tiny HTTP endpoint that serves the latest reading as JSON, for setups
+//! without a metrics backend:
+//!
+//! ```text
+//! $ curl http://localhost:9955/current
+//! {"timestamp":1700000000.0,"age_seconds":0.4,"pm1_cf1":3,...,"aqi":[...]}
+//! ```
+//!
+//! The reading is the one most recently passed to `update_metrics`, i.e. the
+//! latest trusted reading when used with `default_callback`.
+//!
+//! With an `EventStream`, `/events` also pushes every reading published to
+//! it as a Server-Sent Event, for live browser displays:
+//!
+//! ```text
+//! new EventSource("/events").onmessage = (e) => show(JSON.parse(e.data));
+//! ```
+
+use crate::aqi::{calculate_aqi, AqiStandard};
+use crate::metrics::{self, last_reading_at};
+use crate::{ConcentrationBasis, PmsData, Pollutant};
+use log::{info, warn};
+use serde::Serialize;
+use std::io::{self, Write};
+use std::net::ToSocketAddrs;
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tiny_http::{Header, Response, Server};
+
+/// Readings older than this are not served by default, since the sensor
+/// sends one about every second.
+pub const DEFAULT_MAX_AGE: Duration = Duration::from_secs(60);
+
+/// Events queued for a client that hasn't received them yet. A client that
+/// falls further behind is disconnected.
+const EVENT_BUFFER: usize = 16;
+
+/// Fans readings out to the clients connected to `/events`. Clones publish
+/// to the same clients.
+#[derive(Debug, Clone, Default)]
+pub struct EventStream {
+    clients: Arc<Mutex<Vec<SyncSender<Arc<str>>>>>,
+}
+
+impl EventStream {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sends `data` as JSON to every connected client. Clients that have
+    /// disconnected or have `EVENT_BUFFER` events queued are dropped.
+    pub fn publish(&self, data: &PmsData) {
+        let event: Arc<str> = serde_json::to_string(data).unwrap().into();
+        self.clients
+            .lock()
+            .unwrap()
+            .retain(|client| match client.try_send(event.clone()) {
+                Ok(()) => true,
+                Err(TrySendError::Full(_)) => {
+                    warn!("dropping an event stream client that is falling behind");
+                    false
+                }
+                Err(TrySendError::Disconnected(_)) => false,
+            });
+    }
+
+    /// Number of connected clients.
+    pub fn clients(&self) -> usize {
+        self.clients.lock().unwrap().len()
+    }
+
+    fn subscribe(&self) -> Receiver<Arc<str>> {
+        let (sender, receiver) = mpsc::sync_channel(EVENT_BUFFER);
+        self.clients.lock().unwrap().push(sender);
+        receiver
+    }
+}
+
+/// Writes the events from `receiver` to `out` until the client disconnects
+/// or is dropped by `EventStream::publish`.
+fn stream_events<W: Write>(mut out: W, receiver: Receiver<Arc<str>>) -> io::Result<()> {
+    out.write_all(
+        b"HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\n\
+          Cache-Control: no-cache\r\nConnection: keep-alive\r\n\r\n",
+    )?;
+    out.flush()?;
+    for event in receiver {
+        write!(out, "data: {}\n\n", event)?;
+        out.flush()?;
+    }
+    Ok(())
+}
+
+#[derive(Serialize)]
+struct Current<'a> {
+    /// Seconds since the Unix epoch.
+    timestamp: f64,
+    age_seconds: f64,
+    #[serde(flatten)]
+    data: &'a PmsData,
+    aqi: Vec<Aqi>,
+}
+
+/// One `air_quality_index` series, with the same labels.
+#[derive(Serialize)]
+struct Aqi {
+    particle_size: &'static str,
+    standard: &'static str,
+    concentration_basis: &'static str,
+    value: Option<u32>,
+}
+
+#[derive(Serialize)]
+struct Unavailable {
+    error: String,
+    age_seconds: Option<f64>,
+}
+
+/// The status code and body of a `/current` response at `now`.
+fn current(
+    now: SystemTime,
+    reading: Option<(SystemTime, PmsData)>,
+    standards: &[AqiStandard],
+    max_age: Duration,
+) -> (u16, String) {
+    let (at, data) = match reading {
+        Some(reading) => reading,
+        None => {
+            let body = Unavailable {
+                error: "no trusted reading yet; the sensor may still be settling".to_string(),
+                age_seconds: None,
+            };
+            return (503, serde_json::to_string(&body).unwrap());
+        }
+    };
+    let age = now.duration_since(at).unwrap_or_default();
+    if age > max_age {
+        let body = Unavailable {
+            error: format!(
+                "the latest reading is {:.0} s old, more than {:.0} s",
+                age.as_secs_f64(),
+                max_age.as_secs_f64()
+            ),
+            age_seconds: Some(age.as_secs_f64()),
+        };
+        return (503, serde_json::to_string(&body).unwrap());
+    }
+    let mut aqi = Vec::new();
+    for &standard in standards {
+        for basis in ConcentrationBasis::ALL {
+            for (pollutant, breakpoints) in [
+                (Pollutant::Pm2_5, standard.pm2_5()),
+                (Pollutant::Pm10, standard.pm10()),
+            ] {
+                aqi.push(Aqi {
+                    particle_size: pollutant.label(),
+                    standard: standard.label(),
+                    concentration_basis: basis.label(),
+                    value: calculate_aqi(breakpoints, data.concentration(pollutant, basis)),
+                });
+            }
+        }
+    }
+    let body = Current {
+        timestamp: at
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs_f64(),
+        age_seconds: age.as_secs_f64(),
+        data: &data,
+        aqi,
+    };
+    (200, serde_json::to_string(&body).unwrap())
+}
+
+/// Serves `GET /current` on `addr` from a background thread, with the AQI
+/// for each of `standards`. Responds with 503 if there is no reading yet or
+/// it is older than `max_age`.
+pub fn serve_current<A: ToSocketAddrs>(
+    addr: A,
+    standards: Vec<AqiStandard>,
+    max_age: Duration,
+) -> io::Result<thread::JoinHandle<()>> {
+    serve_with_events(addr, standards, max_age, None)
+}
+
+/// Like `serve_current`, but with `events` also serves `GET /events`, with
+/// each client streamed from a thread of its own.
+pub fn serve_with_events<A: ToSocketAddrs>(
+    addr: A,
+    standards: Vec<AqiStandard>,
+    max_age: Duration,
+    events: Option<EventStream>,
+) -> io::Result<thread::JoinHandle<()>> {
+    let server = Server::http(addr).map_err(io::Error::other)?;
+    info!(
+        "serving the latest reading on http://{}/current",
+        server.server_addr()
+    );
+    Ok(thread::spawn(move || {
+        for request in server.incoming_requests() {
+            if let (Some(events), "/events") = (&events, request.url()) {
+                let receiver = events.subscribe();
+                thread::spawn(move || {
+                    if let Err(e) = stream_events(request.into_writer(), receiver) {
+                        info!("event stream client disconnected: {}", e);
+                    }
+                });
+                continue;
+            }
+            let (status, body) = if request.url() == "/current" {
+                current(metrics::now(), last_reading_at(), &standards, max_age)
+            } else {
+                (404, r#"{"error":"not found"}"#.to_string())
+            };
+            let content_type = Header::from_bytes("Content-Type", "application/json").unwrap();
+            let response = Response::from_string(body)
+                .with_status_code(status)
+                .with_header(content_type);
+            if let Err(e) = request.respond(response) {
+                warn!("could not send response: {}", e);
+            }
+        }
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse;
+    use crate::GOLDEN_PACKET;
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+
+    #[test]
+    fn test_current() {
+        let data = parse(GOLDEN_PACKET).unwrap().1.unwrap();
+        let at = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let standards = [AqiStandard::UsEpa];
+
+        let (status, body) = current(at, None, &standards, DEFAULT_MAX_AGE);
+        assert_eq!(status, 503);
+        assert!(body.contains("no trusted reading"), "{}", body);
+
+        let now = at + Duration::from_secs(2);
+        let (status, body) = current(now, Some((at, data.clone())), &standards, DEFAULT_MAX_AGE);
+        assert_eq!(status, 200);
+        let json: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(json["age_seconds"], 2.0);
+        assert_eq!(json["pm2_5_cf1"], 4);
+        assert_eq!(json["aqi"].as_array().unwrap().len(), 4);
+        assert_eq!(json["aqi"][0]["particle_size"], "2.5");
+        assert_eq!(json["aqi"][0]["value"], 17);
+
+        let later = at + Duration::from_secs(120);
+        let (status, body) = current(later, Some((at, data)), &standards, DEFAULT_MAX_AGE);
+        assert_eq!(status, 503);
+        assert!(body.contains("120 s old"), "{}", body);
+    }
+
+    #[test]
+    fn test_event_stream() {
+        let data = parse(GOLDEN_PACKET).unwrap().1.unwrap();
+        let events = EventStream::new();
+        let fast = events.subscribe();
+        let slow = events.subscribe();
+        let gone = events.subscribe();
+        drop(gone);
+        events.publish(&data);
+        assert_eq!(events.clients(), 2);
+        assert!(fast.recv().unwrap().contains(r#""pm2_5_cf1":4"#));
+
+        for _ in 1..EVENT_BUFFER {
+            events.publish(&data);
+            fast.recv().unwrap();
+        }
+        // `slow` has a full buffer now.
+        events.publish(&data);
+        assert_eq!(events.clients(), 1);
+        assert_eq!(slow.iter().count(), EVENT_BUFFER);
+
+        let mut out = Vec::new();
+        drop(events);
+        stream_events(&mut out, fast).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(
+            out.contains("Content-Type: text/event-stream\r\n"),
+            "{}",
+            out
+        );
+        assert!(out.ends_with("}\n\n"), "{}", out);
+    }
+
+    #[test]
+    fn test_serve_events() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        drop(listener);
+        let events = EventStream::new();
+        serve_with_events(
+            addr,
+            vec![AqiStandard::UsEpa],
+            DEFAULT_MAX_AGE,
+            Some(events.clone()),
+        )
+        .unwrap();
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream
+            .write_all(b"GET /events HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .unwrap();
+        while events.clients() == 0 {
+            thread::sleep(Duration::from_millis(1));
+        }
+        events.publish(&parse(GOLDEN_PACKET).unwrap().1.unwrap());
+        let mut response = String::new();
+        let mut buf = [0; 512];
+        while !response.contains("}\n\n") {
+            let n = stream.read(&mut buf).unwrap();
+            assert!(n > 0, "{}", response);
+            response.push_str(std::str::from_utf8(&buf[..n]).unwrap());
+        }
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+        assert!(response.contains("data: {"), "{}", response);
+    }
+
+    #[test]
+    fn test_serve_current() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        drop(listener);
+        serve_current(addr, vec![AqiStandard::UsEpa], DEFAULT_MAX_AGE).unwrap();
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream
+            .write_all(b"GET /other HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 404"), "{}", response);
+        assert!(response.contains("application/json"), "{}", response);
+    }
+}
@@ -1,57 +1,103 @@
-extern crate aqi;
 extern crate lazy_static;
 extern crate nom;
+#[cfg(feature = "prometheus")]
 extern crate prometheus_exporter;
+#[cfg(feature = "serial")]
 extern crate serialport;
 
-use lazy_static::lazy_static;
-use log::{debug, error, info};
 use nom::branch::alt;
 use nom::bytes::streaming::tag;
 use nom::bytes::streaming::take;
-use nom::bytes::streaming::take_until;
 use nom::combinator::map;
-use nom::number::streaming::be_u16;
+use nom::number::streaming::{be_u16, be_u8, u16};
 use nom::sequence::tuple;
 use nom::IResult;
-use nom::Needed;
-use prometheus_exporter::prometheus::{register_gauge_vec, Gauge, GaugeVec};
-use std::error::Error;
-use std::num::NonZeroUsize;
-use std::thread;
-use std::time::{Duration, SystemTime};
-
-lazy_static! {
-    pub static ref PARTICLE_CONCENTRATION_STANDARD: GaugeVec = register_gauge_vec!(
-        "particle_concentration_standard",
-        "concentration (CF=1 standard particle) µg/m³",
-        &["particle_size"]
-    )
-    .unwrap();
-    pub static ref PARTICLE_CONCENTRATION_ENVIRONMENT: GaugeVec = register_gauge_vec!(
-        "particle_concentration_environment",
-        "concentration (under atmospheric environment) µg/m³",
-        &["particle_size"]
-    )
-    .unwrap();
-    pub static ref PARTICLE_COUNT: GaugeVec = register_gauge_vec!(
-        "particle_count",
-        "number of particles with diameter beyond particle_size",
-        &["particle_size"]
-    )
-    .unwrap();
-    pub static ref AIR_QUALITY_INDEX: GaugeVec = register_gauge_vec!(
-        "air_quality_index",
-        "air quality index (aqi) defined by united states environmental protection agency (us epa)",
-        &["particle_size"]
-    )
-    .unwrap();
-}
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+pub mod action;
+pub mod aqi;
+mod callback;
+pub mod clock;
+pub mod config;
+pub mod derived;
+mod error;
+pub mod fuser;
+#[cfg(feature = "http")]
+pub mod http;
+pub mod logfile;
+#[cfg(feature = "prometheus")]
+pub mod metrics;
+#[cfg(feature = "serial")]
+pub mod pi;
+pub mod position;
+#[cfg(feature = "serial")]
+mod reader;
+#[cfg(feature = "remote-write")]
+pub mod remote_write;
+#[cfg(feature = "serial")]
+pub mod replay;
+pub mod sink;
+pub mod stats;
+
+pub use action::{action_callback, action_callback_with_clock};
+pub use aqi::{aqi_category, aqi_color_rgb, calculate_aqi, concentration_for_aqi, AqiCategory};
+pub use callback::{
+    category_callback, category_callback_with_clock, dedup, downsample, downsample_flagged,
+    downsample_with_clock, ring_buffer_callback, ring_buffer_callback_with_clock, trusted_callback,
+    CallbackBuilder, FanOut,
+};
+#[cfg(feature = "prometheus")]
+pub use callback::{default_callback, downsampled_callback, downsampled_metrics};
+#[cfg(feature = "serde")]
+pub use callback::{
+    jsonl_callback, jsonl_callback_with_clock, jsonl_callback_with_position, jsonl_file_callback,
+    jsonl_file_callback_with_clock, jsonl_file_callback_with_position,
+};
+pub use derived::{
+    cigarette_equivalent, estimate_visibility_km, iaq_score, purifier_effectiveness,
+};
+pub use error::{PmsError, ValidationError};
+#[cfg(feature = "prometheus")]
+pub use metrics::{
+    last_reading, reset_metrics, update_aqi, update_metrics, update_sensor_version,
+    AIR_QUALITY_INDEX, PARTICLE_CONCENTRATION_ENVIRONMENT, PARTICLE_CONCENTRATION_STANDARD,
+    PARTICLE_COUNT, SENSOR_VERSION,
+};
+#[cfg(feature = "serial")]
+pub use reader::{
+    join_all, read_active, read_active_with_config, read_active_with_quality,
+    read_active_with_watchdog, read_multi, ChecksumPolicy, Config, ErrorRateHook, Pms, Port,
+    ReadStrategy, SensorConfig, SensorHandle, ValidationMode, Validator,
+};
+#[cfg(feature = "signals")]
+pub use reader::{read_active_until_signal, stop_on_signal};
+pub use sink::{record_reading, ConcentrationBasis, MetricsSink};
 
 const START_MARKER: &str = "\x42\x4d";
-const BAUD_RATE: u32 = 9600;
+const DATA_FRAME_LENGTH: u16 = 28;
+/// Frame length of clones that omit the reserved word (30-byte frames).
+const SHORT_DATA_FRAME_LENGTH: u16 = 26;
+/// Frame length of `Variant::Pms5003st` data frames (40-byte frames).
+const EXTENDED_DATA_FRAME_LENGTH: u16 = 36;
+const ACK_FRAME_LENGTH: u16 = 4;
+/// The start marker and the frame length, which precede the part of a frame
+/// counted by the frame length.
+const HEADER_SIZE: usize = 4;
+/// Size in bytes of a data frame: the header, 13 data words and the checksum.
+pub const FRAME_SIZE: usize = frame_size(DATA_FRAME_LENGTH);
+/// Size in bytes of the largest supported frame, an extended data frame.
+#[cfg(feature = "serial")]
+pub(crate) const MAX_FRAME_SIZE: usize = frame_size(EXTENDED_DATA_FRAME_LENGTH);
+/// Upper end of the PM2.5 range in µg/m³ within which the datasheet's
+/// accuracy applies.
+pub const PM2_5_EFFECTIVE_MAX_UG_M3: u16 = 500;
+/// Upper end of the sensor's maximum measurement range in µg/m³. Readings
+/// with any concentration beyond it are `Quality::OutOfRange`.
+pub const PM2_5_MAX_UG_M3: u16 = 1000;
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct PmsData {
     frame_length: u16,
     pm1_cf1: u16,
@@ -66,49 +112,122 @@ pub struct PmsData {
     pm2_5_count: u16,
     pm5_0_count: u16,
     pm10_0_count: u16,
-    reserved: u16,
+    /// `None` for frames without a reserved word.
+    reserved: Option<u16>,
+    /// Only set for frames read as `Variant::Pms5003st`.
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "Option::is_none")
+    )]
+    extended: Option<Extended>,
     checksum: u16,
 }
 
-fn parse_data(input: &[u8]) -> IResult<&[u8], PmsData> {
-    map(
-        tuple((
-            tag(START_MARKER),
-            be_u16, // frame length
-            be_u16, // data 1
-            be_u16, // data 2
-            be_u16, // ...
-            be_u16,
-            be_u16,
-            be_u16,
-            be_u16,
-            be_u16,
-            be_u16,
-            be_u16,
-            be_u16,
-            be_u16,
-            be_u16, // data 13
-            be_u16, // checksum
-        )),
-        |(
-            _start_marker,
+/// The words that `Variant::Pms5003st` sends between the particle counts and
+/// the reserved word.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+struct Extended {
+    /// In 0.001 mg/m³.
+    formaldehyde: u16,
+    /// In 0.1 °C, two's complement.
+    temperature: u16,
+    /// In 0.1 %.
+    humidity: u16,
+    reserved: u16,
+}
+
+/// The sensor model, which decides how data frames with a frame length other
+/// than that of a PMS7003 are read.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Variant {
+    /// Only standard and short frames are data frames; longer ones are
+    /// skipped as `Frame::Unknown`.
+    #[default]
+    Pms7003,
+    /// Also read 40-byte frames, with formaldehyde, temperature and humidity
+    /// after the particle counts, as sent by the PMS5003ST. See
+    /// `PmsData::temperature` and `PmsData::humidity`.
+    Pms5003st,
+}
+
+impl Variant {
+    pub const ALL: [Variant; 2] = [Variant::Pms7003, Variant::Pms5003st];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Variant::Pms7003 => "pms7003",
+            Variant::Pms5003st => "pms5003st",
+        }
+    }
+}
+
+impl std::str::FromStr for Variant {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Variant::ALL
+            .iter()
+            .copied()
+            .find(|variant| variant.name() == s)
+            .ok_or_else(|| format!("unknown sensor variant: {}", s))
+    }
+}
+
+/// Byte order of the data words and checksum in a data frame.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Endianness {
+    /// As sent by the sensor.
+    #[default]
+    Big,
+    /// For adapters that swap the bytes of each word. The start marker and
+    /// frame length are still expected in the sensor's byte order.
+    Little,
+}
+
+impl From<Endianness> for nom::number::Endianness {
+    fn from(endianness: Endianness) -> Self {
+        match endianness {
+            Endianness::Big => nom::number::Endianness::Big,
+            Endianness::Little => nom::number::Endianness::Little,
+        }
+    }
+}
+
+/// Parses a data frame with any layout, as given by its frame length: 13
+/// data words, 12 without the reserved word, or 17 for an extended frame.
+fn parse_data(input: &[u8], endianness: Endianness) -> IResult<&[u8], PmsData> {
+    let word = || u16(endianness.into());
+    let (input, (_start_marker, frame_length)) = tuple((tag(START_MARKER), be_u16))(input)?;
+    let (input, (data1, data2, data3, data4, data5, data6)) =
+        tuple((word(), word(), word(), word(), word(), word()))(input)?;
+    let (input, (data7, data8, data9, data10, data11, data12)) =
+        tuple((word(), word(), word(), word(), word(), word()))(input)?;
+    let (input, extended) = if frame_length == EXTENDED_DATA_FRAME_LENGTH {
+        map(
+            tuple((word(), word(), word(), word())),
+            |(formaldehyde, temperature, humidity, reserved)| {
+                Some(Extended {
+                    formaldehyde,
+                    temperature,
+                    humidity,
+                    reserved,
+                })
+            },
+        )(input)?
+    } else {
+        (input, None)
+    };
+    let (input, reserved) = if frame_length == SHORT_DATA_FRAME_LENGTH {
+        (input, None)
+    } else {
+        map(word(), Some)(input)?
+    };
+    let (input, checksum) = word()(input)?;
+    Ok((
+        input,
+        PmsData {
             frame_length,
-            data1,
-            data2,
-            data3,
-            data4,
-            data5,
-            data6,
-            data7,
-            data8,
-            data9,
-            data10,
-            data11,
-            data12,
-            data13,
-            checksum,
-        )| PmsData {
-            frame_length: frame_length,
             pm1_cf1: data1,
             pm2_5_cf1: data2,
             pm10_cf1: data3,
@@ -121,185 +240,522 @@ fn parse_data(input: &[u8]) -> IResult<&[u8], PmsData> {
             pm2_5_count: data10,
             pm5_0_count: data11,
             pm10_0_count: data12,
-            reserved: data13,
-            checksum: checksum,
+            reserved,
+            extended,
+            checksum,
+        },
+    ))
+}
+
+/// How far a reading can be trusted. If several apply, the first one listed
+/// is reported.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Quality {
+    Good,
+    /// The checksum doesn't match. Only delivered with
+    /// `ValidationMode::Lenient`.
+    CheckFailed,
+    /// A concentration is beyond the sensor's measurement range.
+    OutOfRange,
+    /// The frame was received more than `Config::max_frame_age` before it
+    /// was delivered.
+    Stale,
+}
+
+/// Response to a command sent to the sensor.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AckFrame {
+    pub command: u8,
+    pub data: u8,
+    pub checksum: u16,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Frame {
+    Data(PmsData),
+    Ack(AckFrame),
+    /// A frame with an unrecognized frame length. Only its header is
+    /// consumed.
+    Unknown,
+}
+
+fn parse_ack(input: &[u8]) -> IResult<&[u8], AckFrame> {
+    map(
+        tuple((tag(START_MARKER), be_u16, be_u8, be_u8, be_u16)),
+        |(_start_marker, _frame_length, command, data, checksum)| AckFrame {
+            command,
+            data,
+            checksum,
         },
     )(input)
 }
 
-pub fn parse(input: &[u8]) -> IResult<&[u8], Option<PmsData>> {
-    alt((map(parse_data, Some), map(take(1usize), |_| None)))(input)
+/// A command that can be sent to the sensor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Command {
+    /// Leave sleep mode. The fan needs about 30 seconds to spin up before
+    /// readings are reliable.
+    Wake,
+    Sleep,
+    /// Send data frames continuously. This is the factory default.
+    ActiveMode,
+    /// Only send a data frame when asked to with `PassiveRead`.
+    PassiveMode,
+    PassiveRead,
 }
 
-pub fn default_callback(settle_time: Duration, echo: bool) -> Box<FnMut(PmsData)> {
-    let mut start_time = None;
-    Box::new(move |data| {
-        if start_time == None {
-            start_time = Some(SystemTime::now());
-            if echo && settle_time > Duration::from_secs(0) {
-                println!("Waiting {:?} until data is trusted...", settle_time);
-            }
+impl Command {
+    /// The command frame: start marker, command, two data bytes and the
+    /// checksum.
+    pub fn to_bytes(self) -> [u8; 7] {
+        let (command, data) = match self {
+            Command::Wake => (0xe4, 0x0001u16),
+            Command::Sleep => (0xe4, 0x0000),
+            Command::ActiveMode => (0xe1, 0x0001),
+            Command::PassiveMode => (0xe1, 0x0000),
+            Command::PassiveRead => (0xe2, 0x0000),
+        };
+        let mut frame = [0u8; 7];
+        frame[..2].copy_from_slice(START_MARKER.as_bytes());
+        frame[2] = command;
+        frame[3..5].copy_from_slice(&data.to_be_bytes());
+        let checksum = compute_checksum(&frame[..5]);
+        frame[5..].copy_from_slice(&checksum.to_be_bytes());
+        frame
+    }
+}
+
+/// Parses the next frame, dispatching on its frame length. Returns `None` if
+/// `input` doesn't start with a start marker, after consuming one byte.
+pub fn parse_frame(input: &[u8]) -> IResult<&[u8], Option<Frame>> {
+    parse_frame_with(input, Endianness::Big)
+}
+
+/// Like `parse_frame`, but reads the words of data frames in `endianness`.
+pub fn parse_frame_with(input: &[u8], endianness: Endianness) -> IResult<&[u8], Option<Frame>> {
+    parse_frame_variant(input, endianness, Variant::Pms7003)
+}
+
+/// Like `parse_frame_with`, but also reads the data frames specific to
+/// `variant`.
+pub fn parse_frame_variant(
+    input: &[u8],
+    endianness: Endianness,
+    variant: Variant,
+) -> IResult<&[u8], Option<Frame>> {
+    let frame_length = match tuple((tag(START_MARKER), be_u16))(input) {
+        Ok((_, (_start_marker, frame_length))) => frame_length,
+        Err(nom::Err::Error(_)) => return map(take(1usize), |_| None)(input),
+        Err(e) => return Err(e),
+    };
+    match frame_length {
+        DATA_FRAME_LENGTH | SHORT_DATA_FRAME_LENGTH => map(
+            |input| parse_data(input, endianness),
+            |data| Some(Frame::Data(data)),
+        )(input),
+        EXTENDED_DATA_FRAME_LENGTH if variant == Variant::Pms5003st => map(
+            |input| parse_data(input, endianness),
+            |data| Some(Frame::Data(data)),
+        )(input),
+        ACK_FRAME_LENGTH => map(parse_ack, |ack| Some(Frame::Ack(ack)))(input),
+        _ => map(take(4usize), |_| Some(Frame::Unknown))(input),
+    }
+}
+
+/// A particle size reported as a mass concentration.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Pollutant {
+    Pm1_0,
+    Pm2_5,
+    Pm10,
+}
+
+impl Pollutant {
+    pub const ALL: [Pollutant; 3] = [Pollutant::Pm1_0, Pollutant::Pm2_5, Pollutant::Pm10];
+
+    /// Value of the `particle_size` metric label.
+    pub fn label(self) -> &'static str {
+        match self {
+            Pollutant::Pm1_0 => "1.0",
+            Pollutant::Pm2_5 => "2.5",
+            Pollutant::Pm10 => "10.0",
         }
-        if let Ok(duration) = start_time.unwrap().elapsed() {
-            if duration < settle_time {
-                info!(
-                    "{:?} until data is trusted, ignoring: {:?}",
-                    settle_time - duration,
-                    data
-                );
-                return;
-            }
+    }
+}
+
+impl PmsData {
+    /// Firmware version, carried in the high byte of the reserved word on
+    /// most units. `None` for frames without a reserved word.
+    pub fn version(&self) -> Option<u8> {
+        self.reserved.map(|reserved| (reserved >> 8) as u8)
+    }
+
+    /// Mass concentration of `pollutant` in µg/m³, from the CF=1 values for
+    /// `ConcentrationBasis::Standard` and the atmospheric ones for
+    /// `ConcentrationBasis::Environment`. AQI is defined on ambient
+    /// concentrations, i.e. the latter.
+    pub fn concentration(&self, pollutant: Pollutant, basis: ConcentrationBasis) -> f64 {
+        let value = match (basis, pollutant) {
+            (ConcentrationBasis::Standard, Pollutant::Pm1_0) => self.pm1_cf1,
+            (ConcentrationBasis::Standard, Pollutant::Pm2_5) => self.pm2_5_cf1,
+            (ConcentrationBasis::Standard, Pollutant::Pm10) => self.pm10_cf1,
+            (ConcentrationBasis::Environment, Pollutant::Pm1_0) => self.pm1_atmo,
+            (ConcentrationBasis::Environment, Pollutant::Pm2_5) => self.pm2_5_atmo,
+            (ConcentrationBasis::Environment, Pollutant::Pm10) => self.pm10_atmo,
+        };
+        value.into()
+    }
+
+    /// The US EPA AQI of the reading: the higher of the PM2.5 and PM10
+    /// sub-indices of the concentrations in `basis`. `None` if either is
+    /// beyond the index.
+    pub fn aqi(&self, basis: ConcentrationBasis) -> Option<u32> {
+        let pm2_5 = aqi::calculate_aqi(
+            &aqi::US_EPA_PM2_5,
+            self.concentration(Pollutant::Pm2_5, basis),
+        )?;
+        let pm10 = aqi::calculate_aqi(
+            &aqi::US_EPA_PM10,
+            self.concentration(Pollutant::Pm10, basis),
+        )?;
+        Some(pm2_5.max(pm10))
+    }
+
+    /// PM1.0 in µg/m³, CF=1 standard particle.
+    pub fn pm1_cf1(&self) -> u16 {
+        self.pm1_cf1
+    }
+
+    /// PM2.5 in µg/m³, CF=1 standard particle.
+    pub fn pm2_5_cf1(&self) -> u16 {
+        self.pm2_5_cf1
+    }
+
+    /// PM10 in µg/m³, CF=1 standard particle.
+    pub fn pm10_cf1(&self) -> u16 {
+        self.pm10_cf1
+    }
+
+    /// PM1.0 in µg/m³, under atmospheric environment.
+    pub fn pm1_atmo(&self) -> u16 {
+        self.pm1_atmo
+    }
+
+    /// PM2.5 in µg/m³, under atmospheric environment.
+    pub fn pm2_5_atmo(&self) -> u16 {
+        self.pm2_5_atmo
+    }
+
+    /// PM10 in µg/m³, under atmospheric environment.
+    pub fn pm10_atmo(&self) -> u16 {
+        self.pm10_atmo
+    }
+}
+
+impl PmsData {
+    /// The frame's words before the checksum, starting with the frame length.
+    /// A missing reserved word is 0; only the first `word_count` words are
+    /// part of the frame.
+    fn words(&self) -> [u16; 14] {
+        [
+            self.frame_length,
+            self.pm1_cf1,
+            self.pm2_5_cf1,
+            self.pm10_cf1,
+            self.pm1_atmo,
+            self.pm2_5_atmo,
+            self.pm10_atmo,
+            self.pm0_3_count,
+            self.pm0_5_count,
+            self.pm1_0_count,
+            self.pm2_5_count,
+            self.pm5_0_count,
+            self.pm10_0_count,
+            self.reserved.unwrap_or(0),
+        ]
+    }
+
+    fn word_count(&self) -> usize {
+        if self.reserved.is_some() {
+            14
+        } else {
+            13
         }
-        update_metrics(&data);
-        if echo {
-            println!("------------------------------------------------");
-            println!("Concentration units (standard)");
-            println!(
-                "pm1.0: {}\tpm2.5: {}\tpm10.0: {}",
-                data.pm1_cf1, data.pm2_5_cf1, data.pm10_cf1
-            );
-            println!();
-            println!("Concentration units (environmental)");
-            println!(
-                "pm1.0: {}\tpm2.5: {}\tpm10.0: {}",
-                data.pm1_atmo, data.pm2_5_atmo, data.pm10_atmo
-            );
-            println!();
-            println!("Particle counts");
-            println!(
-                "pm0.3: {}\tpm0.5: {}\tpm1.0: {}",
-                data.pm0_3_count, data.pm0_5_count, data.pm1_0_count
-            );
-            println!(
-                "pm2.5: {}\tpm5.0: {}\tpm10.0: {}",
-                data.pm2_5_count, data.pm5_0_count, data.pm10_0_count
-            );
-            println!("------------------------------------------------");
+    }
+
+    /// The inverse of `words`. The checksum is computed from the other
+    /// words, and the reserved word is dropped if the frame length is that
+    /// of a frame without one.
+    pub(crate) fn from_words(words: [u16; 14]) -> PmsData {
+        let mut data = PmsData {
+            frame_length: words[0],
+            pm1_cf1: words[1],
+            pm2_5_cf1: words[2],
+            pm10_cf1: words[3],
+            pm1_atmo: words[4],
+            pm2_5_atmo: words[5],
+            pm10_atmo: words[6],
+            pm0_3_count: words[7],
+            pm0_5_count: words[8],
+            pm1_0_count: words[9],
+            pm2_5_count: words[10],
+            pm5_0_count: words[11],
+            pm10_0_count: words[12],
+            reserved: Some(words[13]).filter(|_| words[0] != SHORT_DATA_FRAME_LENGTH),
+            extended: None,
+            checksum: 0,
+        };
+        data.checksum = data.expected_checksum();
+        data
+    }
+
+    /// The words of the frame as sent, before the checksum: unlike `words`,
+    /// this includes those of an extended frame.
+    fn frame_words(&self) -> Vec<u16> {
+        let words = self.words();
+        let mut frame_words = words[..13].to_vec();
+        if let Some(extended) = &self.extended {
+            frame_words.extend_from_slice(&[
+                extended.formaldehyde,
+                extended.temperature,
+                extended.humidity,
+                extended.reserved,
+            ]);
         }
-    })
-}
+        frame_words.extend_from_slice(&words[13..self.word_count()]);
+        frame_words
+    }
 
-pub fn update_aqi(value: aqi::Result<aqi::AirQuality>, metric: &Gauge) {
-    let air_quality = match value {
-        Err(e) => {
-            error!("Could not compute AQI: {}", e);
-            return;
+    fn expected_checksum(&self) -> u16 {
+        self.frame_words()
+            .iter()
+            .fold(compute_checksum(START_MARKER.as_bytes()), |sum, word| {
+                sum.wrapping_add(compute_checksum(&word.to_be_bytes()))
+            })
+    }
+
+    /// Checks the frame length, the checksum against the other fields, and
+    /// that the concentrations are within the sensor's range. Useful for
+    /// readings that weren't produced by the parser, e.g. deserialized ones.
+    pub fn validate(&self) -> Result<(), ValidationError> {
+        let expected_length = match (self.extended, self.reserved) {
+            (Some(_), _) => EXTENDED_DATA_FRAME_LENGTH,
+            (None, Some(_)) => DATA_FRAME_LENGTH,
+            (None, None) => SHORT_DATA_FRAME_LENGTH,
+        };
+        if self.frame_length != expected_length {
+            return Err(ValidationError::FrameLength {
+                frame_length: self.frame_length,
+            });
         }
-        Ok(v) => metric.set(v.aqi().into()),
-    };
-}
+        let expected = self.expected_checksum();
+        if expected != self.checksum {
+            return Err(ValidationError::Checksum {
+                expected,
+                actual: self.checksum,
+            });
+        }
+        let concentrations = [
+            ("pm1_cf1", self.pm1_cf1),
+            ("pm2_5_cf1", self.pm2_5_cf1),
+            ("pm10_cf1", self.pm10_cf1),
+            ("pm1_atmo", self.pm1_atmo),
+            ("pm2_5_atmo", self.pm2_5_atmo),
+            ("pm10_atmo", self.pm10_atmo),
+        ];
+        match concentrations
+            .iter()
+            .find(|&&(_, value)| value > PM2_5_MAX_UG_M3)
+        {
+            Some(&(field, value)) => Err(ValidationError::OutOfRange { field, value }),
+            None => Ok(()),
+        }
+    }
 
-pub fn update_metrics(data: &PmsData) {
-    PARTICLE_CONCENTRATION_STANDARD
-        .with_label_values(&["1.0"])
-        .set(data.pm1_cf1 as f64);
-    PARTICLE_CONCENTRATION_STANDARD
-        .with_label_values(&["2.5"])
-        .set(data.pm2_5_cf1 as f64);
-    PARTICLE_CONCENTRATION_STANDARD
-        .with_label_values(&["10.0"])
-        .set(data.pm10_cf1 as f64);
-
-    PARTICLE_CONCENTRATION_ENVIRONMENT
-        .with_label_values(&["1.0"])
-        .set(data.pm1_atmo as f64);
-    PARTICLE_CONCENTRATION_ENVIRONMENT
-        .with_label_values(&["2.5"])
-        .set(data.pm2_5_atmo as f64);
-    PARTICLE_CONCENTRATION_ENVIRONMENT
-        .with_label_values(&["10.0"])
-        .set(data.pm10_atmo as f64);
-
-    PARTICLE_COUNT
-        .with_label_values(&["0.3"])
-        .set(data.pm0_3_count as f64);
-    PARTICLE_COUNT
-        .with_label_values(&["0.5"])
-        .set(data.pm0_5_count as f64);
-    PARTICLE_COUNT
-        .with_label_values(&["1.0"])
-        .set(data.pm1_0_count as f64);
-    PARTICLE_COUNT
-        .with_label_values(&["2.5"])
-        .set(data.pm2_5_count as f64);
-    PARTICLE_COUNT
-        .with_label_values(&["5.0"])
-        .set(data.pm5_0_count as f64);
-    PARTICLE_COUNT
-        .with_label_values(&["10.0"])
-        .set(data.pm10_0_count as f64);
-
-    update_aqi(
-        aqi::pm2_5(data.pm2_5_cf1.into()),
-        &AIR_QUALITY_INDEX.with_label_values(&["2.5"]),
-    );
-    update_aqi(
-        aqi::pm10(data.pm10_cf1.into()),
-        &AIR_QUALITY_INDEX.with_label_values(&["10.0"]),
-    );
-}
-
-pub fn read_active<F>(port: &str, mut callback: F) -> Result<(), Box<dyn Error>>
-where
-    F: FnMut(PmsData),
-{
-    info!("Reading from {:?}", port);
-    let mut port = serialport::new(port, BAUD_RATE).open()?;
-    info!("Starting read");
-
-    let mut buf = vec![0u8; 64];
-    loop {
-        match port.read(&mut buf[..]) {
-            Ok(p) => {
-                info!("read {} bytes", p);
-                let mut input = &buf[..p];
-                loop {
-                    match parse(input) {
-                        Ok((remainder, None)) => {
-                            debug!("wait for start marker");
-                            input = remainder;
-                        }
-                        Ok((remainder, Some(data))) => {
-                            debug!("got data: {:#?}", data);
-                            callback(data);
-                            input = remainder;
-                        }
-                        Err(nom::Err::Incomplete(nom::Needed::Size(s))) => {
-                            debug!("need {} more bytes!", s);
-                            break;
-                        }
-                        Err(e) => {
-                            error!("{}", e);
-                            break;
-                        }
-                    };
-                }
-            }
-            Err(ref e) if e.kind() == std::io::ErrorKind::TimedOut => {
-                info!("timed out, sleeping...");
-                thread::sleep(Duration::from_millis(1000));
-            }
-            Err(e) => Err(e)?,
+    /// Quality of the frame's contents according to `validate`. The read
+    /// loop additionally reports `Quality::Stale` for frames that waited too
+    /// long to be delivered.
+    pub fn quality(&self) -> Quality {
+        match self.validate() {
+            Ok(()) => Quality::Good,
+            Err(ValidationError::OutOfRange { .. }) => Quality::OutOfRange,
+            Err(_) => Quality::CheckFailed,
         }
     }
+
+    /// Whether both PM2.5 concentrations are within the effective range, up
+    /// to `PM2_5_EFFECTIVE_MAX_UG_M3`. Readings above it are less accurate,
+    /// but still valid up to `PM2_5_MAX_UG_M3`.
+    pub fn in_effective_range(&self) -> bool {
+        self.pm2_5_cf1.max(self.pm2_5_atmo) <= PM2_5_EFFECTIVE_MAX_UG_M3
+    }
+
+    /// Whether every concentration and count is zero, as in the frames sent
+    /// before the fan has spun up or after the laser failed. Such frames
+    /// have a valid checksum but carry no information.
+    pub fn is_all_zero(&self) -> bool {
+        self.words()[1..13].iter().all(|&word| word == 0)
+    }
+
+    /// Temperature in °C, for frames read as `Variant::Pms5003st`.
+    pub fn temperature(&self) -> Option<f64> {
+        self.extended
+            .map(|extended| f64::from(extended.temperature as i16) / 10.0)
+    }
+
+    /// Relative humidity in %, for frames read as `Variant::Pms5003st`.
+    pub fn humidity(&self) -> Option<f64> {
+        self.extended
+            .map(|extended| f64::from(extended.humidity) / 10.0)
+    }
+
+    /// Whether the checksum sent by the sensor matches the rest of the frame.
+    /// Only frames with a bad checksum are delivered with
+    /// `ValidationMode::Lenient`.
+    pub fn has_valid_checksum(&self) -> bool {
+        self.expected_checksum() == self.checksum
+    }
 }
 
-#[cfg(test)]
-mod tests {
-    use std::sync::Mutex;
+/// Builds a data frame from the 13 data words in frame order, i.e. the six
+/// concentrations, the six counts and the reserved word. The checksum is
+/// computed.
+impl From<[u16; 13]> for PmsData {
+    fn from(data: [u16; 13]) -> Self {
+        let mut words = [DATA_FRAME_LENGTH; 14];
+        words[1..].copy_from_slice(&data);
+        PmsData::from_words(words)
+    }
+}
 
-    lazy_static! {
-    // To prevent concurrent access to the metrics.
-    static ref TEST_MUTEX: Mutex<()> = Mutex::new(());
+/// Serializes `data` into a frame as sent by the sensor, e.g. for emulating
+/// one in tests. The checksum is computed from the other fields, so the
+/// frame always passes validation, and `parse_frame` returns `data` with a
+/// correct checksum. Frames without a reserved word are `FRAME_SIZE - 2`
+/// bytes long.
+pub fn encode(data: &PmsData) -> Vec<u8> {
+    let mut frame = START_MARKER.as_bytes().to_vec();
+    for word in &data.frame_words() {
+        frame.extend_from_slice(&word.to_be_bytes());
     }
+    let checksum = compute_checksum(&frame);
+    frame.extend_from_slice(&checksum.to_be_bytes());
+    frame
+}
+
+/// The checksum is the sum of all bytes in the frame before it.
+fn compute_checksum(bytes: &[u8]) -> u16 {
+    bytes
+        .iter()
+        .fold(0u16, |sum, &b| sum.wrapping_add(b.into()))
+}
 
-    const GOLDEN_PACKET: &[u8] = &[
-        0x42, 0x4d, 0x00, 0x1c, 0x00, 0x03, 0x00, 0x04, 0x00, 0x07, 0x00, 0x03, 0x00, 0x04, 0x00,
-        0x07, 0x02, 0xd0, 0x00, 0xb8, 0x00, 0x19, 0x00, 0x08, 0x00, 0x04, 0x00, 0x02, 0x97, 0x00,
-        0x03, 0x0f,
-    ];
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameStatus {
+    /// A complete data frame with a matching checksum.
+    Valid,
+    /// The frame length field isn't that of a data frame.
+    LengthMismatch {
+        frame_length: u16,
+    },
+    ChecksumMismatch {
+        expected: u16,
+        actual: u16,
+    },
+    /// The input ends before the frame does.
+    Truncated,
+}
+
+/// Reports every start marker in `input` along with whether a valid data
+/// frame starts there. Intended for investigating captures from noisy links.
+pub fn frame_offsets(input: &[u8]) -> Vec<(usize, FrameStatus)> {
+    let marker = START_MARKER.as_bytes();
+    input
+        .windows(marker.len())
+        .enumerate()
+        .filter(|(_, window)| *window == marker)
+        .map(|(offset, _)| (offset, frame_status(&input[offset..])))
+        .collect()
+}
+
+/// Size in bytes of a whole frame with the given frame length field, e.g. 32
+/// for a data frame and 8 for a command response.
+pub const fn frame_size(frame_length: u16) -> usize {
+    HEADER_SIZE + frame_length as usize
+}
+
+fn frame_status(frame: &[u8]) -> FrameStatus {
+    if frame.len() < HEADER_SIZE {
+        return FrameStatus::Truncated;
+    }
+    let frame_length = u16::from_be_bytes([frame[2], frame[3]]);
+    if ![
+        DATA_FRAME_LENGTH,
+        SHORT_DATA_FRAME_LENGTH,
+        EXTENDED_DATA_FRAME_LENGTH,
+    ]
+    .contains(&frame_length)
+    {
+        return FrameStatus::LengthMismatch { frame_length };
+    }
+    let size = frame_size(frame_length);
+    if frame.len() < size {
+        return FrameStatus::Truncated;
+    }
+    let expected = compute_checksum(&frame[..size - 2]);
+    let actual = u16::from_be_bytes([frame[size - 2], frame[size - 1]]);
+    if expected != actual {
+        return FrameStatus::ChecksumMismatch { expected, actual };
+    }
+    FrameStatus::Valid
+}
+
+/// Returns `input` starting at the first start marker (`42 4D`), or an empty
+/// slice if there is none. A trailing `42` is kept since it may be the first
+/// half of a marker split across reads.
+pub fn align_to_marker(input: &[u8]) -> &[u8] {
+    let marker = START_MARKER.as_bytes();
+    match input.windows(marker.len()).position(|w| w == marker) {
+        Some(start) => &input[start..],
+        None if input.last() == Some(&marker[0]) => &input[input.len() - 1..],
+        None => &input[input.len()..],
+    }
+}
+
+/// Parses the next data frame. The frame length only picks the layout: 26
+/// leaves out the reserved word, 36 adds the formaldehyde, temperature and
+/// humidity words, and any other length is read as the 28-byte layout.
+/// Unlike `parse_frame`, it isn't checked against the known lengths, so
+/// other frame types, e.g. command acknowledgements, are misread as data
+/// that runs into the following frame. Prefer `parse_frame` and match on
+/// `Frame::Data`.
+pub fn parse(input: &[u8]) -> IResult<&[u8], Option<PmsData>> {
+    alt((
+        map(|input| parse_data(input, Endianness::Big), Some),
+        map(take(1usize), |_| None),
+    ))(input)
+}
+
+/// A data frame captured from a PMS7003, for the tests.
+#[cfg(test)]
+pub(crate) const GOLDEN_PACKET: &[u8] = &[
+    0x42, 0x4d, 0x00, 0x1c, 0x00, 0x03, 0x00, 0x04, 0x00, 0x07, 0x00, 0x03, 0x00, 0x04, 0x00, 0x07,
+    0x02, 0xd0, 0x00, 0xb8, 0x00, 0x19, 0x00, 0x08, 0x00, 0x04, 0x00, 0x02, 0x97, 0x00, 0x03, 0x0f,
+];
+
+/// `secs` seconds after the Unix epoch, for the tests.
+#[cfg(test)]
+pub(crate) fn at(secs: u64) -> std::time::SystemTime {
+    std::time::UNIX_EPOCH + std::time::Duration::from_secs(secs)
+}
+
+#[cfg(test)]
+mod tests {
+    use nom::Needed;
+    use std::num::NonZeroUsize;
 
     use super::*;
+
     #[test]
     fn test_parse_data() {
         let expected = PmsData {
@@ -316,12 +772,161 @@ mod tests {
             pm2_5_count: 8,
             pm5_0_count: 4,
             pm10_0_count: 2,
-            reserved: 38656,
+            reserved: Some(38656),
+            extended: None,
             checksum: 783,
         };
         assert_eq!(parse(GOLDEN_PACKET), Ok(("".as_bytes(), Some(expected))));
     }
 
+    #[test]
+    fn test_parse_little_endian() {
+        let mut swapped = GOLDEN_PACKET.to_vec();
+        for word in swapped[4..].chunks_mut(2) {
+            word.swap(0, 1);
+        }
+        let (_, expected) = parse_frame(GOLDEN_PACKET).unwrap();
+        assert_eq!(
+            parse_frame_with(&swapped, Endianness::Little),
+            Ok(("".as_bytes(), expected.clone()))
+        );
+        assert_ne!(parse_frame(&swapped).unwrap().1, expected);
+    }
+
+    #[test]
+    fn test_command_to_bytes() {
+        assert_eq!(
+            Command::Wake.to_bytes(),
+            [0x42, 0x4d, 0xe4, 0x00, 0x01, 0x01, 0x74]
+        );
+        assert_eq!(
+            Command::ActiveMode.to_bytes(),
+            [0x42, 0x4d, 0xe1, 0x00, 0x01, 0x01, 0x71]
+        );
+        assert_eq!(
+            Command::PassiveRead.to_bytes(),
+            [0x42, 0x4d, 0xe2, 0x00, 0x00, 0x01, 0x71]
+        );
+    }
+
+    #[test]
+    fn test_quality() {
+        let (_, data) = parse(GOLDEN_PACKET).unwrap();
+        let mut data = data.unwrap();
+        assert_eq!(data.quality(), Quality::Good);
+        assert!(data.in_effective_range());
+        data.pm2_5_atmo = PM2_5_EFFECTIVE_MAX_UG_M3 + 1;
+        assert!(!data.in_effective_range());
+        data = PmsData::from_words(data.words());
+        assert_eq!(data.quality(), Quality::Good);
+        data.pm10_atmo = PM2_5_MAX_UG_M3 + 1;
+        assert_eq!(data.quality(), Quality::CheckFailed);
+        data = PmsData::from_words(data.words());
+        assert_eq!(data.quality(), Quality::OutOfRange);
+    }
+
+    #[test]
+    fn test_validate() {
+        let (_, data) = parse(GOLDEN_PACKET).unwrap();
+        let data = data.unwrap();
+        assert_eq!(data.validate(), Ok(()));
+
+        let mut words = [0u16; 13];
+        words[1] = 1200;
+        let out_of_range = PmsData::from(words);
+        assert_eq!(
+            out_of_range.validate(),
+            Err(ValidationError::OutOfRange {
+                field: "pm2_5_cf1",
+                value: 1200
+            })
+        );
+
+        let mut corrupted = data.clone();
+        corrupted.pm0_3_count += 1;
+        assert_eq!(
+            corrupted.validate(),
+            Err(ValidationError::Checksum {
+                expected: 0x0310,
+                actual: 0x030f
+            })
+        );
+
+        let mut wrong_length = data;
+        wrong_length.frame_length = 20;
+        assert_eq!(
+            wrong_length.validate(),
+            Err(ValidationError::FrameLength { frame_length: 20 })
+        );
+    }
+
+    #[test]
+    fn test_from_words() {
+        let (_, data) = parse(GOLDEN_PACKET).unwrap();
+        let data = data.unwrap();
+        let mut words = [0u16; 13];
+        words.copy_from_slice(&data.words()[1..]);
+        assert_eq!(PmsData::from(words), data);
+    }
+
+    #[test]
+    fn test_frame_size() {
+        assert_eq!(FRAME_SIZE, GOLDEN_PACKET.len());
+        assert_eq!(frame_size(SHORT_DATA_FRAME_LENGTH), 30);
+        assert_eq!(frame_size(ACK_FRAME_LENGTH), 8);
+    }
+
+    #[test]
+    fn test_concentration() {
+        let data = PmsData::from([1, 2, 3, 4, 5, 6, 0, 0, 0, 0, 0, 0, 0]);
+        let mut values = Vec::new();
+        for basis in ConcentrationBasis::ALL {
+            for pollutant in Pollutant::ALL {
+                values.push(data.concentration(pollutant, basis));
+            }
+        }
+        assert_eq!(values, [1.0, 2.0, 3.0, 4.0, 5.0, 6.0]);
+        assert_eq!(data.pm2_5_atmo(), 5);
+    }
+
+    #[test]
+    fn test_is_all_zero() {
+        assert!(PmsData::from([0; 13]).is_all_zero());
+        let mut words = [0; 13];
+        words[12] = 0x9700;
+        assert!(PmsData::from(words).is_all_zero());
+        let (_, data) = parse(GOLDEN_PACKET).unwrap();
+        assert!(!data.unwrap().is_all_zero());
+    }
+
+    #[test]
+    fn test_version() {
+        let (_, data) = parse(GOLDEN_PACKET).unwrap();
+        assert_eq!(data.unwrap().version(), Some(0x97));
+    }
+
+    #[test]
+    fn test_parse_short_frame() {
+        // A clone's 30-byte frame: frame length 26 and no reserved word.
+        let mut frame = GOLDEN_PACKET[..28].to_vec();
+        frame[3] = 0x1a;
+        let checksum = compute_checksum(&frame);
+        frame.extend_from_slice(&checksum.to_be_bytes());
+        frame.extend_from_slice(GOLDEN_PACKET);
+
+        let (remainder, short) = parse_frame(&frame).unwrap();
+        assert_eq!(remainder, GOLDEN_PACKET);
+        let Some(Frame::Data(short)) = short else {
+            panic!("expected a data frame, got {:?}", short);
+        };
+        assert_eq!(short.reserved, None);
+        assert_eq!(short.version(), None);
+        assert_eq!(short.pm10_0_count, 2);
+        assert_eq!(short.validate(), Ok(()));
+        assert_eq!(encode(&short), &frame[..30]);
+        assert_eq!(frame_offsets(&frame)[0], (0, FrameStatus::Valid));
+    }
+
     #[test]
     fn test_partial() {
         assert_eq!(
@@ -332,78 +937,195 @@ mod tests {
         );
     }
 
-    fn testdata() -> PmsData {
-        PmsData {
-            frame_length: 28,
-            pm1_cf1: 3,
-            pm2_5_cf1: 4,
-            pm10_cf1: 7,
-            pm1_atmo: 3,
-            pm2_5_atmo: 4,
-            pm10_atmo: 7,
-            pm0_3_count: 720,
-            pm0_5_count: 184,
-            pm1_0_count: 25,
-            pm2_5_count: 8,
-            pm5_0_count: 4,
-            pm10_0_count: 2,
-            reserved: 38656,
-            checksum: 783,
-        }
+    #[test]
+    fn test_align_to_marker() {
+        let mut noisy = b"noise".to_vec();
+        noisy.extend_from_slice(GOLDEN_PACKET);
+        assert_eq!(align_to_marker(&noisy), GOLDEN_PACKET);
+        assert_eq!(align_to_marker(GOLDEN_PACKET), GOLDEN_PACKET);
+        assert_eq!(align_to_marker(b"noise"), b"");
+        assert_eq!(align_to_marker(b"noiseB"), b"B");
+        assert_eq!(align_to_marker(b""), b"");
     }
 
     #[test]
-    fn test_metrics() {
-        let _ = env_logger::builder().is_test(true).try_init();
-        let _guard = TEST_MUTEX.lock().unwrap();
-        update_metrics(&testdata());
+    fn test_parse_frame() {
+        let (remainder, frame) = parse_frame(GOLDEN_PACKET).unwrap();
+        assert!(remainder.is_empty());
+        assert!(matches!(frame, Some(Frame::Data(_))));
+
+        const ACK: &[u8] = &[0x42, 0x4d, 0x00, 0x04, 0xe1, 0x00, 0x01, 0x74];
         assert_eq!(
-            PARTICLE_CONCENTRATION_STANDARD
-                .with_label_values(&["1.0"])
-                .get(),
-            3.0
+            parse_frame(ACK),
+            Ok((
+                "".as_bytes(),
+                Some(Frame::Ack(AckFrame {
+                    command: 0xe1,
+                    data: 0x00,
+                    checksum: 0x174,
+                }))
+            ))
         );
+
+        const UNKNOWN: &[u8] = &[0x42, 0x4d, 0x00, 0x09, 0x01];
         assert_eq!(
-            PARTICLE_CONCENTRATION_STANDARD
-                .with_label_values(&["2.5"])
-                .get(),
-            4.0
+            parse_frame(UNKNOWN),
+            Ok((&UNKNOWN[4..], Some(Frame::Unknown)))
         );
+        assert_eq!(parse_frame(b"abc"), Ok(("bc".as_bytes(), None)));
+    }
+
+    #[test]
+    fn test_parse_extended_frame() {
+        let mut data = parse(GOLDEN_PACKET).unwrap().1.unwrap();
+        data.frame_length = EXTENDED_DATA_FRAME_LENGTH;
+        data.extended = Some(Extended {
+            formaldehyde: 12,
+            temperature: -35i16 as u16,
+            humidity: 456,
+            reserved: 0,
+        });
+        let frame = encode(&data);
+        assert_eq!(frame.len(), frame_size(EXTENDED_DATA_FRAME_LENGTH));
+        assert_eq!(frame.len(), 40);
+        assert_eq!(frame_offsets(&frame), [(0, FrameStatus::Valid)]);
+        assert_eq!(frame_offsets(&frame[..36]), [(0, FrameStatus::Truncated)]);
+
+        // Skipped unless the variant is selected.
+        assert_eq!(parse_frame(&frame), Ok((&frame[4..], Some(Frame::Unknown))));
+        let (remainder, parsed) =
+            parse_frame_variant(&frame, Endianness::Big, Variant::Pms5003st).unwrap();
+        assert!(remainder.is_empty());
+        let parsed = match parsed {
+            Some(Frame::Data(parsed)) => parsed,
+            other => panic!("expected data, got {:?}", other),
+        };
+        assert_eq!(parsed.validate(), Ok(()));
+        assert_eq!(parsed.temperature(), Some(-3.5));
+        assert_eq!(parsed.humidity(), Some(45.6));
+        assert_eq!(parsed.version(), Some(0x97));
+        assert_eq!(parsed.pm2_5_cf1, 4);
+
+        let standard = parse(GOLDEN_PACKET).unwrap().1.unwrap();
+        assert_eq!(standard.temperature(), None);
         assert_eq!(
-            PARTICLE_CONCENTRATION_STANDARD
-                .with_label_values(&["10.0"])
-                .get(),
-            7.0
+            parse_frame_variant(GOLDEN_PACKET, Endianness::Big, Variant::Pms5003st),
+            Ok((&[][..], Some(Frame::Data(standard))))
         );
-        assert_eq!(AIR_QUALITY_INDEX.with_label_values(&["2.5"]).get(), 17.0);
-        assert_eq!(AIR_QUALITY_INDEX.with_label_values(&["10.0"]).get(), 6.0);
+        assert_eq!("pms5003st".parse(), Ok(Variant::Pms5003st));
+    }
+
+    /// Generates structurally valid frames with random payloads.
+    struct RandomFrames(u64);
+
+    impl Iterator for RandomFrames {
+        type Item = PmsData;
+
+        fn next(&mut self) -> Option<PmsData> {
+            let mut word = || {
+                // xorshift64
+                self.0 ^= self.0 << 13;
+                self.0 ^= self.0 >> 7;
+                self.0 ^= self.0 << 17;
+                self.0 as u16
+            };
+            let mut data = PmsData {
+                frame_length: 28,
+                pm1_cf1: word(),
+                pm2_5_cf1: word(),
+                pm10_cf1: word(),
+                pm1_atmo: word(),
+                pm2_5_atmo: word(),
+                pm10_atmo: word(),
+                pm0_3_count: word(),
+                pm0_5_count: word(),
+                pm1_0_count: word(),
+                pm2_5_count: word(),
+                pm5_0_count: word(),
+                pm10_0_count: word(),
+                reserved: Some(word()),
+                extended: None,
+                checksum: 0,
+            };
+            data.checksum = data.expected_checksum();
+            Some(data)
+        }
     }
 
     #[test]
-    fn test_parse_invalid() {
-        const INVALID: &str = "abc";
-        assert_eq!(parse(INVALID.as_bytes()), Ok(("bc".as_bytes(), None)));
+    fn test_encode_golden_packet() {
+        let (_, data) = parse(GOLDEN_PACKET).unwrap();
+        assert_eq!(&encode(&data.unwrap())[..], GOLDEN_PACKET);
     }
 
     #[test]
-    fn test_aqi_valid() {
-        let _ = env_logger::builder().is_test(true).try_init();
-        let _guard = TEST_MUTEX.lock().unwrap();
-        let mut data = testdata();
-        data.pm2_5_cf1 = 37;
-        update_metrics(&data);
-        assert_eq!(AIR_QUALITY_INDEX.with_label_values(&["2.5"]).get(), 105.0);
+    fn test_encode_round_trip() {
+        for data in RandomFrames(0x5eed).take(1000) {
+            let frame = encode(&data);
+            assert_eq!(frame.len(), FRAME_SIZE);
+            assert_eq!(
+                parse_frame(&frame),
+                Ok(("".as_bytes(), Some(Frame::Data(data))))
+            );
+        }
+        for mut data in RandomFrames(0xfee1).take(1000) {
+            data.frame_length = SHORT_DATA_FRAME_LENGTH;
+            data.reserved = None;
+            data.checksum = data.expected_checksum();
+            assert_eq!(
+                parse_frame(&encode(&data)),
+                Ok(("".as_bytes(), Some(Frame::Data(data))))
+            );
+        }
+    }
+
+    #[test]
+    fn test_encode_ignores_checksum() {
+        let (_, data) = parse(GOLDEN_PACKET).unwrap();
+        let mut data = data.unwrap();
+        data.checksum = 0;
+        assert_eq!(encode(&data), GOLDEN_PACKET);
     }
 
     #[test]
-    fn test_aqi_out_of_range() {
-        let _ = env_logger::builder().is_test(true).try_init();
-        let _guard = TEST_MUTEX.lock().unwrap();
-        update_metrics(&testdata());
-        let before = AIR_QUALITY_INDEX.with_label_values(&["10.0"]).get();
-        let mut data = testdata();
-        data.pm10_cf1 = u16::MAX;
-        update_metrics(&data);
-        assert_eq!(AIR_QUALITY_INDEX.with_label_values(&["10.0"]).get(), before);
+    fn test_has_valid_checksum() {
+        let (_, data) = parse(GOLDEN_PACKET).unwrap();
+        assert!(data.unwrap().has_valid_checksum());
+        let mut corrupted = GOLDEN_PACKET.to_vec();
+        corrupted[5] += 1;
+        let (_, data) = parse(&corrupted).unwrap();
+        assert!(!data.unwrap().has_valid_checksum());
+    }
+
+    #[test]
+    fn test_frame_offsets() {
+        let mut input = b"xx".to_vec();
+        input.extend_from_slice(GOLDEN_PACKET);
+        let mut corrupted = GOLDEN_PACKET.to_vec();
+        corrupted[5] += 1;
+        input.extend_from_slice(&corrupted);
+        input.extend_from_slice(&[0x42, 0x4d, 0x00, 0x04, 0xe1]);
+        input.extend_from_slice(&GOLDEN_PACKET[..10]);
+        assert_eq!(
+            frame_offsets(&input),
+            vec![
+                (2, FrameStatus::Valid),
+                (
+                    34,
+                    FrameStatus::ChecksumMismatch {
+                        expected: 784,
+                        actual: 783
+                    }
+                ),
+                (66, FrameStatus::LengthMismatch { frame_length: 4 }),
+                (71, FrameStatus::Truncated),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_invalid() {
+        const INVALID: &str = "abc";
+        assert_eq!(parse(INVALID.as_bytes()), Ok(("bc".as_bytes(), None)));
     }
 }
@@ -0,0 +1,253 @@
+//! A log file that is rolled over by size or at midnight (UTC), keeping disk
+//! usage bounded on long unattended runs.
+//!
+//! Rotated segments are renamed to `<path>.<unix time>`, and with the `gzip`
+//! feature optionally compressed to `<path>.<unix time>.gz` on a background
+//! thread, so that writing never waits for compression.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::thread::JoinHandle;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// When a `RotatingFile` is rolled over. With neither limit set the file is
+/// never rotated.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Rotation {
+    /// Roll over before a write would make the file larger than this.
+    pub max_bytes: Option<u64>,
+    /// Roll over on the first write after midnight UTC.
+    pub daily: bool,
+    /// Gzip-compress rotated segments.
+    #[cfg(feature = "gzip")]
+    pub compress: bool,
+}
+
+pub struct RotatingFile {
+    path: PathBuf,
+    rotation: Rotation,
+    file: File,
+    len: u64,
+    day: u64,
+    compressions: Vec<JoinHandle<()>>,
+}
+
+fn day_of(at: SystemTime) -> u64 {
+    at.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() / SECONDS_PER_DAY
+}
+
+fn open_append(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+impl RotatingFile {
+    /// Opens `path` for appending, creating it if needed.
+    pub fn open<P: AsRef<Path>>(path: P, rotation: Rotation) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let file = open_append(&path)?;
+        let metadata = file.metadata()?;
+        let modified = metadata.modified().unwrap_or_else(|_| SystemTime::now());
+        Ok(RotatingFile {
+            path,
+            rotation,
+            len: metadata.len(),
+            day: day_of(modified),
+            file,
+            compressions: Vec::new(),
+        })
+    }
+
+    /// Appends `line` and a newline, rotating first if needed. A line is
+    /// never split across segments.
+    pub fn write_line(&mut self, line: &str) -> io::Result<()> {
+        self.write_line_at(SystemTime::now(), line)
+    }
+
+    /// Like `write_line`, with `at` as the current time for daily rotation.
+    pub fn write_line_at(&mut self, at: SystemTime, line: &str) -> io::Result<()> {
+        let record_len = line.len() as u64 + 1;
+        let too_large = self
+            .rotation
+            .max_bytes
+            .is_some_and(|max_bytes| self.len + record_len > max_bytes);
+        let new_day = self.rotation.daily && day_of(at) != self.day;
+        // An empty file is never rotated, so a line longer than `max_bytes`
+        // gets a segment of its own.
+        if self.len > 0 && (too_large || new_day) {
+            self.rotate(at)?;
+        }
+        self.day = day_of(at);
+        self.file.write_all(line.as_bytes())?;
+        self.file.write_all(b"\n")?;
+        self.len += record_len;
+        Ok(())
+    }
+
+    fn rotated_path(&self, at: SystemTime) -> PathBuf {
+        let secs = at.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        let base = format!("{}.{}", self.path.display(), secs);
+        let mut candidate = PathBuf::from(&base);
+        let mut n = 1;
+        while candidate.exists() || Path::new(&format!("{}.gz", candidate.display())).exists() {
+            candidate = PathBuf::from(format!("{}.{}", base, n));
+            n += 1;
+        }
+        candidate
+    }
+
+    fn rotate(&mut self, at: SystemTime) -> io::Result<()> {
+        self.file.flush()?;
+        let rotated = self.rotated_path(at);
+        fs::rename(&self.path, &rotated)?;
+        self.file = open_append(&self.path)?;
+        self.len = 0;
+        #[cfg(feature = "gzip")]
+        if self.rotation.compress {
+            self.compressions.retain(|handle| !handle.is_finished());
+            self.compressions
+                .push(std::thread::spawn(move || compress(&rotated)));
+        }
+        Ok(())
+    }
+}
+
+impl Drop for RotatingFile {
+    /// Waits for pending compressions, so that no segment is left half
+    /// compressed on a clean exit.
+    fn drop(&mut self) {
+        for handle in self.compressions.drain(..) {
+            let _ = handle.join();
+        }
+    }
+}
+
+/// Replaces `path` with `<path>.gz`. On failure the uncompressed segment is
+/// kept.
+#[cfg(feature = "gzip")]
+fn compress(path: &Path) {
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use log::error;
+
+    let gz_path = PathBuf::from(format!("{}.gz", path.display()));
+    let result = (|| -> io::Result<()> {
+        let mut input = File::open(path)?;
+        let mut encoder = GzEncoder::new(File::create(&gz_path)?, Compression::default());
+        io::copy(&mut input, &mut encoder)?;
+        encoder.finish()?.sync_all()?;
+        fs::remove_file(path)
+    })();
+    if let Err(e) = result {
+        error!("Could not compress {}: {}", path.display(), e);
+        let _ = fs::remove_file(&gz_path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::at;
+
+    /// A fresh directory under the system temp dir.
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("pms7003-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn segments(dir: &Path) -> Vec<String> {
+        let mut names: Vec<String> = fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn test_rotate_by_size() {
+        let dir = test_dir("size");
+        let path = dir.join("log.jsonl");
+        let rotation = Rotation {
+            max_bytes: Some(10),
+            ..Default::default()
+        };
+        let mut file = RotatingFile::open(&path, rotation).unwrap();
+        file.write_line_at(at(100), "1234").unwrap();
+        file.write_line_at(at(100), "5678").unwrap();
+        file.write_line_at(at(100), "abcd").unwrap();
+        file.write_line_at(at(200), "a line longer than max_bytes")
+            .unwrap();
+        drop(file);
+
+        assert_eq!(
+            segments(&dir),
+            ["log.jsonl", "log.jsonl.100", "log.jsonl.200"]
+        );
+        assert_eq!(
+            fs::read_to_string(dir.join("log.jsonl.100")).unwrap(),
+            "1234\n5678\n"
+        );
+        assert_eq!(
+            fs::read_to_string(dir.join("log.jsonl.200")).unwrap(),
+            "abcd\n"
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_rotate_daily() {
+        let dir = test_dir("daily");
+        let path = dir.join("log.jsonl");
+        let rotation = Rotation {
+            daily: true,
+            ..Default::default()
+        };
+        let mut file = RotatingFile::open(&path, rotation).unwrap();
+        let midnight = 20 * SECONDS_PER_DAY;
+        file.write_line_at(at(midnight - 10), "before").unwrap();
+        file.write_line_at(at(midnight - 1), "before").unwrap();
+        file.write_line_at(at(midnight), "after").unwrap();
+        drop(file);
+
+        let rotated = format!("log.jsonl.{}", midnight);
+        assert_eq!(segments(&dir), ["log.jsonl".to_string(), rotated.clone()]);
+        assert_eq!(
+            fs::read_to_string(dir.join(rotated)).unwrap(),
+            "before\nbefore\n"
+        );
+        assert_eq!(fs::read_to_string(&path).unwrap(), "after\n");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(feature = "gzip")]
+    #[test]
+    fn test_rotate_compressed() {
+        use flate2::read::GzDecoder;
+        use std::io::Read;
+
+        let dir = test_dir("gzip");
+        let path = dir.join("log.jsonl");
+        let rotation = Rotation {
+            max_bytes: Some(6),
+            compress: true,
+            ..Default::default()
+        };
+        let mut file = RotatingFile::open(&path, rotation).unwrap();
+        file.write_line_at(at(100), "first").unwrap();
+        file.write_line_at(at(100), "second").unwrap();
+        drop(file);
+
+        assert_eq!(segments(&dir), ["log.jsonl", "log.jsonl.100.gz"]);
+        let mut contents = String::new();
+        GzDecoder::new(File::open(dir.join("log.jsonl.100.gz")).unwrap())
+            .read_to_string(&mut contents)
+            .unwrap();
+        assert_eq!(contents, "first\n");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
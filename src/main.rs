@@ -5,17 +5,16 @@ extern crate prometheus_exporter;
 extern crate serialport;
 extern crate structopt;
 
-use log::{debug, error, info};
+use log::{debug, info};
 use std::error::Error;
-use std::io::BufReader;
-use std::io::Read;
-use std::thread;
 use std::time::Duration;
 
 use env_logger::Env;
 use structopt::StructOpt;
 
-const BAUD_RATE: u32 = 9600;
+const DEFAULT_SETTLE_TIME_SECONDS: f64 = 30.0;
+#[cfg(feature = "serde")]
+const STATE_SAVE_INTERVAL: Duration = Duration::from_secs(60);
 
 #[derive(Debug, StructOpt)]
 #[structopt(
@@ -32,14 +31,243 @@ struct Cli {
     #[structopt(long, help = "Example: 127.0.0.1:9954")]
     prometheus_bind_addr: Option<String>,
 
-    #[structopt(name = "SERIAL_PORT")]
-    port: String,
+    #[structopt(
+        name = "SERIAL_PORT",
+        help = "Example: /dev/ttyUSB0. Required unless set in the config file"
+    )]
+    port: Option<String>,
 
-    #[structopt(long, default_value = "30.0")]
-    settle_time_seconds: f64,
+    #[cfg(feature = "serde")]
+    #[structopt(
+        long,
+        help = "Read settings from this JSON file. Flags override its values"
+    )]
+    config: Option<String>,
+
+    #[structopt(long, help = "[default: 9600]")]
+    baud_rate: Option<u32>,
+
+    #[structopt(long, help = "[default: 30.0]")]
+    settle_time_seconds: Option<f64>,
+
+    #[structopt(
+        long,
+        help = "Also export the sensor_* series with this location label"
+    )]
+    location: Option<String>,
 
     #[structopt(short, long)]
     quiet: bool,
+
+    #[structopt(
+        long,
+        help = "Accumulate reads until a full frame arrives or this many milliseconds pass"
+    )]
+    assemble_timeout_millis: Option<u64>,
+
+    #[structopt(
+        long,
+        conflicts_with = "assemble-timeout-millis",
+        help = "Read everything buffered before parsing, and process only the most recent frame"
+    )]
+    drain_reads: bool,
+
+    #[structopt(long, help = "Bytes to request from the port per read")]
+    read_buffer_size: Option<usize>,
+
+    #[structopt(
+        long,
+        help = "Only wake up this often and process the most recent frame"
+    )]
+    poll_interval_millis: Option<u64>,
+
+    #[structopt(
+        long,
+        help = "Export the mean of each period of this many seconds instead of every reading"
+    )]
+    metrics_period_seconds: Option<u64>,
+
+    #[structopt(
+        long = "aqi-standard",
+        number_of_values = 1,
+        help = "AQI standard to export (us_epa, eu_caqi). May be repeated [default: us_epa]"
+    )]
+    aqi_standards: Vec<pms7003::aqi::AqiStandard>,
+
+    #[structopt(
+        long,
+        help = "Basis of air_quality_index_selected (standard, environment, threshold:<µg/m³>) [default: standard]"
+    )]
+    aqi_basis: Option<pms7003::metrics::BasisPolicy>,
+
+    #[structopt(
+        long,
+        help = "Also export particle counts per this volume (0.1l, l, m3) as particle_count_converted"
+    )]
+    count_unit: Option<pms7003::metrics::CountUnit>,
+
+    #[structopt(long, help = "Export the raw reserved word as reserved_word")]
+    export_reserved_word: bool,
+
+    #[structopt(
+        long,
+        help = "Export the AQI of the 24-hour average PM2.5 as air_quality_index_24h"
+    )]
+    aqi_24h: bool,
+
+    #[structopt(
+        long,
+        help = "Export the change of each particle count per second as particle_count_rate"
+    )]
+    count_rates: bool,
+
+    #[structopt(
+        long,
+        help = "Export concentrations on the AQI scale as particle_concentration_aqi_scale"
+    )]
+    aqi_scale: bool,
+
+    #[structopt(
+        long,
+        help = "Export each reading as a single air_quality series with the values as labels"
+    )]
+    combined_metric: bool,
+
+    #[structopt(
+        long,
+        help = "Export the slope of the daily mean PM2.5 over this many days as pm2_5_trend_slope"
+    )]
+    pm2_5_trend_days: Option<usize>,
+
+    #[structopt(
+        long,
+        requires = "ambient-pressure-hpa",
+        help = "Ambient temperature for exporting counts normalized to STP as particle_count_stp"
+    )]
+    ambient_temperature_celsius: Option<f64>,
+
+    #[structopt(
+        long,
+        requires = "ambient-temperature-celsius",
+        help = "Ambient pressure for exporting counts normalized to STP as particle_count_stp"
+    )]
+    ambient_pressure_hpa: Option<f64>,
+
+    #[structopt(
+        long,
+        help = "Outdoor PM2.5 concentration in µg/m³ for exporting purifier_effectiveness_percent"
+    )]
+    outdoor_pm2_5: Option<f64>,
+
+    #[structopt(
+        long,
+        help = "Deliver frames with a bad checksum instead of dropping them"
+    )]
+    lenient_checksum: bool,
+
+    #[structopt(
+        long,
+        help = "Exit with an error once more than this fraction of the last minute of frames had a bad checksum"
+    )]
+    max_checksum_error_rate: Option<f64>,
+
+    #[structopt(
+        long,
+        help = "Discard input buffered before the port was opened, and wait for two adjacent valid frames"
+    )]
+    drain_on_open: bool,
+
+    #[structopt(
+        long,
+        parse(from_os_str),
+        help = "Record the raw serial stream with its timing to this file, for replaying"
+    )]
+    record: Option<std::path::PathBuf>,
+
+    #[structopt(
+        long,
+        help = "Read data words least significant byte first, for byte-swapping adapters"
+    )]
+    little_endian: bool,
+
+    #[structopt(
+        long,
+        help = "Sensor model (pms7003, pms5003st); pms5003st also reads temperature and humidity"
+    )]
+    variant: Option<pms7003::Variant>,
+
+    #[structopt(long, help = "Suppress readings identical to the previous one")]
+    dedup: bool,
+
+    #[structopt(
+        long,
+        help = "Don't wake the sensor and put it in active mode on startup"
+    )]
+    no_wake: bool,
+
+    #[structopt(long, help = "Set the DTR line after opening the port (true or false)")]
+    dtr: Option<bool>,
+
+    #[structopt(long, help = "Set the RTS line after opening the port (true or false)")]
+    rts: Option<bool>,
+
+    #[cfg(feature = "serde")]
+    #[structopt(long, help = "Print readings as newline-delimited JSON")]
+    json: bool,
+
+    #[cfg(feature = "serde")]
+    #[structopt(long, help = "Also append readings to this file as JSON lines")]
+    log_file: Option<String>,
+
+    #[cfg(feature = "serde")]
+    #[structopt(long, help = "Rotate the log file before it exceeds this size")]
+    log_max_bytes: Option<u64>,
+
+    #[cfg(feature = "serde")]
+    #[structopt(long, help = "Rotate the log file at midnight UTC")]
+    log_daily: bool,
+
+    #[cfg(feature = "gzip")]
+    #[structopt(long, help = "Gzip-compress rotated log files")]
+    log_gzip: bool,
+
+    #[cfg(feature = "serde")]
+    #[structopt(
+        long,
+        parse(from_os_str),
+        help = "Keep averages and the dose in this file across restarts, saved every minute"
+    )]
+    state_file: Option<std::path::PathBuf>,
+
+    #[cfg(feature = "http")]
+    #[structopt(
+        long,
+        help = "Serve the latest reading as JSON at /current on this address, e.g. 0.0.0.0:9955"
+    )]
+    http_bind_addr: Option<String>,
+
+    #[cfg(feature = "http")]
+    #[structopt(
+        long,
+        requires = "http-bind-addr",
+        help = "Also stream trusted readings as Server-Sent Events at /events"
+    )]
+    http_events: bool,
+
+    #[cfg(feature = "remote-write")]
+    #[structopt(
+        long,
+        help = "Push the metrics to this Prometheus remote-write URL, e.g. http://mimir:9009/api/v1/push"
+    )]
+    remote_write_url: Option<String>,
+
+    #[cfg(feature = "remote-write")]
+    #[structopt(
+        long,
+        requires = "remote-write-url",
+        help = "Seconds between remote-write pushes [default: 15]"
+    )]
+    remote_write_interval_seconds: Option<u64>,
 }
 
 fn list() -> Result<(), Box<dyn Error>> {
@@ -63,18 +291,239 @@ fn main() -> Result<(), Box<dyn Error>> {
         return Ok(());
     }
 
-    let exporter = if let Some(bind_addr) = opt.prometheus_bind_addr {
-        let binding = bind_addr.parse()?;
-        Some(prometheus_exporter::start(binding)?)
+    #[cfg(feature = "serde")]
+    let file = match &opt.config {
+        Some(path) => pms7003::config::load_config(path)?,
+        None => pms7003::config::FileConfig::default(),
+    };
+    #[cfg(not(feature = "serde"))]
+    let file = pms7003::config::FileConfig::default();
+    let port = opt
+        .port
+        .clone()
+        .or(file.port)
+        .ok_or("no serial port given on the command line or in the config file")?;
+    let aqi_standards = if opt.aqi_standards.is_empty() {
+        file.aqi_standards
     } else {
-        None
+        opt.aqi_standards.clone()
     };
 
-    let callback = pms7003::default_callback(
-        Duration::from_millis((opt.settle_time_seconds * 1000.0) as u64),
-        /*echo=*/ !opt.quiet,
-    );
-    pms7003::read_active(&opt.port, callback)?;
+    let mut metrics_config = pms7003::metrics::MetricsConfig::default();
+    if !aqi_standards.is_empty() {
+        metrics_config.aqi_standards = aqi_standards;
+    }
+    let aqi_standards = metrics_config.aqi_standards.clone();
+    metrics_config.aqi_basis = opt.aqi_basis.unwrap_or_default();
+    metrics_config.export_reserved_word = opt.export_reserved_word;
+    metrics_config.aqi_24h = opt.aqi_24h;
+    metrics_config.count_rates = opt.count_rates;
+    metrics_config.aqi_scale = opt.aqi_scale;
+    metrics_config.combined = opt.combined_metric;
+    if opt.pm2_5_trend_days.is_some_and(|days| days < 2) {
+        return Err("--pm2-5-trend-days must be at least 2".into());
+    }
+    metrics_config.pm2_5_trend_days = opt.pm2_5_trend_days;
+    metrics_config.count_unit = opt.count_unit.unwrap_or_default();
+    if let (Some(temperature_celsius), Some(pressure_hpa)) =
+        (opt.ambient_temperature_celsius, opt.ambient_pressure_hpa)
+    {
+        metrics_config.stp_counts = true;
+        pms7003::metrics::set_ambient(pms7003::derived::Ambient {
+            temperature_celsius,
+            pressure_hpa,
+        });
+    }
+    pms7003::metrics::set_outdoor_pm2_5(opt.outdoor_pm2_5);
+    pms7003::metrics::configure(metrics_config);
+    #[cfg(feature = "serde")]
+    if let Some(path) = &opt.state_file {
+        pms7003::metrics::load_state(path);
+        pms7003::metrics::save_state_every(path.clone(), STATE_SAVE_INTERVAL);
+    }
+
+    if let Some(bind_addr) = opt
+        .prometheus_bind_addr
+        .clone()
+        .or(file.prometheus_bind_addr)
+    {
+        let binding = bind_addr.parse()?;
+        let exporter = prometheus_exporter::start(binding)?;
+        pms7003::reset_metrics();
+        pms7003::metrics::track_scrapes(exporter);
+    }
+    #[cfg(feature = "http")]
+    let events = Some(pms7003::http::EventStream::new()).filter(|_| opt.http_events);
+    #[cfg(feature = "http")]
+    if let Some(bind_addr) = &opt.http_bind_addr {
+        pms7003::http::serve_with_events(
+            bind_addr.as_str(),
+            aqi_standards.clone(),
+            pms7003::http::DEFAULT_MAX_AGE,
+            events.clone(),
+        )?;
+    }
+
+    #[cfg(feature = "remote-write")]
+    if let Some(url) = &opt.remote_write_url {
+        let interval = opt
+            .remote_write_interval_seconds
+            .map_or(pms7003::remote_write::DEFAULT_INTERVAL, Duration::from_secs);
+        if interval.is_zero() {
+            return Err("--remote-write-interval-seconds must be positive".into());
+        }
+        pms7003::remote_write::remote_write_every(url, interval)?;
+    }
+
+    let settle_time_seconds = opt
+        .settle_time_seconds
+        .or(file.settle_time_seconds)
+        .unwrap_or(DEFAULT_SETTLE_TIME_SECONDS);
+    let settle_time = Duration::from_millis((settle_time_seconds * 1000.0) as u64);
+    let metrics_period = opt.metrics_period_seconds.map(Duration::from_secs);
+    let variant = opt.variant.unwrap_or_default();
+    pms7003::metrics::export_config(&pms7003::metrics::ExporterConfig {
+        settle_time,
+        aqi_standards: aqi_standards.clone(),
+        variant,
+        metrics_period,
+        combined: opt.combined_metric,
+    });
+    let echo = !opt.quiet;
+    let default_callback = || match metrics_period {
+        Some(period) => pms7003::downsampled_callback(settle_time, echo, period),
+        None => pms7003::default_callback(settle_time, echo),
+    };
+    // Each consumer but the default one, which skips the warm-up itself and
+    // echoes while it waits, is trusted after `settle_time`.
+    let trusted = || pms7003::CallbackBuilder::new().settle_time(settle_time);
+    let callback = pms7003::FanOut::new();
+    #[cfg(feature = "serde")]
+    let callback = if opt.json {
+        let mut json = pms7003::jsonl_callback();
+        let mut metrics: Box<dyn FnMut(pms7003::PmsData)> = match metrics_period {
+            Some(period) => pms7003::downsampled_metrics(period),
+            None => Box::new(|data| pms7003::update_metrics(&data)),
+        };
+        callback.add(trusted(), move |data| {
+            metrics(data.clone());
+            json(data);
+        })
+    } else {
+        callback.add(pms7003::CallbackBuilder::new(), default_callback())
+    };
+    #[cfg(not(feature = "serde"))]
+    let callback = callback.add(pms7003::CallbackBuilder::new(), default_callback());
+    #[cfg(feature = "serde")]
+    let callback = match &opt.log_file {
+        Some(path) => {
+            let rotation = pms7003::logfile::Rotation {
+                max_bytes: opt.log_max_bytes,
+                daily: opt.log_daily,
+                #[cfg(feature = "gzip")]
+                compress: opt.log_gzip,
+            };
+            let file = pms7003::logfile::RotatingFile::open(path, rotation)?;
+            callback.add(trusted(), pms7003::jsonl_file_callback(file))
+        }
+        None => callback,
+    };
+    let callback = match opt.location.clone().or(file.location) {
+        Some(location) => {
+            let sink = pms7003::metrics::LocationSink::new(&location);
+            callback.add(trusted(), move |data| {
+                pms7003::record_reading(&sink, &data, &aqi_standards)
+            })
+        }
+        None => callback,
+    };
+    let callback = match &file.thresholds {
+        Some(thresholds) => {
+            let settle_time = thresholds
+                .settle_time_seconds
+                .map_or(settle_time, |seconds| {
+                    Duration::from_millis((seconds * 1000.0) as u64)
+                });
+            callback.add(
+                pms7003::CallbackBuilder::new().settle_time(settle_time),
+                pms7003::action_callback(
+                    thresholds.thresholds(),
+                    thresholds.on_action(),
+                    thresholds.off_action(),
+                ),
+            )
+        }
+        None => callback,
+    };
+    #[cfg(feature = "http")]
+    let callback = match events {
+        Some(events) => callback.add(trusted(), move |data| events.publish(&data)),
+        None => callback,
+    };
+    let callback = callback.build();
+    let callback = if opt.dedup {
+        pms7003::dedup(callback)
+    } else {
+        callback
+    };
+    let mut config = pms7003::Config::default();
+    if let Some(baud_rate) = opt.baud_rate.or(file.baud_rate) {
+        config.baud_rate = baud_rate;
+    }
+    if let Some(millis) = opt.assemble_timeout_millis {
+        config.strategy = pms7003::ReadStrategy::Assemble {
+            timeout: Duration::from_millis(millis),
+        };
+    }
+    if opt.drain_reads {
+        config.strategy = pms7003::ReadStrategy::Drain;
+    }
+    if let Some(size) = opt.read_buffer_size {
+        if size == 0 {
+            return Err("--read-buffer-size must be positive".into());
+        }
+        config.read_buffer_size = size;
+    }
+    config.poll_interval = opt.poll_interval_millis.map(Duration::from_millis);
+    if opt.lenient_checksum {
+        config.validation = pms7003::ValidationMode::Lenient;
+    }
+    if let Some(rate) = opt.max_checksum_error_rate {
+        if !(0.0..1.0).contains(&rate) {
+            return Err("--max-checksum-error-rate must be at least 0 and below 1".into());
+        }
+        config.checksum_policy = Some(pms7003::ChecksumPolicy {
+            max_error_rate: rate,
+            reconnect: true,
+            ..Default::default()
+        });
+    }
+    config.drain_on_open = opt.drain_on_open;
+    config.record = opt.record.clone();
+    config.wake_on_start = !opt.no_wake;
+    config.dtr = opt.dtr;
+    config.rts = opt.rts;
+    if opt.little_endian {
+        config.endianness = pms7003::Endianness::Little;
+    }
+    config.variant = variant;
+    #[cfg(feature = "signals")]
+    pms7003::stop_on_signal(&config.stop)?;
+    let _up = pms7003::metrics::mark_up();
+    // Dropping the callback at the end of the read passes on the partial
+    // period of `--metrics-period-seconds`. The dose and state are written
+    // even if the read failed, before the error is reported.
+    let result = pms7003::read_active_with_config(&port, &config, callback);
+    pms7003::metrics::flush_metrics();
+    #[cfg(feature = "serde")]
+    if let Some(path) = &opt.state_file {
+        if let Err(e) = pms7003::metrics::save_state(path) {
+            // The read error, if any, is the one to report.
+            result?;
+            return Err(e.into());
+        }
+    }
+    result?;
 
     Ok(())
 }
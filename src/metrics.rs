@@ -0,0 +1,1872 @@
+use crate::aqi::{aqi_category, aqi_scale, calculate_aqi, AqiStandard, Breakpoints, US_EPA_PM2_5};
+use crate::clock::{real_clock, Clock};
+use crate::derived::{
+    cigarette_equivalent, estimate_visibility_km, iaq_score, mass_consistency_ratio,
+    normalize_to_stp, purifier_effectiveness, Ambient, DEFAULT_PARTICLE_DENSITY,
+};
+use crate::sink::{record_reading, ConcentrationBasis, MetricsSink};
+use crate::stats::{
+    CountRate, DoseTracker, DownsampledWindow, PercentileWindow, RollingStdDev,
+    TimeWeightedAverager, Trend, TrendTracker,
+};
+use crate::{PmsData, Pollutant, Variant};
+use lazy_static::lazy_static;
+#[cfg(feature = "serde")]
+use log::info;
+use log::{error, warn};
+use prometheus_exporter::prometheus::{
+    register_counter, register_gauge, register_gauge_vec, register_int_counter, Counter, Gauge,
+    GaugeVec, IntCounter,
+};
+use prometheus_exporter::Exporter;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "serde")]
+use std::fs;
+#[cfg(feature = "serde")]
+use std::io;
+#[cfg(feature = "serde")]
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, OnceLock, RwLock};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+lazy_static! {
+    pub static ref PARTICLE_CONCENTRATION_STANDARD: GaugeVec = register_gauge_vec!(
+        "particle_concentration_standard",
+        "concentration (CF=1 standard particle) µg/m³",
+        &["particle_size"]
+    )
+    .unwrap();
+    pub static ref PARTICLE_CONCENTRATION_ENVIRONMENT: GaugeVec = register_gauge_vec!(
+        "particle_concentration_environment",
+        "concentration (under atmospheric environment) µg/m³",
+        &["particle_size"]
+    )
+    .unwrap();
+    pub static ref PARTICLE_COUNT: GaugeVec = register_gauge_vec!(
+        "particle_count",
+        "number of particles with diameter beyond particle_size in 0.1 L of air",
+        &["particle_size"]
+    )
+    .unwrap();
+    pub static ref PARTICLE_CONCENTRATION_AQI_SCALE: GaugeVec = register_gauge_vec!(
+        "particle_concentration_aqi_scale",
+        "particle concentration mapped onto the scale of an air quality index, without rounding",
+        &["particle_size", "standard", "concentration_basis"]
+    )
+    .unwrap();
+    pub static ref PARTICLE_COUNT_CONVERTED: GaugeVec = register_gauge_vec!(
+        "particle_count_converted",
+        "number of particles with diameter beyond particle_size per unit of air",
+        &["particle_size", "unit"]
+    )
+    .unwrap();
+    pub static ref PARTICLE_COUNT_STP: GaugeVec = register_gauge_vec!(
+        "particle_count_stp",
+        "particle_count normalized to standard temperature and pressure (0 °C, 1013.25 hPa)",
+        &["particle_size"]
+    )
+    .unwrap();
+    pub static ref PARTICLE_COUNT_RATE: GaugeVec = register_gauge_vec!(
+        "particle_count_rate",
+        "change of particle_count per second between consecutive readings",
+        &["particle_size"]
+    )
+    .unwrap();
+    pub static ref AIR_QUALITY_INDEX: GaugeVec = register_gauge_vec!(
+        "air_quality_index",
+        "air quality index (aqi), e.g. as defined by united states environmental protection agency (us epa)",
+        &["particle_size", "standard", "concentration_basis"]
+    )
+    .unwrap();
+    pub static ref AIR_QUALITY_INDEX_SELECTED: GaugeVec = register_gauge_vec!(
+        "air_quality_index_selected",
+        "air_quality_index of the concentration_basis chosen by the basis policy for the latest reading",
+        &["particle_size", "standard", "concentration_basis"]
+    )
+    .unwrap();
+    // The prometheus crate can't attach exemplars, so the time of the
+    // reading behind the current AQI is exported alongside it instead.
+    pub static ref AIR_QUALITY_INDEX_TIMESTAMP: Gauge = register_gauge!(
+        "air_quality_index_timestamp_seconds",
+        "time of the reading that air_quality_index was computed from, in seconds since the epoch"
+    )
+    .unwrap();
+    pub static ref AIR_QUALITY_INDEX_24H: Gauge = register_gauge!(
+        "air_quality_index_24h",
+        "us epa pm2.5 aqi of the 24-hour average concentration (CF=1 standard particle)"
+    )
+    .unwrap();
+    pub static ref SENSOR_PARTICLE_CONCENTRATION: GaugeVec = register_gauge_vec!(
+        "sensor_particle_concentration",
+        "concentration of the sensor at location µg/m³",
+        &["location", "concentration_basis", "particle_size"]
+    )
+    .unwrap();
+    pub static ref SENSOR_PARTICLE_COUNT: GaugeVec = register_gauge_vec!(
+        "sensor_particle_count",
+        "number of particles with diameter beyond particle_size, from the sensor at location",
+        &["location", "particle_size"]
+    )
+    .unwrap();
+    pub static ref SENSOR_AIR_QUALITY_INDEX: GaugeVec = register_gauge_vec!(
+        "sensor_air_quality_index",
+        "air quality index (aqi) of the sensor at location",
+        &["location", "particle_size", "standard", "concentration_basis"]
+    )
+    .unwrap();
+    pub static ref IAQ_SCORE: Gauge = register_gauge!(
+        "iaq_score",
+        "indoor air quality score from 0 to 100, higher is better, from the dominant us epa aqi"
+    )
+    .unwrap();
+    pub static ref PURIFIER_EFFECTIVENESS: Gauge = register_gauge!(
+        "purifier_effectiveness_percent",
+        "reduction of the pm2.5 concentration (ambient) from the outdoor reference"
+    )
+    .unwrap();
+    pub static ref MASS_CONSISTENCY_RATIO: Gauge = register_gauge!(
+        "mass_consistency_ratio",
+        "reported pm2.5 concentration (ambient) over the one estimated from the particle counts"
+    )
+    .unwrap();
+    pub static ref INCONSISTENT_FRAMES: IntCounter = register_int_counter!(
+        "inconsistent_frames_total",
+        "number of readings whose mass_consistency_ratio was outside the configured range"
+    )
+    .unwrap();
+    pub static ref CIGARETTE_EQUIVALENT: Gauge = register_gauge!(
+        "cigarette_equivalent",
+        "cigarettes per day equivalent to the pm2.5 concentration (berkeley earth approximation)"
+    )
+    .unwrap();
+    pub static ref VISIBILITY_KM: Gauge = register_gauge!(
+        "visibility_km",
+        "visual range estimated from the pm2.5 concentration (CF=1 standard particle) km"
+    )
+    .unwrap();
+    pub static ref PM2_5_PERCENTILE: GaugeVec = register_gauge_vec!(
+        "pm2_5_percentile",
+        "percentile of the pm2.5 concentration (CF=1 standard particle) over a window µg/m³",
+        &["percentile"]
+    )
+    .unwrap();
+    pub static ref PM2_5_TWA: Gauge = register_gauge!(
+        "pm2_5_twa",
+        "time-weighted average of the pm2.5 concentration (CF=1 standard particle) over a window µg/m³"
+    )
+    .unwrap();
+    pub static ref PM2_5_STDDEV: Gauge = register_gauge!(
+        "pm2_5_stddev",
+        "standard deviation of the pm2.5 concentration (CF=1 standard particle) over a window µg/m³"
+    )
+    .unwrap();
+    pub static ref PM2_5_TREND_SLOPE: Gauge = register_gauge!(
+        "pm2_5_trend_slope",
+        "change per day of the daily mean pm2.5 concentration (CF=1 standard particle) µg/m³"
+    )
+    .unwrap();
+    pub static ref PM2_5_DOSE: Counter = register_counter!(
+        "pm2_5_dose_total",
+        "cumulative exposure to pm2.5 (CF=1 standard particle) µg·min/m³"
+    )
+    .unwrap();
+    pub static ref RESERVED_WORD: Gauge = register_gauge!(
+        "reserved_word",
+        "raw value of the reserved word in the data frame, for investigating sensor variants"
+    )
+    .unwrap();
+    pub static ref SENSOR_TEMPERATURE: Gauge = register_gauge!(
+        "sensor_temperature_celsius",
+        "temperature reported by sensors with an onboard thermometer, e.g. the PMS5003ST"
+    )
+    .unwrap();
+    pub static ref SENSOR_HUMIDITY: Gauge = register_gauge!(
+        "sensor_relative_humidity_percent",
+        "relative humidity reported by sensors with an onboard hygrometer, e.g. the PMS5003ST"
+    )
+    .unwrap();
+    pub static ref SENSOR_VERSION: Gauge =
+        register_gauge!("sensor_version", "firmware version reported by the sensor").unwrap();
+    pub static ref AIR_QUALITY: GaugeVec = register_gauge_vec!(
+        "air_quality",
+        "US EPA AQI of the latest reading (ambient), with its concentrations and category as labels",
+        &["pm1_0", "pm2_5", "pm10", "category"]
+    )
+    .unwrap();
+    pub static ref FRAMES_PER_MINUTE: Gauge = register_gauge!(
+        "frames_per_minute",
+        "data frames received per minute over the last 20 s, about 60 for a healthy sensor"
+    )
+    .unwrap();
+    pub static ref STALLED_FRAME: Gauge = register_gauge!(
+        "stalled_frame",
+        "1 while a partial frame hasn't completed for several reads, e.g. on a flaky line"
+    )
+    .unwrap();
+    pub static ref SENSOR_SLOW: Gauge = register_gauge!(
+        "sensor_slow",
+        "1 while frames arrive much less often than expected, e.g. because the fan is failing"
+    )
+    .unwrap();
+    pub static ref METRICS_PERIOD_PARTIAL: Gauge = register_gauge!(
+        "metrics_period_partial",
+        "1 if the metrics hold the mean of a metrics period cut short, e.g. on shutdown"
+    )
+    .unwrap();
+    pub static ref PMS_CONFIG: GaugeVec = register_gauge_vec!(
+        "pms_config",
+        "constant 1, with the exporter's configuration as labels",
+        &[
+            "version",
+            "settle_time_seconds",
+            "aqi_standards",
+            "variant",
+            "metrics_period_seconds",
+            "combined",
+        ]
+    )
+    .unwrap();
+    pub static ref PMS_EXPORTER_UP: Gauge =
+        register_gauge!("pms_exporter_up", "1 while the sensor read loop is running").unwrap();
+    pub static ref PMS_LAST_SCRAPE_TIMESTAMP: Gauge = register_gauge!(
+        "pms_last_scrape_timestamp_seconds",
+        "unix time of the most recent prometheus scrape"
+    )
+    .unwrap();
+    pub static ref PMS_SCRAPES: IntCounter =
+        register_int_counter!("pms_scrapes_total", "number of prometheus scrapes served").unwrap();
+    pub static ref REJECTED_VALUES: IntCounter = register_int_counter!(
+        "rejected_values_total",
+        "number of non-finite values that were not exported"
+    )
+    .unwrap();
+    pub static ref CHECKSUM_ERRORS: IntCounter = register_int_counter!(
+        "checksum_errors_total",
+        "number of data frames received with a checksum mismatch"
+    )
+    .unwrap();
+    pub static ref NO_SYNC: IntCounter = register_int_counter!(
+        "no_sync_total",
+        "number of times a long stretch of input held no valid frame, e.g. at the wrong baud rate"
+    )
+    .unwrap();
+    pub static ref REJECTED_FRAMES: IntCounter = register_int_counter!(
+        "rejected_frames_total",
+        "number of data frames dropped by the configured validator"
+    )
+    .unwrap();
+    pub static ref ALL_ZERO_FRAMES: IntCounter = register_int_counter!(
+        "all_zero_frames_total",
+        "number of data frames received with every concentration and count zero"
+    )
+    .unwrap();
+    pub static ref WATCHDOG_RESTARTS: IntCounter = register_int_counter!(
+        "watchdog_restarts_total",
+        "number of times the port was reopened because a read hung"
+    )
+    .unwrap();
+    pub static ref DUPLICATE_FRAMES: IntCounter = register_int_counter!(
+        "duplicate_frames_total",
+        "number of readings suppressed for being identical to the previous one"
+    )
+    .unwrap();
+    /// Labels of the one `air_quality` series, see `MetricsConfig::combined`.
+    static ref AIR_QUALITY_LABELS: Mutex<Option<[String; 4]>> = Mutex::new(None);
+    static ref LAST_READING: Mutex<Option<(SystemTime, PmsData)>> = Mutex::new(None);
+    static ref METRICS_CONFIG: RwLock<MetricsConfig> = RwLock::new(MetricsConfig::default());
+    static ref PM2_5_WINDOW: Mutex<Option<PercentileWindow>> = Mutex::new(None);
+    static ref PM2_5_TWA_AVERAGER: Mutex<Option<TimeWeightedAverager>> = Mutex::new(None);
+    static ref PM2_5_STDDEV_TRACKER: Mutex<Option<RollingStdDev>> = Mutex::new(None);
+    static ref PM2_5_TREND: Mutex<Option<TrendTracker>> = Mutex::new(None);
+    static ref PM2_5_DOSE_TRACKER: Mutex<Option<DoseTracker>> = Mutex::new(None);
+    static ref AMBIENT: Mutex<Option<Ambient>> = Mutex::new(None);
+    static ref OUTDOOR_PM2_5: Mutex<Option<f64>> = Mutex::new(None);
+    static ref COUNT_RATE: Mutex<Option<CountRate>> = Mutex::new(None);
+    static ref PM2_5_24H_WINDOW: Mutex<Option<DownsampledWindow>> = Mutex::new(None);
+    static ref GAUGE_CACHE: GaugeCache = GaugeCache::default();
+}
+
+/// Value of the `standard` label for the non-standard PM1.0 sub-index.
+const CUSTOM_STANDARD: &str = "custom";
+
+/// The volume of air that particle counts are given for.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum CountUnit {
+    /// 0.1 L, as reported by the sensor.
+    #[default]
+    PerDeciliter,
+    PerLiter,
+    PerCubicMeter,
+}
+
+impl CountUnit {
+    pub const ALL: [CountUnit; 3] = [
+        CountUnit::PerDeciliter,
+        CountUnit::PerLiter,
+        CountUnit::PerCubicMeter,
+    ];
+
+    /// Value of the `unit` metric label.
+    pub fn label(self) -> &'static str {
+        match self {
+            CountUnit::PerDeciliter => "0.1l",
+            CountUnit::PerLiter => "l",
+            CountUnit::PerCubicMeter => "m3",
+        }
+    }
+
+    /// What a count per 0.1 L is multiplied by.
+    pub fn factor(self) -> f64 {
+        match self {
+            CountUnit::PerDeciliter => 1.0,
+            CountUnit::PerLiter => 10.0,
+            CountUnit::PerCubicMeter => 10_000.0,
+        }
+    }
+}
+
+impl FromStr for CountUnit {
+    type Err = String;
+
+    /// Parses a `unit` label value, e.g. `m3`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        CountUnit::ALL
+            .iter()
+            .copied()
+            .find(|unit| unit.label() == s)
+            .ok_or_else(|| format!("unknown count unit: {}", s))
+    }
+}
+
+/// Which concentrations `air_quality_index_selected` is computed from.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BasisPolicy {
+    /// Always the given basis.
+    Fixed(ConcentrationBasis),
+    /// CF=1 while the CF=1 PM2.5 is below this many µg/m³, and atmospheric
+    /// from there on, for methodologies that only trust the atmospheric
+    /// correction at higher concentrations.
+    Threshold(f64),
+}
+
+impl Default for BasisPolicy {
+    /// CF=1, like `air_quality_index_24h`.
+    fn default() -> Self {
+        BasisPolicy::Fixed(ConcentrationBasis::Standard)
+    }
+}
+
+impl BasisPolicy {
+    /// The basis to compute the AQI of `data` from.
+    pub fn basis(self, data: &PmsData) -> ConcentrationBasis {
+        match self {
+            BasisPolicy::Fixed(basis) => basis,
+            BasisPolicy::Threshold(threshold) if f64::from(data.pm2_5_cf1) < threshold => {
+                ConcentrationBasis::Standard
+            }
+            BasisPolicy::Threshold(_) => ConcentrationBasis::Environment,
+        }
+    }
+}
+
+impl FromStr for BasisPolicy {
+    type Err = String;
+
+    /// Parses `standard`, `environment`, or `threshold:<µg/m³>`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(threshold) = s.strip_prefix("threshold:") {
+            return match threshold.parse::<f64>() {
+                Ok(threshold) if threshold.is_finite() && threshold >= 0.0 => {
+                    Ok(BasisPolicy::Threshold(threshold))
+                }
+                _ => Err(format!("invalid basis threshold: {}", threshold)),
+            };
+        }
+        ConcentrationBasis::ALL
+            .iter()
+            .copied()
+            .find(|basis| basis.label() == s)
+            .map(BasisPolicy::Fixed)
+            .ok_or_else(|| format!("unknown basis policy: {}", s))
+    }
+}
+
+/// Controls which optional series `update_metrics` exports.
+#[derive(Debug, Clone)]
+pub struct MetricsConfig {
+    /// Standards to export `air_quality_index` for, each under its own
+    /// `standard` label.
+    pub aqi_standards: Vec<AqiStandard>,
+    /// Chooses the basis of `air_quality_index_selected` for each reading.
+    /// The chosen basis is in its `concentration_basis` label.
+    pub aqi_basis: BasisPolicy,
+    /// Breakpoints for a PM1.0 sub-index, exported as `air_quality_index`
+    /// with `particle_size="1.0"`. The US EPA defines no AQI for PM1.0, so
+    /// this is non-standard and off by default.
+    pub pm1_0_breakpoints: Option<Breakpoints>,
+    /// Exports percentiles of PM2.5 over a sliding window as
+    /// `pm2_5_percentile`.
+    pub pm2_5_percentiles: Option<PercentileConfig>,
+    /// Exports the time-weighted average of PM2.5 over this window as
+    /// `pm2_5_twa`.
+    pub pm2_5_twa_window: Option<Duration>,
+    /// Also exports the standard deviation of PM2.5 over `pm2_5_twa_window`
+    /// as `pm2_5_stddev`, each reading weighted equally. A value near zero
+    /// over a long window while the concentration isn't zero suggests a
+    /// stuck sensor.
+    pub pm2_5_stddev: bool,
+    /// Exports the slope of the daily mean PM2.5 over this many days as
+    /// `pm2_5_trend_slope`, see `stats::TrendTracker` and `pm2_5_trend`.
+    /// Since it takes days to fill, it is best combined with `save_state`.
+    pub pm2_5_trend_days: Option<usize>,
+    /// Exports how the reported PM2.5 compares to the mass estimated from the
+    /// particle counts as `mass_consistency_ratio`, see
+    /// `derived::mass_consistency_ratio`, and counts outliers.
+    pub mass_consistency: Option<ConsistencyConfig>,
+    /// Accumulates the PM2.5 exposure in `pm2_5_dose_total`, attributing at
+    /// most this long to a single reading. See `stats::DoseTracker`.
+    pub pm2_5_dose_max_gap: Option<Duration>,
+    /// Exports the reserved word of every frame as `reserved_word`. Its
+    /// meaning varies between sensor variants.
+    pub export_reserved_word: bool,
+    /// Exports `air_quality_index_24h`, the US EPA AQI of the PM2.5
+    /// concentration averaged over the last 24 hours. This is how the
+    /// official AQI is defined, so unlike `air_quality_index` it is
+    /// comparable to regulator figures and isn't thrown off by short spikes.
+    /// Readings are kept as one-minute means.
+    pub aqi_24h: bool,
+    /// Exports the change of each particle count per second since the
+    /// previous reading as `particle_count_rate`. Decreases are skipped.
+    pub count_rates: bool,
+    /// Exports the particle counts normalized to standard temperature and
+    /// pressure as `particle_count_stp`, see `derived::normalize_to_stp`.
+    /// Nothing is exported until the conditions are given to `set_ambient`.
+    pub stp_counts: bool,
+    /// Exports the PM2.5 and PM10 concentrations on the scale of each of
+    /// `aqi_standards` as `particle_concentration_aqi_scale`. Unlike
+    /// `air_quality_index`, the values aren't rounded, so they can share a
+    /// panel with the concentrations.
+    pub aqi_scale: bool,
+    /// Also exports the particle counts per this volume of air as
+    /// `particle_count_converted`, with the volume in the `unit` label.
+    /// `particle_count` stays per 0.1 L, as sent by the sensor.
+    pub count_unit: CountUnit,
+    /// Exports each reading as a single `air_quality` series instead of all
+    /// other reading series: the US EPA AQI as the value, and the ambient
+    /// PM1.0, PM2.5 and PM10 concentrations and the AQI category as labels.
+    /// The previous series is removed, so there is only ever one, but every
+    /// distinct reading is a new series to the backend.
+    pub combined: bool,
+    /// Timestamps the readings for the windows, averages and rates above, and
+    /// `last_reading_at`.
+    pub clock: Arc<dyn Clock>,
+}
+
+impl Default for MetricsConfig {
+    fn default() -> Self {
+        MetricsConfig {
+            aqi_standards: vec![AqiStandard::UsEpa],
+            aqi_basis: BasisPolicy::default(),
+            pm1_0_breakpoints: None,
+            pm2_5_percentiles: None,
+            pm2_5_twa_window: None,
+            pm2_5_stddev: false,
+            pm2_5_trend_days: None,
+            mass_consistency: None,
+            pm2_5_dose_max_gap: None,
+            export_reserved_word: false,
+            aqi_24h: false,
+            count_rates: false,
+            stp_counts: false,
+            aqi_scale: false,
+            count_unit: CountUnit::default(),
+            combined: false,
+            clock: real_clock(),
+        }
+    }
+}
+
+/// See `MetricsConfig::mass_consistency`.
+#[derive(Debug, Clone)]
+pub struct ConsistencyConfig {
+    /// Particle density in g/cm³, see `derived::mass_from_counts`.
+    pub density: f64,
+    /// Readings with a ratio outside this range are counted in
+    /// `inconsistent_frames_total`.
+    pub min_ratio: f64,
+    pub max_ratio: f64,
+}
+
+impl Default for ConsistencyConfig {
+    fn default() -> Self {
+        ConsistencyConfig {
+            density: DEFAULT_PARTICLE_DENSITY,
+            min_ratio: 0.1,
+            max_ratio: 10.0,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct PercentileConfig {
+    pub window: Duration,
+    /// Quantiles in `[0, 1]`, e.g. `vec![0.5, 0.95]`.
+    pub quantiles: Vec<f64>,
+}
+
+impl Default for PercentileConfig {
+    fn default() -> Self {
+        PercentileConfig {
+            window: Duration::from_secs(3600),
+            quantiles: vec![0.5, 0.95],
+        }
+    }
+}
+
+/// Replaces the configuration used by `update_metrics` and `reset_metrics`.
+/// Resets any windowed state.
+pub fn configure(config: MetricsConfig) {
+    *PM2_5_WINDOW.lock().unwrap() = config
+        .pm2_5_percentiles
+        .as_ref()
+        .map(|percentiles| PercentileWindow::new(percentiles.window));
+    *PM2_5_TWA_AVERAGER.lock().unwrap() = config.pm2_5_twa_window.map(TimeWeightedAverager::new);
+    *PM2_5_STDDEV_TRACKER.lock().unwrap() = config
+        .pm2_5_twa_window
+        .filter(|_| config.pm2_5_stddev)
+        .map(RollingStdDev::new);
+    *PM2_5_TREND.lock().unwrap() = config.pm2_5_trend_days.map(TrendTracker::new);
+    *PM2_5_DOSE_TRACKER.lock().unwrap() = config.pm2_5_dose_max_gap.map(DoseTracker::new);
+    *PM2_5_24H_WINDOW.lock().unwrap() = Some(DownsampledWindow::new(
+        Duration::from_secs(24 * 60 * 60),
+        Duration::from_secs(60),
+    ))
+    .filter(|_| config.aqi_24h);
+    *COUNT_RATE.lock().unwrap() = Some(CountRate::new()).filter(|_| config.count_rates);
+    *METRICS_CONFIG.write().unwrap() = config;
+}
+
+/// The trackers behind the windowed and cumulative series, as kept by
+/// `save_state`.
+#[cfg(feature = "serde")]
+#[derive(Serialize, Deserialize)]
+struct State {
+    pm2_5_window: Option<PercentileWindow>,
+    pm2_5_twa: Option<TimeWeightedAverager>,
+    pm2_5_stddev: Option<RollingStdDev>,
+    pm2_5_trend: Option<TrendTracker>,
+    pm2_5_dose: Option<DoseTracker>,
+    pm2_5_24h: Option<DownsampledWindow>,
+    count_rate: Option<CountRate>,
+}
+
+/// Writes the state of the percentile window, the time-weighted average and
+/// standard deviation, the trend, the dose, the 24-hour AQI window and the
+/// count rates to `path` as JSON, so that `load_state` can resume them after
+/// a restart. The file is replaced atomically, so a crash while saving
+/// leaves the previous state.
+#[cfg(feature = "serde")]
+pub fn save_state<P: AsRef<Path>>(path: P) -> io::Result<()> {
+    let state = State {
+        pm2_5_window: PM2_5_WINDOW.lock().unwrap().clone(),
+        pm2_5_twa: PM2_5_TWA_AVERAGER.lock().unwrap().clone(),
+        pm2_5_stddev: PM2_5_STDDEV_TRACKER.lock().unwrap().clone(),
+        pm2_5_trend: PM2_5_TREND.lock().unwrap().clone(),
+        pm2_5_dose: PM2_5_DOSE_TRACKER.lock().unwrap().clone(),
+        pm2_5_24h: PM2_5_24H_WINDOW.lock().unwrap().clone(),
+        count_rate: COUNT_RATE.lock().unwrap().clone(),
+    };
+    let path = path.as_ref();
+    let mut temporary = path.as_os_str().to_owned();
+    temporary.push(".tmp");
+    fs::write(&temporary, serde_json::to_vec(&state)?)?;
+    fs::rename(&temporary, path)
+}
+
+/// Restores the state saved by `save_state` into the trackers enabled by
+/// `configure`, which must be called first. A saved tracker keeps the window
+/// it was created with, and the ones that aren't enabled are left out.
+/// `pm2_5_dose_total` continues from the saved dose.
+///
+/// If the file is missing or can't be parsed, a warning is logged and the
+/// trackers start fresh. Returns whether the state was restored.
+#[cfg(feature = "serde")]
+pub fn load_state<P: AsRef<Path>>(path: P) -> bool {
+    let path = path.as_ref();
+    let state: State = match fs::read(path)
+        .and_then(|contents| serde_json::from_slice(&contents).map_err(io::Error::from))
+    {
+        Ok(state) => state,
+        Err(e) => {
+            warn!("Not restoring state from {:?}, starting fresh: {}", path, e);
+            return false;
+        }
+    };
+    fn restore<T>(slot: &Mutex<Option<T>>, saved: Option<T>) {
+        let mut slot = slot.lock().unwrap();
+        if let (Some(tracker), Some(saved)) = (slot.as_mut(), saved) {
+            *tracker = saved;
+        }
+    }
+    restore(&PM2_5_WINDOW, state.pm2_5_window);
+    restore(&PM2_5_TWA_AVERAGER, state.pm2_5_twa);
+    restore(&PM2_5_STDDEV_TRACKER, state.pm2_5_stddev);
+    restore(&PM2_5_TREND, state.pm2_5_trend);
+    restore(&PM2_5_DOSE_TRACKER, state.pm2_5_dose);
+    restore(&PM2_5_24H_WINDOW, state.pm2_5_24h);
+    restore(&COUNT_RATE, state.count_rate);
+    if let Some(tracker) = PM2_5_DOSE_TRACKER.lock().unwrap().as_ref() {
+        PM2_5_DOSE.reset();
+        PM2_5_DOSE.inc_by(tracker.cumulative_dose());
+    }
+    info!("Restored state from {:?}", path);
+    true
+}
+
+/// Spawns a thread that calls `save_state` every `interval`, logging
+/// failures.
+#[cfg(feature = "serde")]
+pub fn save_state_every(path: PathBuf, interval: Duration) -> thread::JoinHandle<()> {
+    thread::spawn(move || loop {
+        thread::sleep(interval);
+        if let Err(e) = save_state(&path) {
+            error!("Could not save state to {:?}: {}", path, e);
+        }
+    })
+}
+
+fn percentile_label(quantile: f64) -> String {
+    format!("{}", quantile * 100.0)
+}
+
+const CONCENTRATION_SIZES: &[&str] = &["1.0", "2.5", "10.0"];
+const COUNT_SIZES: &[&str] = &["0.3", "0.5", "1.0", "2.5", "5.0", "10.0"];
+const STANDARD_LABELS: &[&str] = &["us_epa", "eu_caqi", CUSTOM_STANDARD];
+
+/// Child gauges of the vectors written for every frame, each looked up in
+/// its `GaugeVec` on first use only. A child is created when first set, so
+/// that series for unconfigured standards don't appear in the output.
+#[derive(Default)]
+struct GaugeCache {
+    /// Indexed by basis, then by `CONCENTRATION_SIZES`.
+    concentration: [[OnceLock<Gauge>; 3]; 2],
+    /// Indexed by `COUNT_SIZES`.
+    count: [OnceLock<Gauge>; 6],
+    /// Indexed by `CONCENTRATION_SIZES`, `STANDARD_LABELS` and basis.
+    aqi: [[[OnceLock<Gauge>; 2]; 3]; 3],
+}
+
+fn basis_index(basis: ConcentrationBasis) -> usize {
+    match basis {
+        ConcentrationBasis::Standard => 0,
+        ConcentrationBasis::Environment => 1,
+    }
+}
+
+fn label_index(labels: &[&str], label: &str) -> Option<usize> {
+    labels.iter().position(|&l| l == label)
+}
+
+/// Calls `f` with the child of `vec` for `labels`, taken from `slot` if
+/// there is one. Labels outside the cache are looked up every time.
+fn with_gauge(
+    vec: &GaugeVec,
+    slot: Option<&OnceLock<Gauge>>,
+    labels: &[&str],
+    f: impl FnOnce(&Gauge),
+) {
+    match slot {
+        Some(slot) => f(slot.get_or_init(|| vec.with_label_values(labels))),
+        None => f(&vec.with_label_values(labels)),
+    }
+}
+
+pub fn update_aqi(value: Option<u32>, metric: &Gauge) {
+    match value {
+        None => error!("Could not compute AQI: value is out of range"),
+        Some(v) => metric.set(v.into()),
+    }
+}
+
+/// Sets `gauge` to `value` unless it is NaN or infinite, in which case the
+/// gauge keeps its previous value and `rejected_values_total` is
+/// incremented.
+pub fn set_gauge_checked(gauge: &Gauge, value: f64) {
+    if value.is_finite() {
+        gauge.set(value);
+    } else {
+        warn!("Not exporting non-finite value {}", value);
+        REJECTED_VALUES.inc();
+    }
+}
+
+/// Records the sensor's firmware version. Called once with the first frame.
+pub fn update_sensor_version(data: &PmsData) {
+    if let Some(version) = data.version() {
+        SENSOR_VERSION.set(version.into());
+    }
+}
+
+/// Sets `PMS_EXPORTER_UP` to 1 until the returned guard is dropped.
+///
+/// Hold the guard for as long as the read loop runs, e.g.
+/// `let _up = mark_up(); read_active(port, callback)`.
+pub fn mark_up() -> UpGuard {
+    PMS_EXPORTER_UP.set(1.0);
+    UpGuard { _private: () }
+}
+
+pub struct UpGuard {
+    _private: (),
+}
+
+impl Drop for UpGuard {
+    fn drop(&mut self) {
+        PMS_EXPORTER_UP.set(0.0);
+    }
+}
+
+pub fn record_scrape(at: SystemTime) {
+    PMS_SCRAPES.inc();
+    if let Ok(since_epoch) = at.duration_since(UNIX_EPOCH) {
+        PMS_LAST_SCRAPE_TIMESTAMP.set(since_epoch.as_secs_f64());
+    }
+}
+
+/// Spawns a thread that calls `record_scrape` for every request served by
+/// `exporter`.
+pub fn track_scrapes(exporter: Exporter) {
+    thread::spawn(move || loop {
+        let _guard = exporter.wait_request();
+        record_scrape(now());
+    });
+}
+
+/// The settings exported as `pms_config`, so that a fleet of exporters can
+/// be audited from the metrics backend.
+#[derive(Debug, Clone, Default)]
+pub struct ExporterConfig {
+    pub settle_time: Duration,
+    pub aqi_standards: Vec<AqiStandard>,
+    pub variant: Variant,
+    /// See `callback::downsample`.
+    pub metrics_period: Option<Duration>,
+    /// See `MetricsConfig::combined`.
+    pub combined: bool,
+}
+
+/// Replaces the `pms_config` series with one for `config`. Called once at
+/// startup. The standards are joined with commas, and the metrics period is
+/// empty if readings aren't downsampled.
+pub fn export_config(config: &ExporterConfig) {
+    let standards: Vec<_> = config.aqi_standards.iter().map(|s| s.label()).collect();
+    let settle_time = config.settle_time.as_secs_f64().to_string();
+    let metrics_period = config
+        .metrics_period
+        .map(|period| period.as_secs_f64().to_string())
+        .unwrap_or_default();
+    PMS_CONFIG.reset();
+    PMS_CONFIG
+        .with_label_values(&[
+            env!("CARGO_PKG_VERSION"),
+            &settle_time,
+            &standards.join(","),
+            config.variant.name(),
+            &metrics_period,
+            &config.combined.to_string(),
+        ])
+        .set(1.0);
+}
+
+/// Sets the ambient temperature and pressure used for `particle_count_stp`,
+/// e.g. from a weather station. Applies from the next reading on.
+pub fn set_ambient(ambient: Ambient) {
+    *AMBIENT.lock().unwrap() = Some(ambient);
+}
+
+/// Sets the outdoor PM2.5 concentration (µg/m³) that the readings are
+/// compared against for `purifier_effectiveness_percent`, e.g. a fixed
+/// baseline, or the ambient concentration from a second sensor outside,
+/// updated from its callback. `None` clears it, and the gauge is NaN until
+/// one is set again. Applies from the next reading on.
+pub fn set_outdoor_pm2_5(concentration: Option<f64>) {
+    *OUTDOOR_PM2_5.lock().unwrap() = concentration;
+}
+
+/// Whether PM2.5 has been improving or worsening over the last
+/// `MetricsConfig::pm2_5_trend_days`, e.g. for an arrow on a display. `None`
+/// if it isn't configured or there are fewer than two days of readings.
+pub fn pm2_5_trend() -> Option<Trend> {
+    PM2_5_TREND.lock().unwrap().as_ref()?.trend()
+}
+
+/// Returns the reading most recently passed to `update_metrics`, if any.
+pub fn last_reading() -> Option<PmsData> {
+    last_reading_at().map(|(_, data)| data)
+}
+
+/// The time from `MetricsConfig::clock`, which readings are recorded at.
+pub(crate) fn now() -> SystemTime {
+    METRICS_CONFIG.read().unwrap().clock.now()
+}
+
+/// Like `last_reading`, but also returns when it was recorded.
+pub fn last_reading_at() -> Option<(SystemTime, PmsData)> {
+    LAST_READING.lock().unwrap().clone()
+}
+
+/// Exports readings into the Prometheus gauges in this module.
+///
+/// Child gauges are resolved once and cached, so recording a frame doesn't
+/// go through the label maps of the `GaugeVec`s.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PrometheusSink;
+
+impl MetricsSink for PrometheusSink {
+    fn record_concentration(&self, basis: ConcentrationBasis, size: &str, value: f64) {
+        let vec = match basis {
+            ConcentrationBasis::Standard => &*PARTICLE_CONCENTRATION_STANDARD,
+            ConcentrationBasis::Environment => &*PARTICLE_CONCENTRATION_ENVIRONMENT,
+        };
+        let slot = label_index(CONCENTRATION_SIZES, size)
+            .map(|i| &GAUGE_CACHE.concentration[basis_index(basis)][i]);
+        with_gauge(vec, slot, &[size], |gauge| set_gauge_checked(gauge, value));
+    }
+
+    fn record_count(&self, size: &str, value: f64) {
+        let slot = label_index(COUNT_SIZES, size).map(|i| &GAUGE_CACHE.count[i]);
+        with_gauge(&PARTICLE_COUNT, slot, &[size], |gauge| {
+            set_gauge_checked(gauge, value)
+        });
+    }
+
+    fn record_aqi(&self, basis: ConcentrationBasis, size: &str, standard: &str, value: u32) {
+        let slot = label_index(CONCENTRATION_SIZES, size)
+            .zip(label_index(STANDARD_LABELS, standard))
+            .map(|(i, j)| &GAUGE_CACHE.aqi[i][j][basis_index(basis)]);
+        let labels = [size, standard, basis.label()];
+        with_gauge(&AIR_QUALITY_INDEX, slot, &labels, |gauge| {
+            gauge.set(value.into())
+        });
+    }
+}
+
+/// Exports readings from one of several sensors into the `sensor_*` gauges,
+/// under its own `location` label. Use with `read_multi`, e.g. with
+/// `record_reading(&LocationSink::new(&sensor.location), &data, &standards)`
+/// as the callback.
+#[derive(Debug, Clone)]
+pub struct LocationSink {
+    location: String,
+}
+
+impl LocationSink {
+    pub fn new(location: &str) -> Self {
+        LocationSink {
+            location: location.to_string(),
+        }
+    }
+}
+
+impl MetricsSink for LocationSink {
+    fn record_concentration(&self, basis: ConcentrationBasis, size: &str, value: f64) {
+        let labels = [self.location.as_str(), basis.label(), size];
+        set_gauge_checked(
+            &SENSOR_PARTICLE_CONCENTRATION.with_label_values(&labels),
+            value,
+        );
+    }
+
+    fn record_count(&self, size: &str, value: f64) {
+        let labels = [self.location.as_str(), size];
+        set_gauge_checked(&SENSOR_PARTICLE_COUNT.with_label_values(&labels), value);
+    }
+
+    fn record_aqi(&self, basis: ConcentrationBasis, size: &str, standard: &str, value: u32) {
+        SENSOR_AIR_QUALITY_INDEX
+            .with_label_values(&[&self.location, size, standard, basis.label()])
+            .set(value.into());
+    }
+}
+
+/// Sets `air_quality_index_selected` from the basis `config.aqi_basis`
+/// chooses for `data`, and removes the series of the other basis.
+fn update_selected_aqi(data: &PmsData, config: &MetricsConfig) {
+    let selected = config.aqi_basis.basis(data);
+    for standard in &config.aqi_standards {
+        for (pollutant, breakpoints) in [
+            (Pollutant::Pm2_5, standard.pm2_5()),
+            (Pollutant::Pm10, standard.pm10()),
+        ] {
+            for basis in ConcentrationBasis::ALL {
+                let labels = [pollutant.label(), standard.label(), basis.label()];
+                if basis != selected {
+                    let _ = AIR_QUALITY_INDEX_SELECTED.remove_label_values(&labels);
+                    continue;
+                }
+                let gauge = AIR_QUALITY_INDEX_SELECTED.with_label_values(&labels);
+                match calculate_aqi(breakpoints, data.concentration(pollutant, basis)) {
+                    Some(value) => gauge.set(value.into()),
+                    None => gauge.set(f64::NAN),
+                }
+            }
+        }
+    }
+}
+
+/// Exports `data` into the Prometheus gauges, including the optional series
+/// enabled through `configure`.
+pub fn update_metrics(data: &PmsData) {
+    update_metrics_with(&PrometheusSink, data);
+}
+
+/// Like `update_metrics`, but records the concentrations, counts and AQI
+/// through `sink`. The remaining series are Prometheus-only.
+pub fn update_metrics_with(sink: &dyn MetricsSink, data: &PmsData) {
+    let config = METRICS_CONFIG.read().unwrap();
+    let now = config.clock.now();
+    *LAST_READING.lock().unwrap() = Some((now, data.clone()));
+    if config.combined {
+        update_air_quality(data);
+        return;
+    }
+
+    record_reading(sink, data, &config.aqi_standards);
+    update_selected_aqi(data, &config);
+    if let Ok(since_epoch) = now.duration_since(UNIX_EPOCH) {
+        AIR_QUALITY_INDEX_TIMESTAMP.set(since_epoch.as_secs_f64());
+    }
+    if config.aqi_scale {
+        for standard in &config.aqi_standards {
+            for basis in ConcentrationBasis::ALL {
+                for (pollutant, breakpoints) in [
+                    (Pollutant::Pm2_5, standard.pm2_5()),
+                    (Pollutant::Pm10, standard.pm10()),
+                ] {
+                    let concentration = data.concentration(pollutant, basis);
+                    if let Some(value) = aqi_scale(breakpoints, concentration) {
+                        PARTICLE_CONCENTRATION_AQI_SCALE
+                            .with_label_values(&[
+                                pollutant.label(),
+                                standard.label(),
+                                basis.label(),
+                            ])
+                            .set(value);
+                    }
+                }
+            }
+        }
+    }
+    if let Some(breakpoints) = &config.pm1_0_breakpoints {
+        for (basis, pm1) in [
+            (ConcentrationBasis::Standard, data.pm1_cf1),
+            (ConcentrationBasis::Environment, data.pm1_atmo),
+        ] {
+            match calculate_aqi(breakpoints, pm1.into()) {
+                Some(value) => sink.record_aqi(basis, "1.0", CUSTOM_STANDARD, value),
+                None => error!("Could not compute AQI: value is out of range"),
+            }
+        }
+    }
+
+    if let (true, Some(reserved)) = (config.export_reserved_word, data.reserved) {
+        RESERVED_WORD.set(reserved.into());
+    }
+    if let Some(temperature) = data.temperature() {
+        SENSOR_TEMPERATURE.set(temperature);
+    }
+    if let Some(humidity) = data.humidity() {
+        SENSOR_HUMIDITY.set(humidity);
+    }
+
+    IAQ_SCORE.set(iaq_score(data).into());
+    if let Some(consistency) = &config.mass_consistency {
+        let ratio = mass_consistency_ratio(data, consistency.density);
+        if let Some(ratio) = ratio {
+            if !(consistency.min_ratio..=consistency.max_ratio).contains(&ratio) {
+                warn!(
+                    "reported PM2.5 is {:.1} times the mass estimated from the particle counts",
+                    ratio
+                );
+                INCONSISTENT_FRAMES.inc();
+            }
+        }
+        // Infinite for mass without particles, which is a valid result
+        // rather than a failed calculation, so it isn't counted as rejected.
+        match ratio {
+            Some(ratio) if ratio.is_infinite() => MASS_CONSISTENCY_RATIO.set(f64::NAN),
+            Some(ratio) => set_gauge_checked(&MASS_CONSISTENCY_RATIO, ratio),
+            None => MASS_CONSISTENCY_RATIO.set(f64::NAN),
+        }
+    }
+    let effectiveness = OUTDOOR_PM2_5
+        .lock()
+        .unwrap()
+        .and_then(|outdoor| purifier_effectiveness(data.pm2_5_atmo.into(), outdoor));
+    PURIFIER_EFFECTIVENESS.set(effectiveness.unwrap_or(f64::NAN));
+    set_gauge_checked(
+        &CIGARETTE_EQUIVALENT,
+        cigarette_equivalent(data.pm2_5_cf1.into()),
+    );
+    set_gauge_checked(
+        &VISIBILITY_KM,
+        estimate_visibility_km(data.pm2_5_cf1.into()),
+    );
+
+    if let (Some(percentiles), Some(window)) = (
+        &config.pm2_5_percentiles,
+        PM2_5_WINDOW.lock().unwrap().as_mut(),
+    ) {
+        window.push(now, data.pm2_5_cf1.into());
+        for &quantile in &percentiles.quantiles {
+            if let Some(value) = window.percentile(quantile) {
+                set_gauge_checked(
+                    &PM2_5_PERCENTILE.with_label_values(&[&percentile_label(quantile)]),
+                    value,
+                );
+            }
+        }
+    }
+
+    if let Some(averager) = PM2_5_TWA_AVERAGER.lock().unwrap().as_mut() {
+        averager.push(now, data.pm2_5_cf1.into());
+        if let Some(value) = averager.average() {
+            set_gauge_checked(&PM2_5_TWA, value);
+        }
+    }
+
+    if let Some(tracker) = PM2_5_STDDEV_TRACKER.lock().unwrap().as_mut() {
+        tracker.push(now, data.pm2_5_cf1.into());
+        if let Some(value) = tracker.std_dev() {
+            set_gauge_checked(&PM2_5_STDDEV, value);
+        }
+    }
+
+    if let Some(tracker) = PM2_5_TREND.lock().unwrap().as_mut() {
+        tracker.push(now, data.pm2_5_cf1.into());
+        if let Some(slope) = tracker.slope() {
+            set_gauge_checked(&PM2_5_TREND_SLOPE, slope);
+        }
+    }
+
+    if let Some(tracker) = PM2_5_DOSE_TRACKER.lock().unwrap().as_mut() {
+        PM2_5_DOSE.inc_by(tracker.push(now, data.pm2_5_cf1.into()));
+    }
+
+    let counts = [
+        data.pm0_3_count,
+        data.pm0_5_count,
+        data.pm1_0_count,
+        data.pm2_5_count,
+        data.pm5_0_count,
+        data.pm10_0_count,
+    ];
+    if config.count_unit != CountUnit::PerDeciliter {
+        for (size, count) in COUNT_SIZES.iter().zip(counts) {
+            set_gauge_checked(
+                &PARTICLE_COUNT_CONVERTED.with_label_values(&[size, config.count_unit.label()]),
+                f64::from(count) * config.count_unit.factor(),
+            );
+        }
+    }
+    if let (true, Some(ambient)) = (config.stp_counts, *AMBIENT.lock().unwrap()) {
+        for (size, count) in COUNT_SIZES.iter().zip(counts) {
+            set_gauge_checked(
+                &PARTICLE_COUNT_STP.with_label_values(&[size]),
+                normalize_to_stp(count.into(), ambient),
+            );
+        }
+    }
+
+    if let Some(count_rate) = COUNT_RATE.lock().unwrap().as_mut() {
+        let rates = count_rate.push(now, data);
+        for (size, rate) in COUNT_SIZES.iter().zip(rates) {
+            if let Some(rate) = rate {
+                set_gauge_checked(&PARTICLE_COUNT_RATE.with_label_values(&[size]), rate);
+            }
+        }
+    }
+
+    if let Some(window) = PM2_5_24H_WINDOW.lock().unwrap().as_mut() {
+        window.push(now, data.pm2_5_cf1.into());
+        if let Some(mean) = window.mean() {
+            update_aqi(calculate_aqi(&US_EPA_PM2_5, mean), &AIR_QUALITY_INDEX_24H);
+        }
+    }
+}
+
+/// Replaces the `air_quality` series with one for `data`.
+fn update_air_quality(data: &PmsData) {
+    let basis = ConcentrationBasis::Environment;
+    let aqi = match data.aqi(basis) {
+        Some(aqi) => aqi,
+        None => {
+            error!("Could not compute AQI: value is out of range");
+            return;
+        }
+    };
+    let labels = [
+        data.pm1_atmo.to_string(),
+        data.pm2_5_atmo.to_string(),
+        data.pm10_atmo.to_string(),
+        aqi_category(aqi).name().to_string(),
+    ];
+    let mut previous = AIR_QUALITY_LABELS.lock().unwrap();
+    if let Some(previous) = previous.as_ref().filter(|&previous| *previous != labels) {
+        let previous: Vec<&str> = previous.iter().map(String::as_str).collect();
+        let _ = AIR_QUALITY.remove_label_values(&previous);
+    }
+    let values: Vec<&str> = labels.iter().map(String::as_str).collect();
+    AIR_QUALITY.with_label_values(&values).set(aqi.into());
+    *previous = Some(labels);
+}
+
+/// Brings the trackers up to date on shutdown, so that the time since the
+/// last reading isn't lost. Call this after the read loop stops, whether or
+/// not it failed, and before `save_state`.
+///
+/// `pm2_5_dose` then includes the last reading up to now, and the day in
+/// progress of the trend and the minute in progress of the 24-hour AQI
+/// window are included as partial periods in `pm2_5_trend_slope` and
+/// `air_quality_index_24h`. The percentile window, time-weighted average and
+/// standard deviation include every reading already. The partial metrics
+/// period of `downsampled_metrics` is passed on when its callback is
+/// dropped, not here.
+pub fn flush_metrics() {
+    let now = METRICS_CONFIG.read().unwrap().clock.now();
+    if let Some(tracker) = PM2_5_DOSE_TRACKER.lock().unwrap().as_mut() {
+        PM2_5_DOSE.inc_by(tracker.flush(now));
+    }
+    if let Some(tracker) = PM2_5_TREND.lock().unwrap().as_mut() {
+        if let Some(slope) = tracker.flush(now) {
+            set_gauge_checked(&PM2_5_TREND_SLOPE, slope.value);
+        }
+    }
+    if let Some(window) = PM2_5_24H_WINDOW.lock().unwrap().as_mut() {
+        if let Some(mean) = window.flush(now) {
+            update_aqi(
+                calculate_aqi(&US_EPA_PM2_5, mean.value),
+                &AIR_QUALITY_INDEX_24H,
+            );
+        }
+    }
+}
+
+/// Sets every gauge to NaN, which Prometheus treats as "no data".
+///
+/// Call this at startup, so that the series exist before the first frame, and
+/// on shutdown or when the sensor stops producing data, so that dashboards
+/// don't keep showing the last reading. Also clears `last_reading`.
+pub fn reset_metrics() {
+    *LAST_READING.lock().unwrap() = None;
+    if let Some(previous) = AIR_QUALITY_LABELS.lock().unwrap().take() {
+        let previous: Vec<&str> = previous.iter().map(String::as_str).collect();
+        let _ = AIR_QUALITY.remove_label_values(&previous);
+    }
+    for size in CONCENTRATION_SIZES {
+        PARTICLE_CONCENTRATION_STANDARD
+            .with_label_values(&[size])
+            .set(f64::NAN);
+        PARTICLE_CONCENTRATION_ENVIRONMENT
+            .with_label_values(&[size])
+            .set(f64::NAN);
+    }
+    for size in COUNT_SIZES {
+        PARTICLE_COUNT.with_label_values(&[size]).set(f64::NAN);
+    }
+    let config = METRICS_CONFIG.read().unwrap();
+    for basis in ConcentrationBasis::ALL {
+        for standard in &config.aqi_standards {
+            for size in &["2.5", "10.0"] {
+                let labels = [*size, standard.label(), basis.label()];
+                AIR_QUALITY_INDEX.with_label_values(&labels).set(f64::NAN);
+                let _ = AIR_QUALITY_INDEX_SELECTED.remove_label_values(&labels);
+                if config.aqi_scale {
+                    PARTICLE_CONCENTRATION_AQI_SCALE
+                        .with_label_values(&labels)
+                        .set(f64::NAN);
+                }
+            }
+        }
+        if config.pm1_0_breakpoints.is_some() {
+            AIR_QUALITY_INDEX
+                .with_label_values(&["1.0", CUSTOM_STANDARD, basis.label()])
+                .set(f64::NAN);
+        }
+    }
+    if let Some(percentiles) = &config.pm2_5_percentiles {
+        for &quantile in &percentiles.quantiles {
+            PM2_5_PERCENTILE
+                .with_label_values(&[&percentile_label(quantile)])
+                .set(f64::NAN);
+        }
+    }
+    if config.pm2_5_twa_window.is_some() {
+        PM2_5_TWA.set(f64::NAN);
+        if config.pm2_5_stddev {
+            PM2_5_STDDEV.set(f64::NAN);
+        }
+    }
+    if config.pm2_5_trend_days.is_some() {
+        PM2_5_TREND_SLOPE.set(f64::NAN);
+    }
+    if config.mass_consistency.is_some() {
+        MASS_CONSISTENCY_RATIO.set(f64::NAN);
+    }
+    if config.export_reserved_word {
+        RESERVED_WORD.set(f64::NAN);
+    }
+    if config.aqi_24h {
+        AIR_QUALITY_INDEX_24H.set(f64::NAN);
+    }
+    if config.stp_counts {
+        for size in COUNT_SIZES {
+            PARTICLE_COUNT_STP.with_label_values(&[size]).set(f64::NAN);
+        }
+    }
+    if config.count_unit != CountUnit::PerDeciliter {
+        for size in COUNT_SIZES {
+            PARTICLE_COUNT_CONVERTED
+                .with_label_values(&[size, config.count_unit.label()])
+                .set(f64::NAN);
+        }
+    }
+    if config.count_rates {
+        for size in COUNT_SIZES {
+            PARTICLE_COUNT_RATE.with_label_values(&[size]).set(f64::NAN);
+        }
+    }
+    SENSOR_TEMPERATURE.set(f64::NAN);
+    SENSOR_HUMIDITY.set(f64::NAN);
+    IAQ_SCORE.set(f64::NAN);
+    PURIFIER_EFFECTIVENESS.set(f64::NAN);
+    CIGARETTE_EQUIVALENT.set(f64::NAN);
+    VISIBILITY_KM.set(f64::NAN);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::GOLDEN_PACKET;
+    use std::sync::Mutex;
+
+    lazy_static! {
+    // To prevent concurrent access to the metrics.
+    static ref TEST_MUTEX: Mutex<()> = Mutex::new(());
+    }
+
+    fn testdata() -> PmsData {
+        PmsData {
+            frame_length: 28,
+            pm1_cf1: 3,
+            pm2_5_cf1: 4,
+            pm10_cf1: 7,
+            pm1_atmo: 3,
+            pm2_5_atmo: 4,
+            pm10_atmo: 7,
+            pm0_3_count: 720,
+            pm0_5_count: 184,
+            pm1_0_count: 25,
+            pm2_5_count: 8,
+            pm5_0_count: 4,
+            pm10_0_count: 2,
+            reserved: Some(38656),
+            extended: None,
+            checksum: 783,
+        }
+    }
+
+    #[test]
+    fn test_metrics() {
+        let _ = env_logger::builder().is_test(true).try_init();
+        let _guard = TEST_MUTEX.lock().unwrap();
+        let before = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        update_metrics(&testdata());
+        assert!(AIR_QUALITY_INDEX_TIMESTAMP.get() >= before.as_secs_f64());
+        assert_eq!(IAQ_SCORE.get(), 93.0);
+        assert_eq!(
+            PARTICLE_CONCENTRATION_STANDARD
+                .with_label_values(&["1.0"])
+                .get(),
+            3.0
+        );
+        assert_eq!(
+            PARTICLE_CONCENTRATION_STANDARD
+                .with_label_values(&["2.5"])
+                .get(),
+            4.0
+        );
+        assert_eq!(
+            PARTICLE_CONCENTRATION_STANDARD
+                .with_label_values(&["10.0"])
+                .get(),
+            7.0
+        );
+        assert_eq!(
+            AIR_QUALITY_INDEX
+                .with_label_values(&["2.5", "us_epa", "standard"])
+                .get(),
+            17.0
+        );
+        assert_eq!(
+            AIR_QUALITY_INDEX
+                .with_label_values(&["10.0", "us_epa", "standard"])
+                .get(),
+            6.0
+        );
+        assert_eq!(VISIBILITY_KM.get(), 156.48);
+    }
+
+    #[test]
+    fn test_metrics_golden_packet() {
+        let _guard = TEST_MUTEX.lock().unwrap();
+        let data = crate::parse(GOLDEN_PACKET).unwrap().1.unwrap();
+        update_metrics(&data);
+        for (size, value) in [("1.0", 3.0), ("2.5", 4.0), ("10.0", 7.0)] {
+            let standard = PARTICLE_CONCENTRATION_STANDARD.with_label_values(&[size]);
+            let environment = PARTICLE_CONCENTRATION_ENVIRONMENT.with_label_values(&[size]);
+            assert_eq!(standard.get(), value, "{}", size);
+            assert_eq!(environment.get(), value, "{}", size);
+        }
+        let counts = [720.0, 184.0, 25.0, 8.0, 4.0, 2.0];
+        for (size, value) in COUNT_SIZES.iter().zip(counts) {
+            assert_eq!(
+                PARTICLE_COUNT.with_label_values(&[size]).get(),
+                value,
+                "{}",
+                size
+            );
+        }
+        for basis in ConcentrationBasis::ALL {
+            for (size, value) in [("2.5", 17.0), ("10.0", 6.0)] {
+                let labels = [size, "us_epa", basis.label()];
+                let aqi = AIR_QUALITY_INDEX.with_label_values(&labels).get();
+                assert_eq!(aqi, value, "{:?}", labels);
+            }
+        }
+    }
+
+    #[test]
+    fn test_aqi_concentration_basis() {
+        let _guard = TEST_MUTEX.lock().unwrap();
+        let mut data = testdata();
+        data.pm2_5_atmo = 37;
+        update_metrics(&data);
+        assert_eq!(
+            AIR_QUALITY_INDEX
+                .with_label_values(&["2.5", "us_epa", "standard"])
+                .get(),
+            17.0
+        );
+        assert_eq!(
+            AIR_QUALITY_INDEX
+                .with_label_values(&["2.5", "us_epa", "environment"])
+                .get(),
+            105.0
+        );
+    }
+
+    #[test]
+    fn test_aqi_basis() {
+        let _guard = TEST_MUTEX.lock().unwrap();
+        configure(MetricsConfig {
+            aqi_basis: "threshold:10".parse().unwrap(),
+            ..Default::default()
+        });
+        let selected = |basis: &str| {
+            AIR_QUALITY_INDEX_SELECTED
+                .get_metric_with_label_values(&["2.5", "us_epa", basis])
+                .unwrap()
+                .get()
+        };
+        let mut data = testdata();
+        data.pm2_5_atmo = 37;
+        update_metrics(&data);
+        assert_eq!(selected("standard"), 17.0);
+        assert!(AIR_QUALITY_INDEX_SELECTED
+            .remove_label_values(&["2.5", "us_epa", "environment"])
+            .is_err());
+
+        data.pm2_5_cf1 = 40;
+        update_metrics(&data);
+        assert_eq!(selected("environment"), 105.0);
+        assert!(AIR_QUALITY_INDEX_SELECTED
+            .remove_label_values(&["2.5", "us_epa", "standard"])
+            .is_err());
+        configure(MetricsConfig::default());
+
+        assert_eq!(
+            "environment".parse(),
+            Ok(BasisPolicy::Fixed(ConcentrationBasis::Environment))
+        );
+        assert!("threshold:-1".parse::<BasisPolicy>().is_err());
+        assert!("blended".parse::<BasisPolicy>().is_err());
+    }
+
+    #[test]
+    fn test_pm1_0_aqi() {
+        let _guard = TEST_MUTEX.lock().unwrap();
+        configure(MetricsConfig {
+            pm1_0_breakpoints: Some(crate::aqi::US_EPA_PM2_5),
+            ..Default::default()
+        });
+        update_metrics(&testdata());
+        configure(MetricsConfig::default());
+        assert_eq!(
+            AIR_QUALITY_INDEX
+                .with_label_values(&["1.0", "custom", "standard"])
+                .get(),
+            13.0
+        );
+    }
+
+    #[test]
+    fn test_pm2_5_percentiles() {
+        let _guard = TEST_MUTEX.lock().unwrap();
+        configure(MetricsConfig {
+            pm2_5_percentiles: Some(PercentileConfig::default()),
+            ..Default::default()
+        });
+        let mut data = testdata();
+        for pm2_5 in 1..=20 {
+            data.pm2_5_cf1 = pm2_5;
+            update_metrics(&data);
+        }
+        configure(MetricsConfig::default());
+        assert_eq!(PM2_5_PERCENTILE.with_label_values(&["50"]).get(), 10.0);
+        assert_eq!(PM2_5_PERCENTILE.with_label_values(&["95"]).get(), 19.0);
+    }
+
+    #[test]
+    fn test_pm2_5_trend() {
+        let _guard = TEST_MUTEX.lock().unwrap();
+        let clock = crate::clock::MockClock::new(UNIX_EPOCH + Duration::from_secs(1_700_006_400));
+        configure(MetricsConfig {
+            pm2_5_trend_days: Some(7),
+            clock: Arc::new(clock.clone()),
+            ..Default::default()
+        });
+        let mut data = testdata();
+        for pm2_5 in [30, 20, 10] {
+            data.pm2_5_cf1 = pm2_5;
+            update_metrics(&data);
+            clock.advance(Duration::from_secs(24 * 60 * 60));
+        }
+        assert_eq!(pm2_5_trend(), Some(Trend::Improving));
+        configure(MetricsConfig::default());
+        assert_eq!(PM2_5_TREND_SLOPE.get(), -10.0);
+        assert_eq!(pm2_5_trend(), None);
+    }
+
+    #[test]
+    fn test_mass_consistency() {
+        let _guard = TEST_MUTEX.lock().unwrap();
+        configure(MetricsConfig {
+            mass_consistency: Some(ConsistencyConfig::default()),
+            ..Default::default()
+        });
+        let before = INCONSISTENT_FRAMES.get();
+        update_metrics(&testdata());
+        assert!((MASS_CONSISTENCY_RATIO.get() - 3.0).abs() < 0.01);
+        assert_eq!(INCONSISTENT_FRAMES.get(), before);
+        let mut data = testdata();
+        data.pm2_5_atmo = 400;
+        update_metrics(&data);
+        assert_eq!(INCONSISTENT_FRAMES.get(), before + 1);
+
+        // Mass but no particles.
+        update_metrics(&testdata());
+        let rejected = REJECTED_VALUES.get();
+        data.pm0_3_count = 0;
+        data.pm0_5_count = 0;
+        data.pm1_0_count = 0;
+        data.pm2_5_count = 0;
+        data.pm5_0_count = 0;
+        data.pm10_0_count = 0;
+        update_metrics(&data);
+        configure(MetricsConfig::default());
+        assert_eq!(INCONSISTENT_FRAMES.get(), before + 2);
+        assert!(MASS_CONSISTENCY_RATIO.get().is_nan());
+        assert_eq!(REJECTED_VALUES.get(), rejected);
+    }
+
+    #[test]
+    fn test_pm2_5_stddev() {
+        let _guard = TEST_MUTEX.lock().unwrap();
+        configure(MetricsConfig {
+            pm2_5_twa_window: Some(Duration::from_secs(3600)),
+            pm2_5_stddev: true,
+            ..Default::default()
+        });
+        let mut data = testdata();
+        for pm2_5 in [2, 4, 4, 4, 5, 5, 7, 9] {
+            data.pm2_5_cf1 = pm2_5;
+            update_metrics(&data);
+        }
+        configure(MetricsConfig::default());
+        assert!((PM2_5_STDDEV.get() - 2.0).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_set_gauge_checked() {
+        let gauge = CIGARETTE_EQUIVALENT.clone();
+        let _guard = TEST_MUTEX.lock().unwrap();
+        let rejected = REJECTED_VALUES.get();
+        set_gauge_checked(&gauge, 2.0);
+        set_gauge_checked(&gauge, f64::NAN);
+        set_gauge_checked(&gauge, f64::INFINITY);
+        assert_eq!(gauge.get(), 2.0);
+        assert_eq!(REJECTED_VALUES.get(), rejected + 2);
+    }
+
+    #[test]
+    fn test_multiple_aqi_standards() {
+        let _guard = TEST_MUTEX.lock().unwrap();
+        configure(MetricsConfig {
+            aqi_standards: vec![AqiStandard::UsEpa, AqiStandard::EuCaqi],
+            ..Default::default()
+        });
+        update_metrics(&testdata());
+        configure(MetricsConfig::default());
+        assert_eq!(
+            AIR_QUALITY_INDEX
+                .with_label_values(&["2.5", "us_epa", "standard"])
+                .get(),
+            17.0
+        );
+        assert_eq!(
+            AIR_QUALITY_INDEX
+                .with_label_values(&["2.5", "eu_caqi", "standard"])
+                .get(),
+            7.0
+        );
+    }
+
+    #[test]
+    fn test_location_sink() {
+        let mut data = testdata();
+        record_reading(&LocationSink::new("kitchen"), &data, &[AqiStandard::UsEpa]);
+        data.pm2_5_cf1 = 37;
+        record_reading(&LocationSink::new("garage"), &data, &[AqiStandard::UsEpa]);
+        let aqi = |location| {
+            SENSOR_AIR_QUALITY_INDEX
+                .with_label_values(&[location, "2.5", "us_epa", "standard"])
+                .get()
+        };
+        assert_eq!(aqi("kitchen"), 17.0);
+        assert_eq!(aqi("garage"), 105.0);
+        assert_eq!(
+            SENSOR_PARTICLE_COUNT
+                .with_label_values(&["kitchen", "0.3"])
+                .get(),
+            720.0
+        );
+    }
+
+    #[test]
+    fn test_aqi_scale() {
+        let _guard = TEST_MUTEX.lock().unwrap();
+        configure(MetricsConfig {
+            aqi_scale: true,
+            ..Default::default()
+        });
+        update_metrics(&testdata());
+        configure(MetricsConfig::default());
+        let value = PARTICLE_CONCENTRATION_AQI_SCALE
+            .with_label_values(&["2.5", "us_epa", "standard"])
+            .get();
+        assert!((value - 16.667).abs() < 1e-3);
+    }
+
+    #[test]
+    fn test_count_unit() {
+        let _guard = TEST_MUTEX.lock().unwrap();
+        configure(MetricsConfig {
+            count_unit: "m3".parse().unwrap(),
+            ..Default::default()
+        });
+        update_metrics(&testdata());
+        configure(MetricsConfig::default());
+        assert_eq!(
+            PARTICLE_COUNT_CONVERTED
+                .with_label_values(&["0.3", "m3"])
+                .get(),
+            7_200_000.0
+        );
+        assert!("m^3".parse::<CountUnit>().is_err());
+    }
+
+    #[test]
+    fn test_clock() {
+        let _guard = TEST_MUTEX.lock().unwrap();
+        let at = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        configure(MetricsConfig {
+            clock: Arc::new(crate::clock::MockClock::new(at)),
+            ..Default::default()
+        });
+        update_metrics(&testdata());
+        configure(MetricsConfig::default());
+        assert_eq!(last_reading_at(), Some((at, testdata())));
+        assert_eq!(AIR_QUALITY_INDEX_TIMESTAMP.get(), 1_700_000_000.0);
+    }
+
+    #[test]
+    fn test_flush_metrics() {
+        let _guard = TEST_MUTEX.lock().unwrap();
+        let clock = crate::clock::MockClock::new(UNIX_EPOCH + Duration::from_secs(1_700_000_000));
+        configure(MetricsConfig {
+            pm2_5_dose_max_gap: Some(Duration::from_secs(60)),
+            aqi_24h: true,
+            clock: Arc::new(clock.clone()),
+            ..Default::default()
+        });
+        PM2_5_DOSE.reset();
+        AIR_QUALITY_INDEX_24H.set(0.0);
+        flush_metrics();
+        update_metrics(&testdata());
+        clock.advance(Duration::from_secs(30));
+        flush_metrics();
+        assert_eq!(PM2_5_DOSE.get(), 2.0);
+        assert_eq!(AIR_QUALITY_INDEX_24H.get(), 17.0);
+        clock.advance(Duration::from_secs(15));
+        update_metrics(&testdata());
+        assert_eq!(PM2_5_DOSE.get(), 3.0);
+        configure(MetricsConfig::default());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_save_and_load_state() {
+        let _guard = TEST_MUTEX.lock().unwrap();
+        let path = std::env::temp_dir().join(format!("pms7003-state-{}", std::process::id()));
+        let clock = crate::clock::MockClock::new(UNIX_EPOCH + Duration::from_secs(1_700_000_000));
+        let config = || MetricsConfig {
+            pm2_5_percentiles: Some(PercentileConfig::default()),
+            pm2_5_dose_max_gap: Some(Duration::from_secs(60)),
+            clock: Arc::new(clock.clone()),
+            ..Default::default()
+        };
+        configure(config());
+        PM2_5_DOSE.reset();
+        update_metrics(&testdata());
+        clock.advance(Duration::from_secs(30));
+        update_metrics(&testdata());
+        save_state(&path).unwrap();
+
+        // As after a restart.
+        configure(config());
+        PM2_5_DOSE.reset();
+        assert!(load_state(&path));
+        assert_eq!(PM2_5_DOSE.get(), 2.0);
+        assert_eq!(PM2_5_WINDOW.lock().unwrap().as_ref().unwrap().len(), 2);
+        clock.advance(Duration::from_secs(30));
+        update_metrics(&testdata());
+        assert_eq!(PM2_5_DOSE.get(), 4.0);
+
+        fs::write(&path, "{").unwrap();
+        configure(config());
+        assert!(!load_state(&path));
+        assert!(PM2_5_WINDOW.lock().unwrap().as_ref().unwrap().is_empty());
+        fs::remove_file(&path).unwrap();
+        assert!(!load_state(&path));
+        configure(MetricsConfig::default());
+    }
+
+    #[test]
+    fn test_combined() {
+        let _guard = TEST_MUTEX.lock().unwrap();
+        configure(MetricsConfig {
+            combined: true,
+            ..Default::default()
+        });
+        update_metrics(&testdata());
+        let good = ["3", "4", "7", "Good"];
+        assert_eq!(AIR_QUALITY.with_label_values(&good).get(), 17.0);
+
+        let mut data = testdata();
+        data.pm2_5_atmo = 37;
+        update_metrics(&data);
+        let unhealthy = ["3", "37", "7", "Unhealthy for Sensitive Groups"];
+        assert_eq!(AIR_QUALITY.with_label_values(&unhealthy).get(), 105.0);
+        // The previous series is gone.
+        assert!(AIR_QUALITY.remove_label_values(&good).is_err());
+
+        reset_metrics();
+        configure(MetricsConfig::default());
+        assert!(AIR_QUALITY.remove_label_values(&unhealthy).is_err());
+    }
+
+    #[test]
+    fn test_stp_counts() {
+        let _guard = TEST_MUTEX.lock().unwrap();
+        configure(MetricsConfig {
+            stp_counts: true,
+            ..Default::default()
+        });
+        set_ambient(Ambient {
+            temperature_celsius: 273.15,
+            pressure_hpa: crate::derived::STP_PRESSURE_HPA,
+        });
+        update_metrics(&testdata());
+        configure(MetricsConfig::default());
+        assert_eq!(PARTICLE_COUNT_STP.with_label_values(&["0.3"]).get(), 1440.0);
+        assert_eq!(PARTICLE_COUNT.with_label_values(&["0.3"]).get(), 720.0);
+    }
+
+    #[test]
+    fn test_purifier_effectiveness() {
+        let _guard = TEST_MUTEX.lock().unwrap();
+        update_metrics(&testdata());
+        assert!(PURIFIER_EFFECTIVENESS.get().is_nan());
+        set_outdoor_pm2_5(Some(16.0));
+        update_metrics(&testdata());
+        set_outdoor_pm2_5(None);
+        // 4 µg/m³ inside.
+        assert_eq!(PURIFIER_EFFECTIVENESS.get(), 75.0);
+    }
+
+    #[test]
+    fn test_reserved_word() {
+        let _guard = TEST_MUTEX.lock().unwrap();
+        configure(MetricsConfig {
+            export_reserved_word: true,
+            ..Default::default()
+        });
+        update_metrics(&testdata());
+        configure(MetricsConfig::default());
+        assert_eq!(RESERVED_WORD.get(), 38656.0);
+    }
+
+    #[test]
+    fn test_sensor_version() {
+        update_sensor_version(&testdata());
+        assert_eq!(SENSOR_VERSION.get(), 151.0);
+    }
+
+    #[test]
+    fn test_mark_up() {
+        {
+            let _up = mark_up();
+            assert_eq!(PMS_EXPORTER_UP.get(), 1.0);
+        }
+        assert_eq!(PMS_EXPORTER_UP.get(), 0.0);
+    }
+
+    #[test]
+    fn test_record_scrape() {
+        let before = PMS_SCRAPES.get();
+        record_scrape(UNIX_EPOCH + std::time::Duration::from_secs(1234));
+        assert_eq!(PMS_SCRAPES.get(), before + 1);
+        assert_eq!(PMS_LAST_SCRAPE_TIMESTAMP.get(), 1234.0);
+    }
+
+    #[test]
+    fn test_export_config() {
+        export_config(&ExporterConfig {
+            settle_time: Duration::from_secs(30),
+            aqi_standards: vec![AqiStandard::UsEpa, AqiStandard::EuCaqi],
+            ..Default::default()
+        });
+        export_config(&ExporterConfig {
+            settle_time: Duration::from_millis(1500),
+            aqi_standards: vec![AqiStandard::UsEpa, AqiStandard::EuCaqi],
+            metrics_period: Some(Duration::from_secs(60)),
+            ..Default::default()
+        });
+        let families = prometheus_exporter::prometheus::gather();
+        let family = families
+            .iter()
+            .find(|family| family.get_name() == "pms_config")
+            .unwrap();
+        // The first call's series was replaced.
+        assert_eq!(family.get_metric().len(), 1);
+        let metric = &family.get_metric()[0];
+        assert_eq!(metric.get_gauge().get_value(), 1.0);
+        let labels: Vec<_> = metric
+            .get_label()
+            .iter()
+            .map(|label| (label.get_name(), label.get_value()))
+            .collect();
+        assert!(
+            labels.contains(&("settle_time_seconds", "1.5")),
+            "{:?}",
+            labels
+        );
+        assert!(
+            labels.contains(&("aqi_standards", "us_epa,eu_caqi")),
+            "{:?}",
+            labels
+        );
+        assert!(labels.contains(&("variant", "pms7003")), "{:?}", labels);
+        assert!(
+            labels.contains(&("metrics_period_seconds", "60")),
+            "{:?}",
+            labels
+        );
+        assert!(labels.contains(&("combined", "false")), "{:?}", labels);
+    }
+
+    #[test]
+    fn test_last_reading() {
+        let _guard = TEST_MUTEX.lock().unwrap();
+        let mut data = testdata();
+        data.pm2_5_cf1 = 12;
+        update_metrics(&data);
+        assert_eq!(last_reading(), Some(data));
+    }
+
+    #[test]
+    fn test_reset_metrics() {
+        let _guard = TEST_MUTEX.lock().unwrap();
+        update_metrics(&testdata());
+        reset_metrics();
+        assert_eq!(last_reading(), None);
+        assert!(PARTICLE_CONCENTRATION_STANDARD
+            .with_label_values(&["2.5"])
+            .get()
+            .is_nan());
+        assert!(PARTICLE_COUNT.with_label_values(&["0.3"]).get().is_nan());
+        assert!(AIR_QUALITY_INDEX
+            .with_label_values(&["10.0", "us_epa", "standard"])
+            .get()
+            .is_nan());
+        assert!(SENSOR_TEMPERATURE.get().is_nan());
+    }
+
+    #[test]
+    fn test_aqi_valid() {
+        let _ = env_logger::builder().is_test(true).try_init();
+        let _guard = TEST_MUTEX.lock().unwrap();
+        let mut data = testdata();
+        data.pm2_5_cf1 = 37;
+        update_metrics(&data);
+        assert_eq!(
+            AIR_QUALITY_INDEX
+                .with_label_values(&["2.5", "us_epa", "standard"])
+                .get(),
+            105.0
+        );
+    }
+
+    #[test]
+    fn test_aqi_out_of_range() {
+        let _ = env_logger::builder().is_test(true).try_init();
+        let _guard = TEST_MUTEX.lock().unwrap();
+        update_metrics(&testdata());
+        let before = AIR_QUALITY_INDEX
+            .with_label_values(&["10.0", "us_epa", "standard"])
+            .get();
+        let mut data = testdata();
+        data.pm10_cf1 = u16::MAX;
+        update_metrics(&data);
+        assert_eq!(
+            AIR_QUALITY_INDEX
+                .with_label_values(&["10.0", "us_epa", "standard"])
+                .get(),
+            before
+        );
+    }
+}
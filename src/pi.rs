@@ -0,0 +1,140 @@
+//! Reading a sensor wired to a Raspberry Pi's GPIO14 (TXD) and GPIO15 (RXD)
+//! through the hardware UART.
+//!
+//! `/dev/serial0` points at whichever UART is routed to those pins. Before
+//! it can be used, the serial console has to be disabled, since a getty on
+//! the port reads the sensor's bytes and writes login prompts to it: in
+//! `raspi-config`, under Interface Options > Serial Port, answer "no" to the
+//! login shell and "yes" to the hardware. On models with Bluetooth, the pins
+//! get the mini UART, whose baud rate follows the GPU clock; adding
+//! `dtoverlay=disable-bt` to `/boot/config.txt` gives them the PL011 UART
+//! instead.
+
+use crate::reader::{Config, Pms};
+use crate::PmsError;
+use std::fs;
+use std::path::Path;
+use std::process;
+
+/// The UART on GPIO14/15.
+pub const PI_UART: &str = "/dev/serial0";
+
+/// A process that has a port open.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PortHolder {
+    pub pid: u32,
+    /// The process name, e.g. `agetty`.
+    pub name: String,
+}
+
+/// The processes other than this one that have the device at `path` open,
+/// following symlinks such as `/dev/serial0`. Found through `/proc`, so
+/// always empty on other platforms than Linux.
+pub fn port_holders<P: AsRef<Path>>(path: P) -> Vec<PortHolder> {
+    let device = match fs::canonicalize(path) {
+        Ok(device) => device,
+        Err(_) => return Vec::new(),
+    };
+    let processes = match fs::read_dir("/proc") {
+        Ok(processes) => processes,
+        Err(_) => return Vec::new(),
+    };
+    let mut holders = Vec::new();
+    for process in processes.flatten() {
+        let pid = match process.file_name().to_str().and_then(|s| s.parse().ok()) {
+            Some(pid) if pid != process::id() => pid,
+            _ => continue,
+        };
+        // Other users' processes can't be inspected without privileges.
+        let fds = match fs::read_dir(process.path().join("fd")) {
+            Ok(fds) => fds,
+            Err(_) => continue,
+        };
+        let holds_device = fds
+            .flatten()
+            .any(|fd| fs::read_link(fd.path()).is_ok_and(|target| target == device));
+        if holds_device {
+            let name = fs::read_to_string(process.path().join("comm")).unwrap_or_default();
+            holders.push(PortHolder {
+                pid,
+                name: name.trim().to_string(),
+            });
+        }
+    }
+    holders.sort_by_key(|holder| holder.pid);
+    holders
+}
+
+/// The error for a port at `path` that is held by `holders`.
+pub(crate) fn busy_error(path: &str, holders: &[PortHolder]) -> PmsError {
+    PmsError::PortBusy {
+        path: path.to_string(),
+        holders: holders
+            .iter()
+            .map(|holder| format!("{} (pid {})", holder.name, holder.pid))
+            .collect(),
+    }
+}
+
+/// Opens `PI_UART` at the sensor's settings. The port is opened for
+/// exclusive access, and fails with `PmsError::PortBusy` if another
+/// process already has it open, typically a getty for the serial console
+/// or `hciuart` for Bluetooth, since it would consume the sensor's bytes.
+pub fn open_pi_uart(config: Config) -> Result<Pms, PmsError> {
+    open_exclusive(PI_UART, config)
+}
+
+fn open_exclusive(path: &str, config: Config) -> Result<Pms, PmsError> {
+    let holders = port_holders(path);
+    if !holders.is_empty() {
+        return Err(busy_error(path, &holders));
+    }
+    let mut pms = Pms::new(path, config);
+    pms.open()?;
+    Ok(pms)
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+    use serialport::{SerialPort, TTYPort};
+    use std::fs::File;
+    use std::process::{Command, Stdio};
+
+    #[test]
+    fn test_port_busy() {
+        let (_master, slave) = TTYPort::pair().unwrap();
+        let path = slave.name().unwrap();
+        // Held by this process only.
+        assert_eq!(port_holders(&path), []);
+
+        let mut getty = Command::new("sleep")
+            .arg("10")
+            .stdin(Stdio::from(File::open(&path).unwrap()))
+            .spawn()
+            .unwrap();
+        let holders = port_holders(&path);
+        let config = Config {
+            wake_on_start: false,
+            ..Default::default()
+        };
+        let error = open_exclusive(&path, config).err();
+        getty.kill().unwrap();
+        getty.wait().unwrap();
+
+        assert_eq!(
+            holders,
+            [PortHolder {
+                pid: getty.id(),
+                name: "sleep".to_string(),
+            }]
+        );
+        let error = error.unwrap().to_string();
+        assert!(
+            error.contains(&format!("sleep (pid {})", getty.id())),
+            "{}",
+            error
+        );
+        assert!(port_holders("/nonexistent").is_empty());
+    }
+}
@@ -0,0 +1,96 @@
+//! Where a reading was taken, for mobile monitoring, e.g. with the sensor on
+//! a bike. The position is looked up for every reading from a
+//! `PositionSource`, and readings taken without a fix simply have none.
+//!
+//! ```
+//! use pms7003::position::{Position, PositionSource};
+//!
+//! let (source, current) = PositionSource::shared();
+//! assert_eq!(source.position(), None);
+//! // E.g. from the thread reading the GPS receiver.
+//! *current.lock().unwrap() = Position::new(63.43, 10.39);
+//! assert_eq!(source.position().unwrap().latitude, 63.43);
+//! ```
+//!
+//! Coordinates are only written to the JSON lines, since as metric labels
+//! every position would be a new series.
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+/// A position in degrees, north and east positive.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Position {
+    pub latitude: f64,
+    pub longitude: f64,
+}
+
+impl Position {
+    /// `None` unless both coordinates are in range, so that a garbled fix
+    /// is treated like no fix.
+    pub fn new(latitude: f64, longitude: f64) -> Option<Self> {
+        let in_range = (-90.0..=90.0).contains(&latitude) && (-180.0..=180.0).contains(&longitude);
+        in_range.then_some(Position {
+            latitude,
+            longitude,
+        })
+    }
+}
+
+/// Looks up the current position, `None` while there is no fix.
+#[derive(Clone)]
+pub struct PositionSource(Arc<dyn Fn() -> Option<Position> + Send + Sync>);
+
+impl PositionSource {
+    pub fn new<F>(source: F) -> Self
+    where
+        F: Fn() -> Option<Position> + Send + Sync + 'static,
+    {
+        PositionSource(Arc::new(source))
+    }
+
+    /// A source that reports whatever was last stored in the returned
+    /// position, for positions that are pushed rather than looked up.
+    pub fn shared() -> (Self, Arc<Mutex<Option<Position>>>) {
+        let current = Arc::new(Mutex::new(None));
+        let source = {
+            let current = Arc::clone(&current);
+            PositionSource::new(move || *current.lock().unwrap())
+        };
+        (source, current)
+    }
+
+    pub fn position(&self) -> Option<Position> {
+        (self.0)()
+    }
+}
+
+impl fmt::Debug for PositionSource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("PositionSource(..)")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_position() {
+        assert_eq!(
+            Position::new(-33.9, 151.2),
+            Some(Position {
+                latitude: -33.9,
+                longitude: 151.2
+            })
+        );
+        assert_eq!(Position::new(91.0, 0.0), None);
+        assert_eq!(Position::new(0.0, f64::NAN), None);
+
+        let source = PositionSource::new(|| Position::new(1.0, 2.0));
+        assert_eq!(source.position().unwrap().longitude, 2.0);
+    }
+}
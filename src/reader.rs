@@ -0,0 +1,2124 @@
+use crate::clock::{real_clock, Clock};
+use crate::replay::RecordingPort;
+use crate::PmsError;
+use crate::{
+    align_to_marker, frame_size, parse_frame_variant, Command, Endianness, Frame, PmsData, Quality,
+    Variant, FRAME_SIZE, MAX_FRAME_SIZE, START_MARKER,
+};
+use log::{debug, error, info, warn};
+use serialport::{ClearBuffer, SerialPort};
+use std::collections::VecDeque;
+use std::error::Error;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const BAUD_RATE: u32 = 9600;
+const MAX_BUFFER_SIZE: usize = 4096;
+/// Room for two frames per read, so that a read starting mid-frame still
+/// gets a whole one.
+const READ_BUFFER_SIZE: usize = 2 * FRAME_SIZE;
+const MAX_FRAME_AGE: Duration = Duration::from_secs(5);
+/// The sensor sends about one frame per second, so a read rarely waits this
+/// long. Also bounds how long it takes to notice `Config::stop`.
+const READ_TIMEOUT: Duration = Duration::from_millis(1000);
+const FRAME_INTERVAL: Duration = Duration::from_secs(1);
+const SLOW_FRAME_FACTOR: f64 = 5.0;
+/// About half a minute of frames.
+const NO_SYNC_BYTES: usize = 1024;
+/// The rest of a frame takes milliseconds to arrive, so a few timeouts is
+/// plenty.
+const STALLED_FRAME_TIMEOUTS: u32 = 3;
+/// About a minute of frames, long enough that a burst of noise doesn't
+/// count as a failing link.
+const CHECKSUM_WINDOW: usize = 60;
+const MAX_CHECKSUM_ERROR_RATE: f64 = 0.2;
+/// Span of the arrival times `frames_per_minute` is computed from.
+const FRAME_RATE_WINDOW: Duration = Duration::from_secs(20);
+/// Lower bound on how often the watchdog checks a worker, so that a zero
+/// `Config::timeout` doesn't have it spin.
+const MIN_WATCHDOG_CHECK_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Controls when buffered serial input is handed to the parser.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ReadStrategy {
+    /// Parse after every read. Incomplete frames are carried over to the
+    /// next read.
+    #[default]
+    Immediate,
+    /// Keep accumulating short reads until a full frame is buffered or
+    /// `timeout` has passed since the first unparsed byte arrived. Useful for
+    /// bridges (e.g. SC16IS752) that deliver bytes in bursts with gaps.
+    Assemble { timeout: Duration },
+    /// After every read, keep reading until the port has no more bytes
+    /// buffered, then deliver only the most recent complete frame. After a
+    /// backlog, e.g. a slow callback, this skips straight to the freshest
+    /// reading instead of working through the stale ones.
+    Drain,
+}
+
+/// What to do with data frames whose checksum doesn't match.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ValidationMode {
+    /// Drop the frame.
+    #[default]
+    Strict,
+    /// Deliver the frame anyway. The callback can tell it apart with
+    /// `PmsData::has_valid_checksum`. Useful on flaky links where some data
+    /// is better than none.
+    Lenient,
+}
+
+/// A user-supplied check run on every data frame after the checksum, e.g.
+/// for site-specific sanity rules. Frames it returns false for are dropped
+/// and counted in `rejected_frames_total`.
+///
+/// ```
+/// use pms7003::Validator;
+///
+/// // PM10 includes PM2.5, so it can't be lower.
+/// let validator = Validator::new(|data| data.pm10_atmo() >= data.pm2_5_atmo());
+/// ```
+#[derive(Clone)]
+pub struct Validator(Arc<dyn Fn(&PmsData) -> bool + Send + Sync>);
+
+impl Validator {
+    pub fn new<F>(validator: F) -> Self
+    where
+        F: Fn(&PmsData) -> bool + Send + Sync + 'static,
+    {
+        Validator(Arc::new(validator))
+    }
+
+    pub fn accepts(&self, data: &PmsData) -> bool {
+        (self.0)(data)
+    }
+}
+
+impl fmt::Debug for Validator {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Validator(..)")
+    }
+}
+
+/// Called with the checksum error rate when it exceeds
+/// `ChecksumPolicy::max_error_rate`, e.g. to raise an alert.
+#[derive(Clone)]
+pub struct ErrorRateHook(Arc<dyn Fn(f64) + Send + Sync>);
+
+impl ErrorRateHook {
+    pub fn new<F>(hook: F) -> Self
+    where
+        F: Fn(f64) + Send + Sync + 'static,
+    {
+        ErrorRateHook(Arc::new(hook))
+    }
+
+    pub fn call(&self, rate: f64) {
+        (self.0)(rate)
+    }
+}
+
+impl fmt::Debug for ErrorRateHook {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("ErrorRateHook(..)")
+    }
+}
+
+/// What to do when the link or sensor is failing, as told by a sustained
+/// rate of checksum errors. The rate is taken over the last `window` data
+/// frames, and only acted on once the window is full, so a burst of noise
+/// that is short compared to it is ignored. After acting, the window starts
+/// over.
+#[derive(Debug, Clone)]
+pub struct ChecksumPolicy {
+    pub window: usize,
+    /// Fraction of the frames in the window, between 0 and 1, with a bad
+    /// checksum above which the policy acts. A warning is logged either way.
+    pub max_error_rate: f64,
+    pub hook: Option<ErrorRateHook>,
+    /// End the read loop with `PmsError::ChecksumErrors`.
+    /// `read_active_with_watchdog` reopens the port on it, and elsewhere it
+    /// lets a process supervisor restart the process.
+    pub reconnect: bool,
+}
+
+impl Default for ChecksumPolicy {
+    fn default() -> Self {
+        ChecksumPolicy {
+            window: CHECKSUM_WINDOW,
+            max_error_rate: MAX_CHECKSUM_ERROR_RATE,
+            hook: None,
+            reconnect: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub baud_rate: u32,
+    /// Read timeout passed to the serial port. The read loop is paced by
+    /// this, so it should be non-zero.
+    pub timeout: Duration,
+    pub strategy: ReadStrategy,
+    /// Bytes requested from the port per read. Must be non-zero, or reading
+    /// fails with `PmsError::InvalidConfig`. A larger buffer takes in more of
+    /// a backlog per read.
+    pub read_buffer_size: usize,
+    pub validation: ValidationMode,
+    /// Run on every data frame that passes `validation`.
+    pub validator: Option<Validator>,
+    pub checksum_policy: Option<ChecksumPolicy>,
+    /// After this many bytes without a valid frame, a warning suggesting a
+    /// baud rate, wiring or mode problem is logged and `no_sync_total` is
+    /// incremented, and again every as many bytes after that. 0 disables it.
+    pub no_sync_bytes: usize,
+    /// After this many consecutive read timeouts with part of a frame
+    /// buffered, e.g. because its last bytes were lost on a flaky line, a
+    /// warning is logged and `stalled_frame` is set until bytes arrive again.
+    /// 0 disables it.
+    pub stalled_frame_timeouts: u32,
+    /// Also discard the partial frame once it is stalled, so that reading
+    /// resyncs at the next frame instead of completing the stalled one with
+    /// its bytes.
+    pub discard_stalled_frame: bool,
+    pub endianness: Endianness,
+    /// Which extended data frames to read besides those of a PMS7003.
+    pub variant: Variant,
+    /// If set, sleep this long between reads and only deliver the most recent
+    /// complete frame, discarding older ones. Trades data density for fewer
+    /// wakeups on constrained hardware.
+    pub poll_interval: Option<Duration>,
+    /// Send `Command::Wake` and `Command::ActiveMode` after opening the port,
+    /// in case a previous process left the sensor asleep or in passive mode.
+    pub wake_on_start: bool,
+    /// Drive the DTR and RTS lines after opening the port. Some USB bridges
+    /// are wired so that the sensor is held in reset (or asleep, via SET)
+    /// until one of them is set. `None` leaves a line as the driver set it.
+    pub dtr: Option<bool>,
+    pub rts: Option<bool>,
+    /// Upper bound on buffered input. If a link streams bytes that never
+    /// form a frame, the oldest bytes beyond this are discarded.
+    pub max_buffer_size: usize,
+    /// Readings that were buffered longer than this before being delivered,
+    /// e.g. by `ReadStrategy::Assemble`, are reported as `Quality::Stale`.
+    pub max_frame_age: Duration,
+    /// How often the sensor sends frames when healthy, about 1 s in active
+    /// mode. With `poll_interval` set, the longer of the two is expected.
+    pub expected_frame_interval: Duration,
+    /// Once no frame has arrived for this many expected intervals, a warning
+    /// is logged and `sensor_slow` is set, e.g. for a fan that is starting
+    /// to fail. Cleared again by a frame that arrives in time.
+    pub slow_frame_factor: f64,
+    /// All-zero frames, as sent while the fan spins up, are not delivered for
+    /// this long after the port is opened. They are counted in
+    /// `all_zero_frames_total` either way.
+    pub zero_frame_warmup: Duration,
+    /// Discard input buffered before the port was opened, e.g. a partial
+    /// frame or output from a previous mode, and read until two consecutive
+    /// frames with a valid checksum, the second starting right where the
+    /// first ended, before delivering any. Delivery starts with the second,
+    /// since a lone frame that passes its checksum may still be a chance
+    /// match in leftover bytes. Frames before it are dropped even with
+    /// `ValidationMode::Lenient`.
+    pub drain_on_open: bool,
+    /// Record everything read from the port, with its timing, to this file,
+    /// e.g. for `replay::replay_file_timed`. The file is overwritten.
+    pub record: Option<PathBuf>,
+    /// The read loop returns `Ok(())` once this is set.
+    pub stop: Arc<AtomicBool>,
+    /// While this is set, the read loop keeps reading and parsing, but
+    /// discards every frame instead of delivering it. The port stays open
+    /// and the sensor keeps streaming, so delivery resumes with the next
+    /// frame once it is cleared.
+    pub pause: Arc<AtomicBool>,
+    /// Measures the warm-up, assembly, staleness, frame rate and watchdog
+    /// intervals.
+    pub clock: Arc<dyn Clock>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            baud_rate: BAUD_RATE,
+            timeout: READ_TIMEOUT,
+            strategy: ReadStrategy::default(),
+            read_buffer_size: READ_BUFFER_SIZE,
+            validation: ValidationMode::default(),
+            validator: None,
+            checksum_policy: None,
+            no_sync_bytes: NO_SYNC_BYTES,
+            stalled_frame_timeouts: STALLED_FRAME_TIMEOUTS,
+            discard_stalled_frame: false,
+            endianness: Endianness::default(),
+            variant: Variant::default(),
+            poll_interval: None,
+            wake_on_start: true,
+            dtr: None,
+            rts: None,
+            max_buffer_size: MAX_BUFFER_SIZE,
+            max_frame_age: MAX_FRAME_AGE,
+            expected_frame_interval: FRAME_INTERVAL,
+            slow_frame_factor: SLOW_FRAME_FACTOR,
+            zero_frame_warmup: Duration::from_secs(0),
+            drain_on_open: false,
+            record: None,
+            stop: Arc::new(AtomicBool::new(false)),
+            pause: Arc::new(AtomicBool::new(false)),
+            clock: real_clock(),
+        }
+    }
+}
+
+impl Config {
+    /// Rejects settings that would keep the read loop from making progress.
+    fn check(&self) -> Result<(), PmsError> {
+        if self.read_buffer_size == 0 {
+            return Err(PmsError::InvalidConfig {
+                field: "read_buffer_size",
+                reason: "must be non-zero",
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Returns true if `input` holds at least one complete frame after the first
+/// start marker. The frame's size is taken from its frame length, capped at
+/// that of the largest supported frame.
+fn has_complete_frame(input: &[u8]) -> bool {
+    let aligned = align_to_marker(input);
+    if !aligned.starts_with(START_MARKER.as_bytes()) || aligned.len() < 4 {
+        return false;
+    }
+    let frame_length = u16::from_be_bytes([aligned[2], aligned[3]]);
+    aligned.len() >= MAX_FRAME_SIZE.min(frame_size(frame_length))
+}
+
+/// Whether only the most recent of the frames decoded together is delivered.
+fn latest_only(config: &Config) -> bool {
+    config.poll_interval.is_some() || config.strategy == ReadStrategy::Drain
+}
+
+/// Reads everything that `port` has buffered into `pending`.
+fn drain_port(port: &mut dyn Port, buf: &mut [u8], pending: &mut Vec<u8>) -> io::Result<()> {
+    while port.bytes_to_read()? > 0 {
+        let p = port.read(buf)?;
+        if p == 0 {
+            break;
+        }
+        pending.extend_from_slice(&buf[..p]);
+    }
+    Ok(())
+}
+
+/// Discards the oldest bytes of `pending` beyond `max_size`, returning how
+/// many were discarded.
+fn trim_pending(pending: &mut Vec<u8>, max_size: usize) -> Option<usize> {
+    let excess = pending.len().checked_sub(max_size).filter(|&n| n > 0)?;
+    pending.drain(..excess);
+    Some(excess)
+}
+
+/// Flags a stream whose frames arrive much further apart than expected.
+struct SlowDetector {
+    threshold: Duration,
+    last_frame: Instant,
+    slow: bool,
+}
+
+impl SlowDetector {
+    fn new(config: &Config, now: Instant) -> Self {
+        let interval = config
+            .expected_frame_interval
+            .max(config.poll_interval.unwrap_or_default());
+        SlowDetector {
+            threshold: interval.mul_f64(config.slow_frame_factor),
+            last_frame: now,
+            slow: false,
+        }
+    }
+
+    /// Records a frame received at `now`.
+    fn frame(&mut self, now: Instant) {
+        let in_time = now.duration_since(self.last_frame) <= self.threshold;
+        self.last_frame = now;
+        if in_time {
+            self.set_slow(false);
+        }
+    }
+
+    /// Flags the stream as slow if no frame has arrived for too long.
+    fn check(&mut self, now: Instant) {
+        let since_last = now.duration_since(self.last_frame);
+        if since_last > self.threshold && !self.slow {
+            warn!(
+                "No frame for {:?}, the sensor may be slowing down",
+                since_last
+            );
+            self.set_slow(true);
+        }
+    }
+
+    fn set_slow(&mut self, slow: bool) {
+        if slow != self.slow {
+            self.slow = slow;
+            #[cfg(feature = "prometheus")]
+            crate::metrics::SENSOR_SLOW.set(if slow { 1.0 } else { 0.0 });
+        }
+    }
+}
+
+/// The current frame rate, from the arrival times within `FRAME_RATE_WINDOW`.
+struct FrameRate {
+    arrivals: VecDeque<Instant>,
+}
+
+impl FrameRate {
+    fn new() -> Self {
+        FrameRate {
+            arrivals: VecDeque::new(),
+        }
+    }
+
+    /// Records a frame received at `now`.
+    fn frame(&mut self, now: Instant) {
+        self.arrivals.push_back(now);
+        self.update(now);
+    }
+
+    /// Frames per minute as of `now`, which decays while no frames arrive,
+    /// and sets `frames_per_minute`. `None` until two frames have arrived
+    /// within the window.
+    fn update(&mut self, now: Instant) -> Option<f64> {
+        while let Some(&oldest) = self.arrivals.front() {
+            if now.duration_since(oldest) <= FRAME_RATE_WINDOW {
+                break;
+            }
+            self.arrivals.pop_front();
+        }
+        let rate = match self.arrivals.front() {
+            Some(&oldest) if self.arrivals.len() >= 2 => {
+                let span = now.duration_since(oldest).as_secs_f64();
+                (self.arrivals.len() - 1) as f64 * 60.0 / span
+            }
+            _ => 0.0,
+        };
+        #[cfg(feature = "prometheus")]
+        crate::metrics::FRAMES_PER_MINUTE.set(rate);
+        (self.arrivals.len() >= 2).then_some(rate)
+    }
+}
+
+/// Parses as many frames as possible from `input`, returning the number of
+/// bytes consumed. Trailing bytes of an incomplete frame are left unconsumed.
+/// Checksum mismatches are counted in `checksum_errors_total`, and all-zero
+/// frames in `all_zero_frames_total`.
+/// Frames are only passed on once `sync` is in step with the stream.
+fn decode<F>(input: &[u8], config: &Config, sync: &mut FrameSync, callback: &mut F) -> usize
+where
+    F: FnMut(PmsData),
+{
+    let mut remaining = input;
+    loop {
+        let aligned = align_to_marker(remaining);
+        if aligned.len() < remaining.len() {
+            let skipped = remaining.len() - aligned.len();
+            debug!("skipped {} bytes waiting for start marker", skipped);
+            sync.decoded(skipped, false, config.no_sync_bytes);
+            remaining = aligned;
+        }
+        if remaining.is_empty() {
+            break;
+        }
+        let before = remaining.len();
+        let mut valid = false;
+        match parse_frame_variant(remaining, config.endianness, config.variant) {
+            Ok((remainder, None)) => {
+                debug!("wait for start marker");
+                remaining = remainder;
+            }
+            Ok((remainder, Some(Frame::Data(data)))) => {
+                debug!("got data: {:#?}", data);
+                let start = input.len() - remaining.len();
+                let end = input.len() - remainder.len();
+                let in_sync = sync.frame(start, end, data.has_valid_checksum());
+                if data.is_all_zero() {
+                    warn!("got an all-zero frame, the fan or laser may not be running");
+                    #[cfg(feature = "prometheus")]
+                    crate::metrics::ALL_ZERO_FRAMES.inc();
+                }
+                valid = data.has_valid_checksum();
+                if let Some(policy) = &config.checksum_policy {
+                    sync.checked(valid, policy);
+                }
+                if valid {
+                    if !in_sync {
+                        debug!("dropping frame while syncing");
+                    } else if accepted(config, &data) {
+                        callback(data);
+                    }
+                } else {
+                    #[cfg(feature = "prometheus")]
+                    crate::metrics::CHECKSUM_ERRORS.inc();
+                    match config.validation {
+                        _ if !in_sync => warn!("dropping frame with bad checksum while syncing"),
+                        ValidationMode::Strict => warn!("dropping frame with bad checksum"),
+                        ValidationMode::Lenient => {
+                            warn!("accepting frame with bad checksum");
+                            if accepted(config, &data) {
+                                callback(data);
+                            }
+                        }
+                    }
+                }
+                remaining = remainder;
+            }
+            Ok((remainder, Some(Frame::Ack(ack)))) => {
+                debug!("got ack: {:?}", ack);
+                valid = true;
+                remaining = remainder;
+            }
+            Ok((remainder, Some(Frame::Unknown))) => {
+                debug!("skipping frame with unknown length");
+                remaining = remainder;
+            }
+            Err(nom::Err::Incomplete(nom::Needed::Size(s))) => {
+                debug!("need {} more bytes!", s);
+                break;
+            }
+            Err(nom::Err::Incomplete(nom::Needed::Unknown)) => {
+                debug!("need more bytes!");
+                break;
+            }
+            Err(e) => {
+                let offset = input.len() - remaining.len();
+                error!("{}", PmsError::parse(input, offset, e));
+                break;
+            }
+        };
+        sync.decoded(before - remaining.len(), valid, config.no_sync_bytes);
+    }
+    let consumed = input.len() - remaining.len();
+    sync.consumed(consumed);
+    consumed
+}
+
+/// Whether `data` passes `Config::validator`, if there is one.
+fn accepted(config: &Config, data: &PmsData) -> bool {
+    match &config.validator {
+        Some(validator) if !validator.accepts(data) => {
+            warn!("dropping frame rejected by the validator");
+            #[cfg(feature = "prometheus")]
+            crate::metrics::REJECTED_FRAMES.inc();
+            false
+        }
+        _ => true,
+    }
+}
+
+/// Whether all-zero frames are withheld, see `Config::zero_frame_warmup`.
+/// Checked once per read, so the clock is only read if there is a warm-up.
+fn warming_up(opened_at: Option<Instant>, config: &Config) -> bool {
+    !config.zero_frame_warmup.is_zero()
+        && opened_at.is_some_and(|opened_at| {
+            config.clock.instant().saturating_duration_since(opened_at) < config.zero_frame_warmup
+        })
+}
+
+/// Whether the reader is in step with the frame stream: syncing after
+/// `Config::drain_on_open`, the bytes since the last valid frame for
+/// `Config::no_sync_bytes`, and the recent checksums for
+/// `Config::checksum_policy`.
+#[derive(Debug, Default)]
+struct FrameSync {
+    syncing: bool,
+    /// Where the last valid frame ended while syncing, relative to the input
+    /// being decoded.
+    previous_end: Option<usize>,
+    unsynced_bytes: usize,
+    /// Whether each data frame in the checksum window was valid.
+    checksums: VecDeque<bool>,
+    /// The error rate that should end the read loop, see
+    /// `ChecksumPolicy::reconnect`.
+    failing: Option<f64>,
+}
+
+impl FrameSync {
+    fn start(&mut self) {
+        self.syncing = true;
+        self.previous_end = None;
+    }
+
+    /// Whether the data frame from `start` to `end` may be delivered.
+    fn frame(&mut self, start: usize, end: usize, valid: bool) -> bool {
+        if !self.syncing {
+            return true;
+        }
+        if !valid {
+            self.previous_end = None;
+        } else if self.previous_end == Some(start) {
+            debug!("in sync with the frame stream");
+            self.syncing = false;
+        } else {
+            self.previous_end = Some(end);
+        }
+        !self.syncing
+    }
+
+    /// Moves on to the next input after `consumed` bytes are removed. A
+    /// frame that ended before them isn't adjacent to the next one.
+    fn consumed(&mut self, consumed: usize) {
+        self.previous_end = self.previous_end.filter(|&end| end == consumed).map(|_| 0);
+    }
+
+    /// Counts `n` decoded bytes, `valid` if they were a valid frame. Warns
+    /// and counts in `no_sync_total` every `threshold` bytes without one.
+    fn decoded(&mut self, n: usize, valid: bool, threshold: usize) {
+        if valid {
+            self.unsynced_bytes = 0;
+            return;
+        }
+        self.unsynced_bytes += n;
+        if threshold > 0 && self.unsynced_bytes >= threshold {
+            warn!(
+                "no valid frame in the last {} bytes: check that the baud rate is {}, \
+                 that the sensor's TX is wired to the adapter's RX, and that the \
+                 sensor is in active mode",
+                self.unsynced_bytes, BAUD_RATE
+            );
+            #[cfg(feature = "prometheus")]
+            crate::metrics::NO_SYNC.inc();
+            self.unsynced_bytes = 0;
+        }
+    }
+
+    /// Bytes were discarded without being decoded.
+    fn discarded(&mut self) {
+        self.previous_end = None;
+    }
+
+    /// Records a data frame with a `valid` checksum or not, and acts
+    /// according to `policy` once the window is full and the error rate is
+    /// too high.
+    fn checked(&mut self, valid: bool, policy: &ChecksumPolicy) {
+        self.checksums.push_back(valid);
+        while self.checksums.len() > policy.window {
+            self.checksums.pop_front();
+        }
+        if self.checksums.len() < policy.window {
+            return;
+        }
+        let errors = self.checksums.iter().filter(|&&valid| !valid).count();
+        let rate = errors as f64 / self.checksums.len() as f64;
+        if rate <= policy.max_error_rate {
+            return;
+        }
+        warn!(
+            "{} of the last {} frames had a bad checksum, the link or sensor may be failing",
+            errors,
+            self.checksums.len()
+        );
+        self.checksums.clear();
+        if let Some(hook) = &policy.hook {
+            hook.call(rate);
+        }
+        if policy.reconnect {
+            self.failing = Some(rate);
+        }
+    }
+
+    /// Returns the error to end the read loop with, if the checksum policy
+    /// says so.
+    fn take_failure(&mut self) -> Result<(), PmsError> {
+        match self.failing.take() {
+            Some(rate) => Err(PmsError::ChecksumErrors { rate }),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Counts read timeouts while part of a frame is buffered, see
+/// `Config::stalled_frame_timeouts`.
+#[derive(Debug, Default)]
+struct StallDetector {
+    timeouts: u32,
+    stalled: bool,
+}
+
+impl StallDetector {
+    /// Bytes were read.
+    fn progress(&mut self) {
+        self.timeouts = 0;
+        if self.stalled {
+            self.stalled = false;
+            #[cfg(feature = "prometheus")]
+            crate::metrics::STALLED_FRAME.set(0.0);
+        }
+    }
+
+    /// A read timed out with `pending` still buffered.
+    fn timed_out(&mut self, pending: &mut Vec<u8>, sync: &mut FrameSync, config: &Config) {
+        if pending.is_empty() || config.stalled_frame_timeouts == 0 {
+            self.timeouts = 0;
+            return;
+        }
+        self.timeouts += 1;
+        if self.timeouts < config.stalled_frame_timeouts {
+            return;
+        }
+        if !self.stalled {
+            warn!(
+                "a partial frame of {} bytes hasn't completed in {} reads, \
+                 bytes may be getting lost on the line",
+                pending.len(),
+                self.timeouts
+            );
+            self.stalled = true;
+            #[cfg(feature = "prometheus")]
+            crate::metrics::STALLED_FRAME.set(1.0);
+        }
+        if config.discard_stalled_frame {
+            info!("discarding {} bytes of a stalled frame", pending.len());
+            pending.clear();
+            sync.discarded();
+        }
+    }
+}
+
+/// The part of a serial port used by `Pms`. Implemented for the ports opened
+/// by `serialport`, and can be implemented by in-memory ports for testing.
+pub trait Port: Read + Write + Send {
+    /// Number of bytes that can be read without blocking.
+    fn bytes_to_read(&self) -> io::Result<u32>;
+
+    /// Whether commands can be sent. A port that only reads, e.g. a
+    /// capture file or a receive-only adapter, should return false so that
+    /// `Pms::send_command` fails instead of silently doing nothing.
+    fn is_writable(&self) -> bool {
+        true
+    }
+
+    /// Discards input that was received but not read yet.
+    fn clear_input(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Port for Box<dyn SerialPort> {
+    fn bytes_to_read(&self) -> io::Result<u32> {
+        SerialPort::bytes_to_read(self.as_ref()).map_err(io::Error::from)
+    }
+
+    fn clear_input(&mut self) -> io::Result<()> {
+        self.clear(ClearBuffer::Input).map_err(io::Error::from)
+    }
+}
+
+/// A handle to a sensor, for reading frames and sending commands one at a
+/// time. `read_active` is built on top of this.
+pub struct Pms {
+    path: String,
+    config: Config,
+    port: Option<Box<dyn Port>>,
+    pending: Vec<u8>,
+    ready: VecDeque<PmsData>,
+    opened_at: Option<Instant>,
+    /// Started by `open` with `Config::drain_on_open`.
+    sync: FrameSync,
+    stall: StallDetector,
+    /// When bytes were last read, for `read_active_with_watchdog`.
+    progress: Arc<Mutex<Instant>>,
+}
+
+impl Pms {
+    /// A handle to the sensor at `path`. Nothing is opened until `open`.
+    pub fn new(path: &str, config: Config) -> Self {
+        let now = config.clock.instant();
+        Pms {
+            path: path.to_string(),
+            config,
+            port: None,
+            pending: Vec::new(),
+            ready: VecDeque::new(),
+            opened_at: None,
+            sync: FrameSync::default(),
+            stall: StallDetector::default(),
+            progress: Arc::new(Mutex::new(now)),
+        }
+    }
+
+    /// A handle reading from an already opened `port`.
+    pub fn with_port(port: Box<dyn Port>, config: Config) -> Self {
+        Pms {
+            port: Some(port),
+            opened_at: Some(config.clock.instant()),
+            ..Pms::new("", config)
+        }
+    }
+
+    /// Opens the port unless it's already open, and wakes the sensor if
+    /// `Config::wake_on_start` is set.
+    pub fn open(&mut self) -> Result<(), PmsError> {
+        self.config.check()?;
+        if self.port.is_none() {
+            info!("Reading from {:?}", self.path);
+            // Always opened read-write, so that commands can be sent.
+            let mut port = serialport::new(&self.path, self.config.baud_rate)
+                .timeout(self.config.timeout)
+                .open()
+                .map_err(|e| match e.kind() {
+                    serialport::ErrorKind::Io(io::ErrorKind::PermissionDenied) => {
+                        PmsError::PermissionDenied {
+                            path: self.path.clone(),
+                        }
+                    }
+                    serialport::ErrorKind::Io(io::ErrorKind::NotFound) => e.into(),
+                    // E.g. EBUSY, if another process opened it for
+                    // exclusive access.
+                    _ => match crate::pi::port_holders(&self.path).as_slice() {
+                        [] => e.into(),
+                        holders => crate::pi::busy_error(&self.path, holders),
+                    },
+                })?;
+            if let Some(level) = self.config.dtr {
+                port.write_data_terminal_ready(level)?;
+            }
+            if let Some(level) = self.config.rts {
+                port.write_request_to_send(level)?;
+            }
+            self.port = match &self.config.record {
+                Some(path) => Some(Box::new(RecordingPort::with_clock(
+                    port,
+                    File::create(path)?,
+                    Arc::clone(&self.config.clock),
+                ))),
+                None => Some(Box::new(port)),
+            };
+            self.opened_at = Some(self.config.clock.instant());
+        }
+        if self.config.drain_on_open {
+            self.port.as_mut().unwrap().clear_input()?;
+            self.pending.clear();
+            self.ready.clear();
+            self.sync.start();
+        }
+        if self.config.wake_on_start {
+            for command in [Command::Wake, Command::ActiveMode] {
+                // Not fatal: the sensor may already be streaming, e.g. with
+                // only its TX line connected.
+                if let Err(e) = self.send_command(command) {
+                    warn!("Could not send {:?}: {}", command, e);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.port.is_some()
+    }
+
+    /// Writes `command` to the sensor. Fails with `PmsError::ReadOnly` if the
+    /// port can't be written to.
+    pub fn send_command(&mut self, command: Command) -> Result<(), PmsError> {
+        let port = self.port.as_mut().ok_or(PmsError::NotOpen)?;
+        if !port.is_writable() {
+            return Err(PmsError::ReadOnly);
+        }
+        let result = port
+            .write_all(&command.to_bytes())
+            .and_then(|()| port.flush());
+        match result {
+            Err(e) if e.kind() == io::ErrorKind::PermissionDenied => Err(PmsError::ReadOnly),
+            result => Ok(result?),
+        }
+    }
+
+    /// Returns the next data frame, reading from the port until one is
+    /// complete. Returns `Ok(None)` if a read times out first.
+    pub fn read_frame(&mut self) -> Result<Option<PmsData>, PmsError> {
+        self.config.check()?;
+        loop {
+            if let Some(data) = self.ready.pop_front() {
+                return Ok(Some(data));
+            }
+            let port = self.port.as_mut().ok_or(PmsError::NotOpen)?;
+            let mut buf = vec![0u8; self.config.read_buffer_size];
+            match port.read(&mut buf[..]) {
+                Ok(0) => return Ok(None),
+                Ok(p) => {
+                    *self.progress.lock().unwrap() = self.config.clock.instant();
+                    self.stall.progress();
+                    self.pending.extend_from_slice(&buf[..p]);
+                    if self.config.strategy == ReadStrategy::Drain {
+                        drain_port(port.as_mut(), &mut buf, &mut self.pending)?;
+                    }
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::TimedOut => {
+                    self.stall
+                        .timed_out(&mut self.pending, &mut self.sync, &self.config);
+                    return Ok(None);
+                }
+                Err(e) => return Err(e.into()),
+            }
+            let warming_up = warming_up(self.opened_at, &self.config);
+            let ready = &mut self.ready;
+            let consumed = decode(&self.pending, &self.config, &mut self.sync, &mut |data| {
+                if !(warming_up && data.is_all_zero()) {
+                    ready.push_back(data)
+                }
+            });
+            self.pending.drain(..consumed);
+            self.sync.take_failure()?;
+            if self.config.strategy == ReadStrategy::Drain {
+                while self.ready.len() > 1 {
+                    self.ready.pop_front();
+                }
+            }
+            if let Some(discarded) = trim_pending(&mut self.pending, self.config.max_buffer_size) {
+                self.sync.discarded();
+                return Err(PmsError::Desync { discarded });
+            }
+        }
+    }
+
+    /// Closes the port and discards buffered input.
+    pub fn close(&mut self) {
+        self.port = None;
+        self.pending.clear();
+        self.ready.clear();
+    }
+
+    /// Reads until `Config::stop` is set, passing every data frame to
+    /// `callback` according to `Config::strategy` and
+    /// `Config::poll_interval`.
+    pub fn run<F>(&mut self, mut callback: F) -> Result<(), PmsError>
+    where
+        F: FnMut(PmsData),
+    {
+        self.run_with_quality(|data, _| callback(data))
+    }
+
+    /// Like `run`, but also passes the `Quality` of each reading.
+    pub fn run_with_quality<F>(&mut self, mut callback: F) -> Result<(), PmsError>
+    where
+        F: FnMut(PmsData, Quality),
+    {
+        self.config.check()?;
+        let opened_at = self.opened_at;
+        let progress = &self.progress;
+        let config = &self.config;
+        let clock = &config.clock;
+        let port = self.port.as_mut().ok_or(PmsError::NotOpen)?;
+        let pending = &mut self.pending;
+        let sync = &mut self.sync;
+        let stall = &mut self.stall;
+        for data in self.ready.drain(..) {
+            let quality = data.quality();
+            callback(data, quality);
+        }
+
+        let mut buf = vec![0u8; config.read_buffer_size];
+        let mut assembling_since = None;
+        let mut slow = SlowDetector::new(config, clock.instant());
+        let mut frame_rate = FrameRate::new();
+        loop {
+            if config.stop.load(Ordering::Relaxed) {
+                info!("Stopping read");
+                return Ok(());
+            }
+            if let Some(interval) = config.poll_interval {
+                clock.sleep(interval);
+            }
+            match port.read(&mut buf[..]) {
+                Ok(p) => {
+                    info!("read {} bytes", p);
+                    if p > 0 {
+                        *progress.lock().unwrap() = clock.instant();
+                        stall.progress();
+                    }
+                    if pending.is_empty() {
+                        assembling_since = Some(clock.instant());
+                    }
+                    pending.extend_from_slice(&buf[..p]);
+                    if latest_only(config) {
+                        // Catch up with everything buffered, e.g. while
+                        // sleeping.
+                        drain_port(port.as_mut(), &mut buf, pending)?;
+                    }
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::TimedOut => {
+                    info!("timed out waiting for data");
+                    stall.timed_out(pending, sync, config);
+                }
+                Err(e) => return Err(e.into()),
+            }
+            if let Some(discarded) = trim_pending(pending, config.max_buffer_size) {
+                sync.discarded();
+                warn!("{}", PmsError::Desync { discarded });
+            }
+
+            let ready = match config.strategy {
+                ReadStrategy::Immediate | ReadStrategy::Drain => true,
+                ReadStrategy::Assemble { timeout } => {
+                    has_complete_frame(pending)
+                        || assembling_since.is_some_and(|since| {
+                            clock.instant().saturating_duration_since(since) >= timeout
+                        })
+                }
+            };
+            if ready && !pending.is_empty() {
+                let stale = assembling_since.is_some_and(|since| {
+                    clock.instant().saturating_duration_since(since) > config.max_frame_age
+                });
+                let warming_up = warming_up(opened_at, config);
+                let mut delivered = false;
+                let mut deliver = |data: PmsData| {
+                    delivered = true;
+                    if config.pause.load(Ordering::Relaxed) {
+                        debug!("paused, discarding frame");
+                        return;
+                    }
+                    if warming_up && data.is_all_zero() {
+                        return;
+                    }
+                    let quality = match data.quality() {
+                        Quality::Good if stale => Quality::Stale,
+                        quality => quality,
+                    };
+                    callback(data, quality)
+                };
+                let consumed = if latest_only(config) {
+                    let mut latest = None;
+                    let consumed = decode(pending, config, sync, &mut |data| latest = Some(data));
+                    if let Some(data) = latest {
+                        deliver(data);
+                    }
+                    consumed
+                } else {
+                    decode(pending, config, sync, &mut deliver)
+                };
+                pending.drain(..consumed);
+                sync.take_failure()?;
+                assembling_since = if pending.is_empty() {
+                    None
+                } else {
+                    Some(clock.instant())
+                };
+                if delivered {
+                    slow.frame(clock.instant());
+                    frame_rate.frame(clock.instant());
+                }
+            }
+            slow.check(clock.instant());
+            frame_rate.update(clock.instant());
+        }
+    }
+}
+
+pub fn read_active<F>(port: &str, callback: F) -> Result<(), Box<dyn Error>>
+where
+    F: FnMut(PmsData),
+{
+    read_active_with_config(port, &Config::default(), callback)
+}
+
+/// Like `read_active_with_config`, but also passes the `Quality` of each
+/// reading to `callback`.
+pub fn read_active_with_quality<F>(
+    port: &str,
+    config: &Config,
+    callback: F,
+) -> Result<(), Box<dyn Error>>
+where
+    F: FnMut(PmsData, Quality),
+{
+    let mut pms = Pms::new(port, config.clone());
+    pms.open()?;
+    info!("Starting read");
+    pms.run_with_quality(callback)?;
+    Ok(())
+}
+
+pub fn read_active_with_config<F>(
+    port: &str,
+    config: &Config,
+    callback: F,
+) -> Result<(), Box<dyn Error>>
+where
+    F: FnMut(PmsData),
+{
+    let mut pms = Pms::new(port, config.clone());
+    pms.open()?;
+    info!("Starting read");
+    pms.run(callback)?;
+    Ok(())
+}
+
+/// Like `read_active_with_config`, but guards against a read that never
+/// returns, as with drivers that ignore the read timeout. The port is read on
+/// a worker thread, and if no bytes have been read for `watchdog`, the worker
+/// is abandoned and the port is reopened on a new one, with a fresh callback
+/// from `make_callback`. The port is also reopened when the worker ends with
+/// `PmsError::ChecksumErrors`, see `ChecksumPolicy::reconnect`. The timeout
+/// should be well above the frame interval, since a sensor that stops
+/// sending, e.g. because it was put to sleep, also triggers it.
+///
+/// The abandoned worker is asked to stop, but holds on to its port until its
+/// read returns. If the port can't be reopened meanwhile, the error is
+/// returned, so that a process supervisor can restart the whole process.
+/// A zero `watchdog` fails with `PmsError::InvalidConfig`.
+pub fn read_active_with_watchdog<F, C>(
+    port: &str,
+    config: &Config,
+    watchdog: Duration,
+    make_callback: F,
+) -> Result<(), PmsError>
+where
+    F: Fn() -> C + Send + Sync + 'static,
+    C: FnMut(PmsData),
+{
+    let port = port.to_string();
+    supervise(
+        config,
+        watchdog,
+        move |config| {
+            let mut pms = Pms::new(&port, config);
+            pms.open()?;
+            Ok(pms)
+        },
+        make_callback,
+    )
+}
+
+fn supervise<O, F, C>(
+    config: &Config,
+    watchdog: Duration,
+    open: O,
+    make_callback: F,
+) -> Result<(), PmsError>
+where
+    O: Fn(Config) -> Result<Pms, PmsError> + Send + Sync + 'static,
+    F: Fn() -> C + Send + Sync + 'static,
+    C: FnMut(PmsData),
+{
+    let open = Arc::new(open);
+    let make_callback = Arc::new(make_callback);
+    if watchdog.is_zero() {
+        return Err(PmsError::InvalidConfig {
+            field: "watchdog",
+            reason: "must be non-zero",
+        });
+    }
+    let check_interval = (watchdog / 4)
+        .min(config.timeout)
+        .max(MIN_WATCHDOG_CHECK_INTERVAL);
+    loop {
+        // Each worker gets its own stop flag, so that an abandoned one stays
+        // stopped.
+        let worker_config = Config {
+            stop: Arc::new(AtomicBool::new(false)),
+            ..config.clone()
+        };
+        let worker_stop = Arc::clone(&worker_config.stop);
+        let progress = Arc::new(Mutex::new(config.clock.instant()));
+        let thread = {
+            let open = Arc::clone(&open);
+            let make_callback = Arc::clone(&make_callback);
+            let progress = Arc::clone(&progress);
+            thread::spawn(move || {
+                let mut pms = open(worker_config)?;
+                pms.progress = progress;
+                pms.run(make_callback())
+            })
+        };
+        loop {
+            thread::sleep(check_interval);
+            let stopping = config.stop.load(Ordering::Relaxed);
+            if thread.is_finished() {
+                match thread.join() {
+                    Ok(Err(e @ PmsError::ChecksumErrors { .. })) if !stopping => {
+                        error!("{}, reopening port", e);
+                        break;
+                    }
+                    Ok(result) => return result,
+                    Err(panic) => std::panic::resume_unwind(panic),
+                }
+            }
+            if stopping {
+                worker_stop.store(true, Ordering::Relaxed);
+            }
+            let idle = config
+                .clock
+                .instant()
+                .saturating_duration_since(*progress.lock().unwrap());
+            if idle >= watchdog {
+                worker_stop.store(true, Ordering::Relaxed);
+                if stopping {
+                    warn!("Read is hung, stopping without it");
+                    return Ok(());
+                }
+                error!("No data read for {:?}, reopening port", watchdog);
+                #[cfg(feature = "prometheus")]
+                crate::metrics::WATCHDOG_RESTARTS.inc();
+                break;
+            }
+        }
+    }
+}
+
+/// One sensor read by `read_multi`.
+#[derive(Debug, Clone)]
+pub struct SensorConfig {
+    pub port: String,
+    /// Identifies the sensor, e.g. in the `location` label of
+    /// `metrics::LocationSink`.
+    pub location: String,
+    pub config: Config,
+}
+
+/// A sensor being read on its own thread by `read_multi`.
+pub struct SensorHandle {
+    location: String,
+    stop: Arc<AtomicBool>,
+    pause: Arc<AtomicBool>,
+    thread: thread::JoinHandle<Result<(), PmsError>>,
+}
+
+impl SensorHandle {
+    pub fn location(&self) -> &str {
+        &self.location
+    }
+
+    /// Asks the read loop to return. It notices within `Config::timeout`.
+    pub fn stop(&self) {
+        self.stop.store(true, Ordering::Relaxed);
+    }
+
+    /// Discards frames until `resume`, see `Config::pause`.
+    pub fn pause(&self) {
+        self.pause.store(true, Ordering::Relaxed);
+    }
+
+    pub fn resume(&self) {
+        self.pause.store(false, Ordering::Relaxed);
+    }
+
+    /// Whether the read loop has returned, e.g. because the port failed.
+    pub fn is_finished(&self) -> bool {
+        self.thread.is_finished()
+    }
+
+    /// Waits for the read loop to return. A panic in the callback is
+    /// propagated.
+    pub fn join(self) -> Result<(), PmsError> {
+        match self.thread.join() {
+            Ok(result) => result,
+            Err(panic) => std::panic::resume_unwind(panic),
+        }
+    }
+}
+
+/// Reads each of `sensors` on its own thread. `make_callback` is called on
+/// the sensor's thread to create its callback, so the callback doesn't need
+/// to be `Send`.
+///
+/// Use `join_all` to wait for all sensors, or stop and join them one at a
+/// time through the returned handles.
+pub fn read_multi<F, C>(sensors: Vec<SensorConfig>, make_callback: F) -> Vec<SensorHandle>
+where
+    F: Fn(&SensorConfig) -> C + Send + Sync + 'static,
+    C: FnMut(PmsData),
+{
+    let make_callback = Arc::new(make_callback);
+    sensors
+        .into_iter()
+        .map(|sensor| {
+            let make_callback = Arc::clone(&make_callback);
+            let location = sensor.location.clone();
+            let stop = Arc::clone(&sensor.config.stop);
+            let pause = Arc::clone(&sensor.config.pause);
+            let thread = thread::spawn(move || {
+                let callback = make_callback(&sensor);
+                let mut pms = Pms::new(&sensor.port, sensor.config);
+                pms.open()?;
+                info!("Starting read of {}", sensor.location);
+                pms.run(callback)
+            });
+            SensorHandle {
+                location,
+                stop,
+                pause,
+                thread,
+            }
+        })
+        .collect()
+}
+
+/// Waits for every sensor to stop, returning the errors of the ones that
+/// failed, by location.
+pub fn join_all(handles: Vec<SensorHandle>) -> Result<(), Vec<(String, PmsError)>> {
+    let errors: Vec<(String, PmsError)> = handles
+        .into_iter()
+        .filter_map(|handle| {
+            let location = handle.location.clone();
+            match handle.join() {
+                Ok(()) => None,
+                Err(e) => {
+                    error!("Reading {} failed: {}", location, e);
+                    Some((location, e))
+                }
+            }
+        })
+        .collect();
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors)
+    }
+}
+
+/// Sets `stop` on SIGTERM or SIGINT. A second signal terminates the process
+/// immediately.
+#[cfg(feature = "signals")]
+pub fn stop_on_signal(stop: &Arc<AtomicBool>) -> std::io::Result<()> {
+    use signal_hook::consts::{SIGINT, SIGTERM};
+    use signal_hook::flag;
+    for signal in [SIGTERM, SIGINT] {
+        flag::register_conditional_shutdown(signal, 1, Arc::clone(stop))?;
+        flag::register(signal, Arc::clone(stop))?;
+    }
+    Ok(())
+}
+
+/// Like `read_active_with_config`, but returns cleanly on SIGTERM or SIGINT
+/// after calling `flush`, e.g. to persist buffered readings before a
+/// container is torn down.
+#[cfg(feature = "signals")]
+pub fn read_active_until_signal<F, H>(
+    port: &str,
+    config: &Config,
+    callback: F,
+    flush: H,
+) -> Result<(), Box<dyn Error>>
+where
+    F: FnMut(PmsData),
+    H: FnOnce(),
+{
+    stop_on_signal(&config.stop)?;
+    let result = read_active_with_config(port, config, callback);
+    flush();
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+    use crate::encode;
+    use crate::GOLDEN_PACKET;
+    use std::time::UNIX_EPOCH;
+
+    #[test]
+    fn test_decode_across_reads() {
+        let mut frames = 0;
+        let mut pending = b"xy".to_vec();
+        pending.extend_from_slice(&GOLDEN_PACKET[..10]);
+        let consumed = decode(
+            &pending,
+            &Config::default(),
+            &mut FrameSync::default(),
+            &mut |_| frames += 1,
+        );
+        assert_eq!(frames, 0);
+        assert_eq!(consumed, 2);
+        pending.drain(..consumed);
+
+        pending.extend_from_slice(&GOLDEN_PACKET[10..]);
+        let consumed = decode(
+            &pending,
+            &Config::default(),
+            &mut FrameSync::default(),
+            &mut |_| frames += 1,
+        );
+        assert_eq!(frames, 1);
+        assert_eq!(consumed, pending.len());
+    }
+
+    #[test]
+    fn test_decode_validator() {
+        let mut input = GOLDEN_PACKET.to_vec();
+        input.extend_from_slice(&encode(&PmsData::from([
+            3, 4, 2, 3, 4, 2, 0, 0, 0, 0, 0, 0, 0,
+        ])));
+        let config = Config {
+            validator: Some(Validator::new(|data| data.pm10_cf1 >= data.pm2_5_cf1)),
+            ..Default::default()
+        };
+        let mut frames = Vec::new();
+        decode(&input, &config, &mut FrameSync::default(), &mut |data| {
+            frames.push(data)
+        });
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames[0].pm10_cf1, 7);
+    }
+
+    #[test]
+    fn test_checksum_policy() {
+        let mut bad = GOLDEN_PACKET.to_vec();
+        *bad.last_mut().unwrap() ^= 1;
+        let rates = Arc::new(Mutex::new(Vec::new()));
+        let policy = ChecksumPolicy {
+            window: 4,
+            max_error_rate: 0.5,
+            hook: Some(ErrorRateHook::new({
+                let rates = Arc::clone(&rates);
+                move |rate| rates.lock().unwrap().push(rate)
+            })),
+            reconnect: true,
+        };
+        let config = Config {
+            checksum_policy: Some(policy),
+            ..Default::default()
+        };
+        let mut sync = FrameSync::default();
+        // A single bad frame is noise.
+        for frame in [
+            GOLDEN_PACKET,
+            &bad,
+            GOLDEN_PACKET,
+            GOLDEN_PACKET,
+            &bad,
+            &bad,
+        ] {
+            decode(frame, &config, &mut sync, &mut |_| {});
+        }
+        assert!(rates.lock().unwrap().is_empty());
+        assert!(sync.take_failure().is_ok());
+        decode(&bad, &config, &mut sync, &mut |_| {});
+        assert_eq!(*rates.lock().unwrap(), [0.75]);
+        assert!(matches!(
+            sync.take_failure(),
+            Err(PmsError::ChecksumErrors { rate }) if rate == 0.75
+        ));
+
+        // The watchdog reopens the port.
+        let stop = Arc::clone(&config.stop);
+        let opens = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let open = {
+            let opens = Arc::clone(&opens);
+            let bad = bad.repeat(4);
+            move |config| {
+                let input = match opens.fetch_add(1, Ordering::Relaxed) {
+                    0 => &bad[..],
+                    _ => GOLDEN_PACKET,
+                };
+                Ok(Pms::with_port(Box::new(MockPort::new(input, 64)), config))
+            }
+        };
+        supervise(&config, Duration::from_millis(100), open, move || {
+            let stop = Arc::clone(&stop);
+            move |_| stop.store(true, Ordering::Relaxed)
+        })
+        .unwrap();
+        assert_eq!(opens.load(Ordering::Relaxed), 2);
+        assert_eq!(*rates.lock().unwrap(), [0.75, 1.0]);
+    }
+
+    #[cfg(feature = "prometheus")]
+    #[test]
+    fn test_no_sync() {
+        let config = Config {
+            no_sync_bytes: 100,
+            ..Default::default()
+        };
+        let mut sync = FrameSync::default();
+        let before = crate::metrics::NO_SYNC.get();
+        // As read at the wrong baud rate, with a stray start marker.
+        let mut garbage = vec![0x55; 60];
+        garbage.extend_from_slice(&GOLDEN_PACKET[..10]);
+        garbage.extend_from_slice(&[0xaa; 22]);
+        decode(&garbage, &config, &mut sync, &mut |_| {});
+        assert_eq!(sync.unsynced_bytes, 92);
+        // A valid frame starts the count over.
+        decode(GOLDEN_PACKET, &config, &mut sync, &mut |_| {});
+        assert_eq!(sync.unsynced_bytes, 0);
+        decode(&garbage, &config, &mut sync, &mut |_| {});
+        assert_eq!(crate::metrics::NO_SYNC.get(), before);
+        decode(&garbage, &config, &mut sync, &mut |_| {});
+        assert_eq!(crate::metrics::NO_SYNC.get(), before + 1);
+        // Counted again from where the warning was logged.
+        assert_eq!(sync.unsynced_bytes, 32);
+    }
+
+    #[test]
+    fn test_stalled_frame() {
+        let config = Config {
+            wake_on_start: false,
+            ..Default::default()
+        };
+        let mut input = GOLDEN_PACKET.to_vec();
+        input.extend_from_slice(&GOLDEN_PACKET[..30]);
+        let mut pms = Pms::with_port(Box::new(MockPort::new(&input, 64)), config.clone());
+        assert!(pms.read_frame().unwrap().is_some());
+        for _ in 0..STALLED_FRAME_TIMEOUTS {
+            assert_eq!(pms.read_frame().unwrap(), None);
+        }
+        assert!(pms.stall.stalled);
+        assert_eq!(pms.pending.len(), 30);
+
+        let config = Config {
+            discard_stalled_frame: true,
+            ..config
+        };
+        let mut pms = Pms::with_port(Box::new(MockPort::new(&input, 64)), config);
+        assert!(pms.read_frame().unwrap().is_some());
+        for _ in 0..STALLED_FRAME_TIMEOUTS {
+            assert_eq!(pms.read_frame().unwrap(), None);
+        }
+        assert!(pms.pending.is_empty());
+        pms.stall.progress();
+        assert!(!pms.stall.stalled);
+    }
+
+    #[test]
+    fn test_drain_strategy() {
+        let mut input = Vec::new();
+        for pm2_5 in 1..=3 {
+            input.extend_from_slice(&encode(&PmsData::from([
+                1, pm2_5, 5, 1, pm2_5, 5, 0, 0, 0, 0, 0, 0, 0,
+            ])));
+        }
+        let config = Config {
+            wake_on_start: false,
+            read_buffer_size: 10,
+            ..Default::default()
+        };
+        let mut pms = Pms::with_port(Box::new(MockPort::new(&input, 64)), config.clone());
+        assert_eq!(pms.read_frame().unwrap().unwrap().pm2_5_cf1, 1);
+
+        let config = Config {
+            strategy: ReadStrategy::Drain,
+            ..config
+        };
+        let mut pms = Pms::with_port(Box::new(MockPort::new(&input, 64)), config);
+        assert_eq!(pms.read_frame().unwrap().unwrap().pm2_5_cf1, 3);
+        assert_eq!(pms.read_frame().unwrap(), None);
+
+        let config = Config {
+            wake_on_start: false,
+            read_buffer_size: 0,
+            ..Default::default()
+        };
+        let mut pms = Pms::with_port(Box::new(MockPort::new(&input, 64)), config);
+        assert!(matches!(
+            pms.read_frame(),
+            Err(PmsError::InvalidConfig {
+                field: "read_buffer_size",
+                ..
+            })
+        ));
+        assert!(matches!(
+            pms.run(|_| ()),
+            Err(PmsError::InvalidConfig { .. })
+        ));
+    }
+
+    #[test]
+    fn test_decode_validation_mode() {
+        let mut corrupted = GOLDEN_PACKET.to_vec();
+        corrupted[5] += 1;
+        let mut frames = Vec::new();
+        let consumed = decode(
+            &corrupted,
+            &Config::default(),
+            &mut FrameSync::default(),
+            &mut |data| frames.push(data),
+        );
+        assert_eq!(consumed, corrupted.len());
+        assert!(frames.is_empty());
+
+        let lenient = Config {
+            validation: ValidationMode::Lenient,
+            ..Default::default()
+        };
+        decode(
+            &corrupted,
+            &lenient,
+            &mut FrameSync::default(),
+            &mut |data| frames.push(data),
+        );
+        assert_eq!(frames.len(), 1);
+        assert!(!frames[0].has_valid_checksum());
+    }
+
+    /// Serves `input` in chunks of at most `chunk` bytes, then times out.
+    /// Writes fail with `PermissionDenied` unless `writable`.
+    struct MockPort {
+        input: VecDeque<u8>,
+        chunk: usize,
+        writable: bool,
+        written: Arc<std::sync::Mutex<Vec<u8>>>,
+    }
+
+    impl MockPort {
+        fn new(input: &[u8], chunk: usize) -> Self {
+            MockPort {
+                input: input.iter().copied().collect(),
+                chunk,
+                writable: true,
+                written: Arc::default(),
+            }
+        }
+    }
+
+    impl Read for MockPort {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.input.is_empty() {
+                return Err(io::ErrorKind::TimedOut.into());
+            }
+            let n = buf.len().min(self.chunk).min(self.input.len());
+            for (byte, input) in buf.iter_mut().zip(self.input.drain(..n)) {
+                *byte = input;
+            }
+            Ok(n)
+        }
+    }
+
+    impl Write for MockPort {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if !self.writable {
+                return Err(io::ErrorKind::PermissionDenied.into());
+            }
+            self.written.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Port for MockPort {
+        fn bytes_to_read(&self) -> io::Result<u32> {
+            Ok(self.input.len() as u32)
+        }
+    }
+
+    /// A `MockPort` whose reads take `step` on `clock`.
+    struct TickingPort {
+        port: MockPort,
+        clock: MockClock,
+        step: Duration,
+    }
+
+    impl Read for TickingPort {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.clock.advance(self.step);
+            self.port.read(buf)
+        }
+    }
+
+    impl Write for TickingPort {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.port.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.port.flush()
+        }
+    }
+
+    impl Port for TickingPort {
+        fn bytes_to_read(&self) -> io::Result<u32> {
+            self.port.bytes_to_read()
+        }
+    }
+
+    #[test]
+    fn test_stale_frame() {
+        for (step, expected) in [
+            (Duration::from_secs(2), Quality::Stale),
+            (Duration::from_secs(0), Quality::Good),
+        ] {
+            let clock = MockClock::new(UNIX_EPOCH);
+            let config = Config {
+                strategy: ReadStrategy::Assemble {
+                    timeout: Duration::from_secs(3600),
+                },
+                clock: Arc::new(clock.clone()),
+                ..Default::default()
+            };
+            let stop = Arc::clone(&config.stop);
+            let port = TickingPort {
+                port: MockPort::new(GOLDEN_PACKET, 5),
+                clock,
+                step,
+            };
+            let mut qualities = Vec::new();
+            Pms::with_port(Box::new(port), config)
+                .run_with_quality(|_, quality| {
+                    qualities.push(quality);
+                    stop.store(true, Ordering::Relaxed);
+                })
+                .unwrap();
+            // Seven reads of 5 bytes take 12 s after the first.
+            assert_eq!(qualities, [expected]);
+        }
+    }
+
+    #[test]
+    fn test_pms_lifecycle() {
+        let mut input = b"noise".to_vec();
+        input.extend_from_slice(GOLDEN_PACKET);
+        input.extend_from_slice(GOLDEN_PACKET);
+        let port = MockPort::new(&input, 7);
+        let written = Arc::clone(&port.written);
+        let mut pms = Pms::with_port(Box::new(port), Config::default());
+
+        pms.open().unwrap();
+        let mut wake = Command::Wake.to_bytes().to_vec();
+        wake.extend_from_slice(&Command::ActiveMode.to_bytes());
+        assert_eq!(*written.lock().unwrap(), wake);
+
+        let data = pms.read_frame().unwrap().unwrap();
+        assert_eq!(data.version(), Some(0x97));
+        assert!(pms.read_frame().unwrap().is_some());
+        assert_eq!(pms.read_frame().unwrap(), None);
+
+        pms.send_command(Command::Sleep).unwrap();
+        assert!(written
+            .lock()
+            .unwrap()
+            .ends_with(&Command::Sleep.to_bytes()));
+
+        pms.close();
+        assert!(!pms.is_open());
+        assert!(matches!(pms.read_frame(), Err(PmsError::NotOpen)));
+    }
+
+    #[test]
+    fn test_drain_on_open() {
+        // The mock can't clear input, as if it arrived after the clear.
+        let mut input = GOLDEN_PACKET.to_vec();
+        input[5] += 1;
+        input.extend_from_slice(GOLDEN_PACKET);
+        let config = Config {
+            validation: ValidationMode::Lenient,
+            wake_on_start: false,
+            ..Default::default()
+        };
+        let mut pms = Pms::with_port(Box::new(MockPort::new(&input, 64)), config.clone());
+        pms.open().unwrap();
+        assert!(!pms.read_frame().unwrap().unwrap().has_valid_checksum());
+
+        let config = Config {
+            drain_on_open: true,
+            ..config
+        };
+        let mut pms = Pms::with_port(Box::new(MockPort::new(&input, 64)), config.clone());
+        pms.open().unwrap();
+        assert_eq!(pms.read_frame().unwrap(), None);
+
+        // A lone frame, then two adjacent ones, of which only the second is
+        // delivered.
+        let mut corrupted = GOLDEN_PACKET.to_vec();
+        corrupted[5] += 1;
+        let mut input = GOLDEN_PACKET.to_vec();
+        input.extend_from_slice(b"xyz");
+        input.extend_from_slice(&corrupted);
+        for _ in 0..3 {
+            input.extend_from_slice(GOLDEN_PACKET);
+        }
+        let mut pms = Pms::with_port(Box::new(MockPort::new(&input, 20)), config);
+        pms.open().unwrap();
+        let mut frames = 0;
+        while let Some(data) = pms.read_frame().unwrap() {
+            assert!(data.has_valid_checksum());
+            frames += 1;
+        }
+        assert_eq!(frames, 2);
+    }
+
+    #[test]
+    fn test_frame_sync() {
+        let mut sync = FrameSync::default();
+        assert!(sync.frame(0, 32, false));
+        sync.start();
+        assert!(!sync.frame(0, 32, true));
+        // Not adjacent.
+        assert!(!sync.frame(33, 65, true));
+        sync.consumed(65);
+        assert!(sync.frame(0, 32, true));
+        assert!(sync.frame(40, 72, true));
+
+        sync.start();
+        assert!(!sync.frame(0, 32, true));
+        assert!(!sync.frame(32, 64, false));
+        assert!(!sync.frame(64, 96, true));
+        // An incomplete frame or garbage was consumed since.
+        sync.consumed(100);
+        assert!(!sync.frame(0, 32, true));
+    }
+
+    #[test]
+    fn test_send_command_read_only() {
+        let port = MockPort {
+            writable: false,
+            ..MockPort::new(GOLDEN_PACKET, 64)
+        };
+        let mut pms = Pms::with_port(Box::new(port), Config::default());
+        // Waking is best effort, so opening still succeeds.
+        pms.open().unwrap();
+        assert!(pms.read_frame().unwrap().is_some());
+        assert!(matches!(
+            pms.send_command(Command::Sleep),
+            Err(PmsError::ReadOnly)
+        ));
+    }
+
+    #[test]
+    fn test_run_with_quality() {
+        let mut input = GOLDEN_PACKET.to_vec();
+        input.extend_from_slice(GOLDEN_PACKET);
+        input[32 + 5] += 1;
+        let config = Config {
+            validation: ValidationMode::Lenient,
+            ..Default::default()
+        };
+        let stop = Arc::clone(&config.stop);
+        let mut pms = Pms::with_port(Box::new(MockPort::new(&input, 64)), config);
+        let mut qualities = Vec::new();
+        pms.run_with_quality(|_, quality| {
+            qualities.push(quality);
+            if qualities.len() == 2 {
+                stop.store(true, Ordering::Relaxed);
+            }
+        })
+        .unwrap();
+        assert_eq!(qualities, [Quality::Good, Quality::CheckFailed]);
+    }
+
+    /// Clears `pause` before its second read.
+    struct ResumingPort {
+        inner: MockPort,
+        pause: Arc<AtomicBool>,
+        reads: usize,
+    }
+
+    impl Read for ResumingPort {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.reads += 1;
+            if self.reads == 2 {
+                self.pause.store(false, Ordering::Relaxed);
+            }
+            self.inner.read(buf)
+        }
+    }
+
+    impl Write for ResumingPort {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.inner.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Port for ResumingPort {
+        fn bytes_to_read(&self) -> io::Result<u32> {
+            self.inner.bytes_to_read()
+        }
+    }
+
+    #[test]
+    fn test_pause() {
+        let mut input = GOLDEN_PACKET.to_vec();
+        input.extend_from_slice(GOLDEN_PACKET);
+        input[32 + 5] += 1;
+        let config = Config {
+            validation: ValidationMode::Lenient,
+            ..Default::default()
+        };
+        let stop = Arc::clone(&config.stop);
+        config.pause.store(true, Ordering::Relaxed);
+        let port = ResumingPort {
+            // One frame per read.
+            inner: MockPort::new(&input, 32),
+            pause: Arc::clone(&config.pause),
+            reads: 0,
+        };
+        let mut pms = Pms::with_port(Box::new(port), config);
+        let mut qualities = Vec::new();
+        pms.run_with_quality(|_, quality| {
+            qualities.push(quality);
+            stop.store(true, Ordering::Relaxed);
+        })
+        .unwrap();
+        // The first frame was discarded while paused.
+        assert_eq!(qualities, [Quality::CheckFailed]);
+    }
+
+    #[test]
+    fn test_read_frame_desync() {
+        let mut pending = b"garbage".to_vec();
+        assert_eq!(trim_pending(&mut pending, 7), None);
+        assert_eq!(trim_pending(&mut pending, 3), Some(4));
+        assert_eq!(pending, b"age");
+
+        // A frame can never be assembled in a buffer smaller than a frame.
+        let config = Config {
+            max_buffer_size: 16,
+            ..Default::default()
+        };
+        let port = MockPort::new(GOLDEN_PACKET, 20);
+        let mut pms = Pms::with_port(Box::new(port), config);
+        assert!(matches!(
+            pms.read_frame(),
+            Err(PmsError::Desync { discarded: 4 })
+        ));
+        assert_eq!(pms.read_frame().unwrap(), None);
+    }
+
+    /// A port whose reads never return.
+    struct HangingPort;
+
+    impl Read for HangingPort {
+        fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+            loop {
+                thread::park();
+            }
+        }
+    }
+
+    impl Write for HangingPort {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Port for HangingPort {
+        fn bytes_to_read(&self) -> io::Result<u32> {
+            Ok(0)
+        }
+    }
+
+    #[test]
+    fn test_watchdog() {
+        let watchdog = Duration::from_secs(3600);
+        let clock = MockClock::new(UNIX_EPOCH);
+        let config = Config {
+            timeout: Duration::from_millis(10),
+            clock: Arc::new(clock.clone()),
+            ..Default::default()
+        };
+        let stop = Arc::clone(&config.stop);
+        let opens = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let open = {
+            let opens = Arc::clone(&opens);
+            move |config| {
+                let port: Box<dyn Port> = match opens.fetch_add(1, Ordering::Relaxed) {
+                    0 => {
+                        // Hangs through the whole watchdog interval.
+                        clock.advance(watchdog);
+                        Box::new(HangingPort)
+                    }
+                    _ => Box::new(MockPort::new(GOLDEN_PACKET, 64)),
+                };
+                Ok(Pms::with_port(port, config))
+            }
+        };
+        supervise(&config, watchdog, open, move || {
+            let (sender, stop) = (sender.clone(), Arc::clone(&stop));
+            move |data: PmsData| {
+                sender.send(data.version()).unwrap();
+                stop.store(true, Ordering::Relaxed);
+            }
+        })
+        .unwrap();
+        assert_eq!(opens.load(Ordering::Relaxed), 2);
+        assert_eq!(receiver.try_recv(), Ok(Some(0x97)));
+
+        let error = supervise(
+            &config,
+            Duration::from_secs(0),
+            |_| -> Result<Pms, PmsError> { unreachable!() },
+            || |_: PmsData| (),
+        );
+        assert!(matches!(
+            error,
+            Err(PmsError::InvalidConfig {
+                field: "watchdog",
+                ..
+            })
+        ));
+    }
+
+    /// End-to-end through a real serial port: `read_active_with_config` reads
+    /// the slave side of a pseudo-terminal pair, fed from the master side.
+    #[cfg(unix)]
+    #[test]
+    fn test_read_active_pty() {
+        use std::sync::mpsc;
+
+        let (mut master, slave) = serialport::TTYPort::pair().unwrap();
+        master.set_timeout(Duration::from_secs(5)).unwrap();
+        let path = slave.name().unwrap();
+        let config = Config::default();
+        let stop = Arc::clone(&config.stop);
+        let (sender, receiver) = mpsc::channel();
+        let reader = thread::spawn(move || {
+            read_active_with_config(&path, &config, move |data| sender.send(data).unwrap())
+                .map_err(|e| e.to_string())
+        });
+
+        // The wake-up commands show that the port is open and configured.
+        let mut commands = [0; 14];
+        master.read_exact(&mut commands).unwrap();
+        assert_eq!(&commands[..7], &Command::Wake.to_bytes());
+
+        master.write_all(GOLDEN_PACKET).unwrap();
+        let first = receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(first.version(), Some(0x97));
+
+        let mut changed = first.clone();
+        changed.pm2_5_cf1 = 42;
+        for fragment in encode(&changed).chunks(5) {
+            master.write_all(fragment).unwrap();
+            master.flush().unwrap();
+            thread::sleep(Duration::from_millis(20));
+        }
+        let second = receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(second.pm2_5_cf1, 42);
+        assert!(second.has_valid_checksum());
+
+        stop.store(true, Ordering::Relaxed);
+        reader.join().unwrap().unwrap();
+        drop(slave);
+    }
+
+    #[test]
+    fn test_read_multi_errors() {
+        let sensors = ["kitchen", "bedroom"]
+            .iter()
+            .map(|location| SensorConfig {
+                port: format!("/nonexistent/{}", location),
+                location: location.to_string(),
+                config: Config::default(),
+            })
+            .collect();
+        let handles = read_multi(sensors, |_| |_| panic!("no data expected"));
+        assert_eq!(handles[1].location(), "bedroom");
+        let errors = join_all(handles).unwrap_err();
+        let locations: Vec<&str> = errors.iter().map(|(l, _)| l.as_str()).collect();
+        assert_eq!(locations, ["kitchen", "bedroom"]);
+        assert!(matches!(errors[0].1, PmsError::Serial(_)));
+    }
+
+    #[test]
+    fn test_zero_frame_warmup() {
+        let zero = encode(&PmsData::from([0; 13]));
+        let mut input = zero.clone();
+        input.extend_from_slice(GOLDEN_PACKET);
+        input.extend_from_slice(&zero);
+        let clock = MockClock::new(UNIX_EPOCH);
+        let config = Config {
+            zero_frame_warmup: Duration::from_secs(60),
+            clock: Arc::new(clock.clone()),
+            ..Default::default()
+        };
+        let mut pms = Pms::with_port(Box::new(MockPort::new(&input, 32)), config);
+        assert_eq!(pms.read_frame().unwrap().unwrap().version(), Some(0x97));
+        clock.advance(Duration::from_secs(60));
+        assert!(pms.read_frame().unwrap().unwrap().is_all_zero());
+
+        let mut pms = Pms::with_port(Box::new(MockPort::new(&input, 64)), Config::default());
+        assert!(pms.read_frame().unwrap().unwrap().is_all_zero());
+    }
+
+    #[test]
+    fn test_frame_rate() {
+        let start = Instant::now();
+        let after = |millis| start + Duration::from_millis(millis);
+        let mut rate = FrameRate::new();
+        rate.frame(start);
+        assert_eq!(rate.update(start), None);
+        for second in 1..=30 {
+            rate.frame(after(second * 1000));
+        }
+        assert_eq!(rate.update(after(30_000)), Some(60.0));
+        // Decays while no frames arrive.
+        assert_eq!(rate.update(after(40_000)), Some(30.0));
+        assert_eq!(rate.update(after(60_000)), None);
+
+        for second in 0..10 {
+            rate.frame(after(100_000 + second * 2000));
+        }
+        assert_eq!(rate.update(after(118_000)), Some(30.0));
+    }
+
+    #[test]
+    fn test_slow_detector() {
+        let start = Instant::now();
+        let after = |secs| start + Duration::from_secs(secs);
+        let mut slow = SlowDetector::new(&Config::default(), start);
+        slow.frame(after(1));
+        slow.check(after(5));
+        assert!(!slow.slow);
+        slow.check(after(7));
+        assert!(slow.slow);
+        // A late frame doesn't clear the flag, but one in time does.
+        slow.frame(after(10));
+        assert!(slow.slow);
+        slow.frame(after(11));
+        assert!(!slow.slow);
+
+        let config = Config {
+            poll_interval: Some(Duration::from_secs(10)),
+            ..Default::default()
+        };
+        assert_eq!(
+            SlowDetector::new(&config, start).threshold,
+            Duration::from_secs(50)
+        );
+    }
+
+    #[test]
+    fn test_has_complete_frame() {
+        assert!(!has_complete_frame(&GOLDEN_PACKET[..31]));
+        assert!(has_complete_frame(GOLDEN_PACKET));
+        let mut noisy = b"noise".to_vec();
+        noisy.extend_from_slice(&GOLDEN_PACKET[..20]);
+        assert!(!has_complete_frame(&noisy));
+        noisy.extend_from_slice(&GOLDEN_PACKET[20..]);
+        assert!(has_complete_frame(&noisy));
+        const ACK: &[u8] = &[0x42, 0x4d, 0x00, 0x04, 0xe1, 0x00, 0x01, 0x74];
+        assert!(has_complete_frame(ACK));
+        assert!(!has_complete_frame(&ACK[..7]));
+
+        let mut extended = PmsData::from([0; 13]);
+        extended.frame_length = crate::EXTENDED_DATA_FRAME_LENGTH;
+        extended.extended = Some(crate::Extended {
+            formaldehyde: 1,
+            temperature: 200,
+            humidity: 500,
+            reserved: 0,
+        });
+        let extended = encode(&extended);
+        assert_eq!(extended.len(), 40);
+        assert!(!has_complete_frame(&extended[..39]));
+        assert!(has_complete_frame(&extended));
+    }
+}
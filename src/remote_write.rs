@@ -0,0 +1,483 @@
+//! Pushing the metrics to a Prometheus remote-write endpoint, e.g. Cortex,
+//! Mimir or Thanos receive, for setups where a central server can't scrape
+//! the device.
+//!
+//! The series are gathered from the default registry, so they have the same
+//! names and labels as on `/metrics`. Samples are gathered every interval
+//! and sent in batches: samples that couldn't be sent are kept, up to
+//! `MAX_PENDING_BATCHES` gatherings, and sent with the next batch.
+//!
+//! Only plain `http://` URLs are supported; put a TLS-terminating proxy in
+//! front of endpoints that need HTTPS or authentication.
+
+use crate::clock::{real_clock, Clock};
+use log::{debug, error, warn};
+use prometheus_exporter::prometheus::gather;
+use prometheus_exporter::prometheus::proto::{MetricFamily, MetricType};
+use std::collections::BTreeMap;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::TcpStream;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, UNIX_EPOCH};
+
+/// Gatherings kept for resending while the endpoint is unreachable, about
+/// an hour at the default interval.
+pub const MAX_PENDING_BATCHES: usize = 240;
+pub const DEFAULT_INTERVAL: Duration = Duration::from_secs(15);
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A series, identified by its labels sorted by name, including `__name__`.
+type Labels = Vec<(String, String)>;
+
+/// The samples of each series, each a value and a timestamp in milliseconds
+/// since the Unix epoch.
+#[derive(Debug, Default)]
+struct Batch {
+    series: BTreeMap<Labels, Vec<(f64, i64)>>,
+    gatherings: usize,
+}
+
+impl Batch {
+    /// Adds a sample at `timestamp` for every series in `families`.
+    fn add(&mut self, families: &[MetricFamily], timestamp: i64) {
+        for family in families {
+            for (labels, value) in samples(family) {
+                self.series
+                    .entry(labels)
+                    .or_default()
+                    .push((value, timestamp));
+            }
+        }
+        self.gatherings += 1;
+    }
+
+    /// Drops the oldest gathering's samples, so that at most
+    /// `MAX_PENDING_BATCHES` are kept.
+    fn trim(&mut self) {
+        if self.gatherings <= MAX_PENDING_BATCHES {
+            return;
+        }
+        let oldest = self
+            .series
+            .values()
+            .filter_map(|samples| samples.first())
+            .map(|&(_, timestamp)| timestamp)
+            .min();
+        for samples in self.series.values_mut() {
+            samples.retain(|&(_, timestamp)| Some(timestamp) != oldest);
+        }
+        self.series.retain(|_, samples| !samples.is_empty());
+        self.gatherings -= 1;
+    }
+
+    fn is_empty(&self) -> bool {
+        self.series.is_empty()
+    }
+}
+
+/// The series of `family` and their current values, with histograms and
+/// summaries expanded into their `_bucket`, `_sum` and `_count` series as
+/// on `/metrics`.
+fn samples(family: &MetricFamily) -> Vec<(Labels, f64)> {
+    let name = family.get_name();
+    let mut samples = Vec::new();
+    for metric in family.get_metric() {
+        let series = |suffix: &str, extra: Option<(&str, String)>| {
+            let mut labels: Labels = metric
+                .get_label()
+                .iter()
+                .map(|label| (label.get_name().to_string(), label.get_value().to_string()))
+                .collect();
+            labels.push(("__name__".to_string(), format!("{}{}", name, suffix)));
+            labels.extend(extra.map(|(name, value)| (name.to_string(), value)));
+            labels.sort();
+            labels
+        };
+        match family.get_field_type() {
+            MetricType::COUNTER => {
+                samples.push((series("", None), metric.get_counter().get_value()))
+            }
+            MetricType::GAUGE => samples.push((series("", None), metric.get_gauge().get_value())),
+            // Not produced by the prometheus crate's own metric types.
+            MetricType::UNTYPED => debug!("skipping untyped metric {}", name),
+            MetricType::HISTOGRAM => {
+                let histogram = metric.get_histogram();
+                for bucket in histogram.get_bucket() {
+                    let le = ("le", bucket.get_upper_bound().to_string());
+                    let count = bucket.get_cumulative_count() as f64;
+                    samples.push((series("_bucket", Some(le)), count));
+                }
+                let count = histogram.get_sample_count() as f64;
+                let le = ("le", "+Inf".to_string());
+                samples.push((series("_bucket", Some(le)), count));
+                samples.push((series("_sum", None), histogram.get_sample_sum()));
+                samples.push((series("_count", None), count));
+            }
+            MetricType::SUMMARY => {
+                let summary = metric.get_summary();
+                for quantile in summary.get_quantile() {
+                    let label = ("quantile", quantile.get_quantile().to_string());
+                    samples.push((series("", Some(label)), quantile.get_value()));
+                }
+                samples.push((series("_sum", None), summary.get_sample_sum()));
+                let count = summary.get_sample_count() as f64;
+                samples.push((series("_count", None), count));
+            }
+        }
+    }
+    samples
+}
+
+fn put_varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+/// Appends a length-delimited protobuf field.
+fn put_bytes(out: &mut Vec<u8>, field: u64, bytes: &[u8]) {
+    put_varint(out, field << 3 | 2);
+    put_varint(out, bytes.len() as u64);
+    out.extend_from_slice(bytes);
+}
+
+/// Encodes `batch` as a remote-write `WriteRequest` protobuf message.
+fn encode_write_request(batch: &Batch) -> Vec<u8> {
+    let mut request = Vec::new();
+    for (labels, samples) in &batch.series {
+        let mut series = Vec::new();
+        for (name, value) in labels {
+            let mut label = Vec::new();
+            put_bytes(&mut label, 1, name.as_bytes());
+            put_bytes(&mut label, 2, value.as_bytes());
+            put_bytes(&mut series, 1, &label);
+        }
+        for &(value, timestamp) in samples {
+            let mut sample = Vec::new();
+            // value: double, field 1, 64-bit wire type.
+            put_varint(&mut sample, 1 << 3 | 1);
+            sample.extend_from_slice(&value.to_le_bytes());
+            // timestamp: int64, field 2, varint wire type.
+            put_varint(&mut sample, 2 << 3);
+            put_varint(&mut sample, timestamp as u64);
+            put_bytes(&mut series, 2, &sample);
+        }
+        put_bytes(&mut request, 1, &series);
+    }
+    request
+}
+
+/// Snappy block format made of literals only, which every decoder accepts.
+/// The payloads are a few kilobytes, which isn't worth a compression
+/// dependency for.
+fn snappy_literals(input: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(input.len() + input.len() / 60_000 * 3 + 8);
+    put_varint(&mut out, input.len() as u64);
+    for chunk in input.chunks(1 << 16) {
+        let n = chunk.len() - 1;
+        if n < 60 {
+            out.push((n as u8) << 2);
+        } else if n < 1 << 8 {
+            out.push(60 << 2);
+            out.push(n as u8);
+        } else {
+            out.push(61 << 2);
+            out.extend_from_slice(&(n as u16).to_le_bytes());
+        }
+        out.extend_from_slice(chunk);
+    }
+    out
+}
+
+/// Splits an `http://host[:port]/path` URL into the address to connect to,
+/// the `Host` header and the path.
+fn parse_url(url: &str) -> io::Result<(String, String, String)> {
+    let invalid = || {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("not an http URL: {}", url),
+        )
+    };
+    let rest = url.strip_prefix("http://").ok_or_else(invalid)?;
+    let (host, path) = match rest.find('/') {
+        Some(i) => (&rest[..i], &rest[i..]),
+        None => (rest, "/"),
+    };
+    if host.is_empty() {
+        return Err(invalid());
+    }
+    let addr = if host
+        .rsplit(':')
+        .next()
+        .is_some_and(|port| port.parse::<u16>().is_ok())
+    {
+        host.to_string()
+    } else {
+        format!("{}:80", host)
+    };
+    Ok((addr, host.to_string(), path.to_string()))
+}
+
+/// Sends `body` to `url`, returning the status code.
+fn post(url: &str, body: &[u8]) -> io::Result<u16> {
+    let (addr, host, path) = parse_url(url)?;
+    let mut stream = TcpStream::connect(&addr)?;
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+    write!(
+        stream,
+        "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/x-protobuf\r\n\
+         Content-Encoding: snappy\r\nX-Prometheus-Remote-Write-Version: 0.1.0\r\n\
+         User-Agent: pms7003\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        path,
+        host,
+        body.len()
+    )?;
+    stream.write_all(body)?;
+    let mut status = String::new();
+    BufReader::new(stream).read_line(&mut status)?;
+    status
+        .split_whitespace()
+        .nth(1)
+        .and_then(|code| code.parse().ok())
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("bad status line: {:?}", status.trim_end()),
+            )
+        })
+}
+
+/// Sends `batch`, returning whether it is done with: sent, or rejected by
+/// the endpoint as malformed, which resending wouldn't fix.
+fn send(url: &str, batch: &Batch) -> bool {
+    let body = snappy_literals(&encode_write_request(batch));
+    match post(url, &body) {
+        Ok(status) if (200..300).contains(&status) => {
+            debug!("sent {} series to {}", batch.series.len(), url);
+            true
+        }
+        Ok(status) if (400..500).contains(&status) && status != 429 => {
+            error!(
+                "{} rejected the samples with status {}, dropping them",
+                url, status
+            );
+            true
+        }
+        Ok(status) => {
+            warn!("{} responded with status {}, will retry", url, status);
+            false
+        }
+        Err(e) => {
+            warn!("could not send samples to {}: {}, will retry", url, e);
+            false
+        }
+    }
+}
+
+/// Spawns a thread that gathers the metrics every `interval` and sends them
+/// to the remote-write endpoint at `url`. Fails if `url` isn't an `http://`
+/// URL.
+pub fn remote_write_every(url: &str, interval: Duration) -> io::Result<thread::JoinHandle<()>> {
+    remote_write_every_with_clock(url, interval, real_clock())
+}
+
+/// Like `remote_write_every`, with the samples timestamped by `clock`.
+pub fn remote_write_every_with_clock(
+    url: &str,
+    interval: Duration,
+    clock: Arc<dyn Clock>,
+) -> io::Result<thread::JoinHandle<()>> {
+    parse_url(url)?;
+    let url = url.to_string();
+    Ok(thread::spawn(move || {
+        let mut batch = Batch::default();
+        loop {
+            thread::sleep(interval);
+            let timestamp = clock
+                .now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis() as i64;
+            batch.add(&gather(), timestamp);
+            batch.trim();
+            if !batch.is_empty() && send(&url, &batch) {
+                batch = Batch::default();
+            }
+        }
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use prometheus_exporter::prometheus::{GaugeVec, Opts, Registry};
+    use std::io::Read;
+    use std::net::TcpListener;
+
+    fn families() -> Vec<MetricFamily> {
+        let registry = Registry::new();
+        let gauge = GaugeVec::new(Opts::new("pm", "help"), &["size"]).unwrap();
+        registry.register(Box::new(gauge.clone())).unwrap();
+        gauge.with_label_values(&["2.5"]).set(4.0);
+        registry.gather()
+    }
+
+    /// Undoes `snappy_literals`.
+    fn unsnappy(input: &[u8]) -> Vec<u8> {
+        let (mut len, mut shift, mut i) = (0usize, 0, 0);
+        loop {
+            len |= ((input[i] & 0x7f) as usize) << shift;
+            shift += 7;
+            i += 1;
+            if input[i - 1] < 0x80 {
+                break;
+            }
+        }
+        let mut out = Vec::new();
+        while i < input.len() {
+            let tag = input[i] as usize;
+            assert_eq!(tag & 3, 0, "not a literal");
+            let (n, header) = match tag >> 2 {
+                60 => (input[i + 1] as usize, 2),
+                61 => (u16::from_le_bytes([input[i + 1], input[i + 2]]) as usize, 3),
+                n => (n, 1),
+            };
+            i += header;
+            out.extend_from_slice(&input[i..i + n + 1]);
+            i += n + 1;
+        }
+        assert_eq!(out.len(), len);
+        out
+    }
+
+    #[test]
+    fn test_encode_write_request() {
+        let mut batch = Batch::default();
+        batch.add(&families(), 1000);
+        let encoded = encode_write_request(&batch);
+        let mut expected = vec![0x0a, 43];
+        // Labels, sorted by name.
+        expected.extend_from_slice(b"\x0a\x0e\x0a\x08__name__\x12\x02pm");
+        expected.extend_from_slice(b"\x0a\x0b\x0a\x04size\x12\x032.5");
+        expected.extend_from_slice(&[0x12, 12, 0x09]);
+        expected.extend_from_slice(&4.0f64.to_le_bytes());
+        expected.extend_from_slice(&[0x10, 0xe8, 0x07]);
+        assert_eq!(encoded, expected);
+    }
+
+    #[test]
+    fn test_batch_trim() {
+        let mut batch = Batch::default();
+        for i in 0..MAX_PENDING_BATCHES as i64 + 2 {
+            batch.add(&families(), i);
+            batch.trim();
+        }
+        let samples = batch.series.values().next().unwrap();
+        assert_eq!(samples.len(), MAX_PENDING_BATCHES);
+        assert_eq!(samples[0].1, 2);
+    }
+
+    #[test]
+    fn test_snappy_literals() {
+        for len in [0, 1, 60, 61, 256, 257, 70_000] {
+            let input: Vec<u8> = (0..len).map(|i| i as u8).collect();
+            assert_eq!(unsnappy(&snappy_literals(&input)), input, "{}", len);
+        }
+    }
+
+    #[test]
+    fn test_parse_url() {
+        let parsed = parse_url("http://mimir:9009/api/v1/push").unwrap();
+        assert_eq!(
+            parsed,
+            (
+                "mimir:9009".to_string(),
+                "mimir:9009".to_string(),
+                "/api/v1/push".to_string()
+            )
+        );
+        assert_eq!(parse_url("http://receive").unwrap().0, "receive:80");
+        assert!(parse_url("https://mimir/api/v1/push").is_err());
+        assert!(parse_url("http:///push").is_err());
+    }
+
+    #[test]
+    fn test_send() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/api/v1/push", listener.local_addr().unwrap());
+        let server = thread::spawn(move || {
+            let mut requests = Vec::new();
+            for status in [503, 400, 204] {
+                let (stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream);
+                let mut head = String::new();
+                while !head.ends_with("\r\n\r\n") {
+                    reader.read_line(&mut head).unwrap();
+                }
+                let length = head
+                    .lines()
+                    .find_map(|line| line.strip_prefix("Content-Length: "))
+                    .unwrap()
+                    .parse()
+                    .unwrap();
+                let mut body = vec![0; length];
+                reader.read_exact(&mut body).unwrap();
+                write!(reader.get_mut(), "HTTP/1.1 {} Whatever\r\n\r\n", status).unwrap();
+                requests.push((head, body));
+            }
+            requests
+        });
+        let mut batch = Batch::default();
+        batch.add(&families(), 1000);
+        // Retried on server errors, dropped on client errors.
+        assert!(!send(&url, &batch));
+        assert!(send(&url, &batch));
+        assert!(send(&url, &batch));
+        let requests = server.join().unwrap();
+        let (head, body) = &requests[2];
+        assert!(
+            head.starts_with("POST /api/v1/push HTTP/1.1\r\n"),
+            "{}",
+            head
+        );
+        assert!(head.contains("Content-Encoding: snappy\r\n"), "{}", head);
+        assert!(
+            head.contains("X-Prometheus-Remote-Write-Version: 0.1.0\r\n"),
+            "{}",
+            head
+        );
+        assert_eq!(unsnappy(body), encode_write_request(&batch));
+    }
+
+    #[test]
+    fn test_remote_write_every_with_clock() {
+        lazy_static::initialize(&crate::metrics::RESERVED_WORD);
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/api/v1/push", listener.local_addr().unwrap());
+        let clock = crate::clock::MockClock::new(UNIX_EPOCH + Duration::from_secs(1));
+        remote_write_every_with_clock(&url, Duration::from_millis(10), Arc::new(clock)).unwrap();
+
+        let (stream, _) = listener.accept().unwrap();
+        let mut reader = BufReader::new(stream);
+        let mut head = String::new();
+        while !head.ends_with("\r\n\r\n") {
+            reader.read_line(&mut head).unwrap();
+        }
+        let length = head
+            .lines()
+            .find_map(|line| line.strip_prefix("Content-Length: "))
+            .unwrap()
+            .parse()
+            .unwrap();
+        let mut body = vec![0; length];
+        reader.read_exact(&mut body).unwrap();
+        write!(reader.get_mut(), "HTTP/1.1 204 No Content\r\n\r\n").unwrap();
+        // Every sample is timestamped 1000 ms.
+        let request = unsnappy(&body);
+        assert!(request.windows(3).any(|w| w == [0x10, 0xe8, 0x07]));
+    }
+}
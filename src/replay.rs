@@ -0,0 +1,273 @@
+//! Recording the raw serial stream with its timing, and replaying it, so
+//! that time-dependent logic (settle time, averaging windows) can be tested
+//! against real-world streams.
+//!
+//! A recording is a sequence of chunks, one per read from the port: the
+//! arrival time in microseconds since the Unix epoch (8 bytes), the length
+//! (4 bytes), both big-endian, and the bytes read.
+
+use crate::clock::{real_clock, Clock};
+use crate::reader::{Config, Pms, Port};
+use crate::{PmsData, PmsError};
+use std::convert::TryInto;
+use std::fs::File;
+use std::io::{self, BufReader, Read, Write};
+use std::path::Path;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const CHUNK_HEADER_SIZE: usize = 12;
+
+/// Writes one chunk of a recording.
+fn write_chunk<W: Write>(out: &mut W, at: SystemTime, bytes: &[u8]) -> io::Result<()> {
+    let micros = at
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_micros() as u64;
+    let mut header = [0u8; CHUNK_HEADER_SIZE];
+    header[..8].copy_from_slice(&micros.to_be_bytes());
+    header[8..].copy_from_slice(&(bytes.len() as u32).to_be_bytes());
+    out.write_all(&header)?;
+    out.write_all(bytes)
+}
+
+/// Reads the next chunk of a recording, or `None` at the end.
+fn read_chunk<R: Read>(input: &mut R) -> io::Result<Option<(Duration, Vec<u8>)>> {
+    let mut header = [0u8; CHUNK_HEADER_SIZE];
+    match input.read_exact(&mut header) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+    let micros = u64::from_be_bytes(header[..8].try_into().unwrap());
+    let len = u32::from_be_bytes(header[8..].try_into().unwrap());
+    let mut bytes = vec![0; len as usize];
+    input.read_exact(&mut bytes)?;
+    Ok(Some((Duration::from_micros(micros), bytes)))
+}
+
+/// A port that records everything read from `port` to `out`. See
+/// `Config::record`.
+pub struct RecordingPort<P, W> {
+    port: P,
+    out: W,
+    clock: Arc<dyn Clock>,
+}
+
+impl<P: Port, W: Write + Send> RecordingPort<P, W> {
+    pub fn new(port: P, out: W) -> Self {
+        Self::with_clock(port, out, real_clock())
+    }
+
+    /// Like `new`, with each chunk timestamped by `clock`.
+    pub fn with_clock(port: P, out: W, clock: Arc<dyn Clock>) -> Self {
+        RecordingPort { port, out, clock }
+    }
+}
+
+impl<P: Port, W: Write + Send> Read for RecordingPort<P, W> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.port.read(buf)?;
+        if n > 0 {
+            write_chunk(&mut self.out, self.clock.now(), &buf[..n])?;
+        }
+        Ok(n)
+    }
+}
+
+impl<P: Port, W: Write + Send> Write for RecordingPort<P, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.port.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.port.flush()
+    }
+}
+
+impl<P: Port, W: Write + Send> Port for RecordingPort<P, W> {
+    fn bytes_to_read(&self) -> io::Result<u32> {
+        self.port.bytes_to_read()
+    }
+
+    fn is_writable(&self) -> bool {
+        self.port.is_writable()
+    }
+
+    fn clear_input(&mut self) -> io::Result<()> {
+        self.port.clear_input()
+    }
+}
+
+/// A read-only port that serves a recording, sleeping before each chunk to
+/// reproduce the gap since the one before, divided by `speed`. Reads return
+/// `Ok(0)` at the end.
+pub struct ReplayPort<R> {
+    input: R,
+    speed: f64,
+    last: Option<Duration>,
+    chunk: Vec<u8>,
+    position: usize,
+}
+
+impl<R: Read + Send> ReplayPort<R> {
+    /// `speed` must be positive; 2.0 replays twice as fast as recorded.
+    pub fn new(input: R, speed: f64) -> Self {
+        assert!(speed > 0.0, "speed must be positive");
+        ReplayPort {
+            input,
+            speed,
+            last: None,
+            chunk: Vec::new(),
+            position: 0,
+        }
+    }
+}
+
+impl<R: Read + Send> Read for ReplayPort<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.position == self.chunk.len() {
+            let (at, chunk) = match read_chunk(&mut self.input)? {
+                Some(chunk) => chunk,
+                None => return Ok(0),
+            };
+            if let Some(last) = self.last {
+                thread::sleep(at.saturating_sub(last).div_f64(self.speed));
+            }
+            self.last = Some(at);
+            self.chunk = chunk;
+            self.position = 0;
+        }
+        let n = buf.len().min(self.chunk.len() - self.position);
+        buf[..n].copy_from_slice(&self.chunk[self.position..self.position + n]);
+        self.position += n;
+        Ok(n)
+    }
+}
+
+impl<R: Read + Send> Write for ReplayPort<R> {
+    fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+        Err(io::ErrorKind::PermissionDenied.into())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<R: Read + Send> Port for ReplayPort<R> {
+    fn bytes_to_read(&self) -> io::Result<u32> {
+        Ok((self.chunk.len() - self.position) as u32)
+    }
+
+    fn is_writable(&self) -> bool {
+        false
+    }
+}
+
+/// Passes every data frame of the recording at `path` to `callback`,
+/// reproducing the original timing at `speed` times the recorded pace.
+pub fn replay_file_timed<P, F>(path: P, speed: f64, mut callback: F) -> Result<(), PmsError>
+where
+    P: AsRef<Path>,
+    F: FnMut(PmsData),
+{
+    let port = ReplayPort::new(BufReader::new(File::open(path)?), speed);
+    let config = Config {
+        wake_on_start: false,
+        ..Default::default()
+    };
+    let mut pms = Pms::with_port(Box::new(port), config);
+    while let Some(data) = pms.read_frame()? {
+        callback(data);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+    use crate::GOLDEN_PACKET;
+    use std::collections::VecDeque;
+    use std::time::Instant;
+
+    /// Serves one chunk per read.
+    struct ChunkPort(VecDeque<Vec<u8>>);
+
+    impl Read for ChunkPort {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let chunk = self.0.pop_front().unwrap_or_default();
+            buf[..chunk.len()].copy_from_slice(&chunk);
+            Ok(chunk.len())
+        }
+    }
+
+    impl Write for ChunkPort {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Port for ChunkPort {
+        fn bytes_to_read(&self) -> io::Result<u32> {
+            Ok(0)
+        }
+    }
+
+    #[test]
+    fn test_record_and_replay() {
+        let chunks = vec![GOLDEN_PACKET[..10].to_vec(), GOLDEN_PACKET[10..].to_vec()];
+        let mut port = RecordingPort::new(ChunkPort(chunks.into()), Vec::new());
+        let mut buf = [0; 64];
+        while port.read(&mut buf).unwrap() > 0 {}
+        let recording = port.out;
+        assert_eq!(recording.len(), 2 * CHUNK_HEADER_SIZE + GOLDEN_PACKET.len());
+
+        let replay = ReplayPort::new(io::Cursor::new(recording), 1.0);
+        let mut pms = Pms::with_port(Box::new(replay), Config::default());
+        assert_eq!(pms.read_frame().unwrap().unwrap().version(), Some(0x97));
+        assert_eq!(pms.read_frame().unwrap(), None);
+    }
+
+    #[test]
+    fn test_record_with_clock() {
+        let clock = MockClock::new(UNIX_EPOCH + Duration::from_secs(10));
+        let chunks = vec![GOLDEN_PACKET.to_vec()];
+        let mut port =
+            RecordingPort::with_clock(ChunkPort(chunks.into()), Vec::new(), Arc::new(clock));
+        let mut buf = [0; 64];
+        while port.read(&mut buf).unwrap() > 0 {}
+        let (at, bytes) = read_chunk(&mut &port.out[..]).unwrap().unwrap();
+        assert_eq!(at, Duration::from_secs(10));
+        assert_eq!(bytes, GOLDEN_PACKET);
+    }
+
+    #[test]
+    fn test_replay_file_timed() {
+        let start = UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+        let mut recording = Vec::new();
+        write_chunk(&mut recording, start, GOLDEN_PACKET).unwrap();
+        write_chunk(
+            &mut recording,
+            start + Duration::from_millis(400),
+            GOLDEN_PACKET,
+        )
+        .unwrap();
+        let path = std::env::temp_dir().join(format!("pms7003-replay-{}", std::process::id()));
+        std::fs::write(&path, &recording).unwrap();
+
+        let started = Instant::now();
+        let mut arrivals = Vec::new();
+        replay_file_timed(&path, 2.0, |_| arrivals.push(started.elapsed())).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(arrivals.len(), 2);
+        assert!(arrivals[1] - arrivals[0] >= Duration::from_millis(200));
+        assert!(arrivals[1] - arrivals[0] < Duration::from_millis(400));
+    }
+}
@@ -0,0 +1,160 @@
+//! Backend-agnostic recording of readings.
+//!
+//! `record_reading` turns a reading into concentration, count and AQI values
+//! and hands them to a `MetricsSink`, so that readings can be routed into any
+//! metrics backend. `metrics::PrometheusSink` is the default implementation.
+
+use crate::aqi::{calculate_aqi, AqiStandard};
+use crate::{PmsData, Pollutant};
+use log::error;
+
+/// Which of the two concentration sets reported by the sensor a value comes
+/// from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConcentrationBasis {
+    /// CF=1, standard particle.
+    Standard,
+    /// Under atmospheric environment.
+    Environment,
+}
+
+impl ConcentrationBasis {
+    pub const ALL: [ConcentrationBasis; 2] = [
+        ConcentrationBasis::Standard,
+        ConcentrationBasis::Environment,
+    ];
+
+    /// Value of the `concentration_basis` metric label.
+    pub fn label(self) -> &'static str {
+        match self {
+            ConcentrationBasis::Standard => "standard",
+            ConcentrationBasis::Environment => "environment",
+        }
+    }
+}
+
+/// Receives the values derived from each reading.
+///
+/// `size` is the particle size in µm as it appears in the `particle_size`
+/// label, e.g. `"2.5"`, and `standard` is an `AqiStandard` label.
+pub trait MetricsSink {
+    /// Mass concentration in µg/m³.
+    fn record_concentration(&self, basis: ConcentrationBasis, size: &str, value: f64);
+    /// Number of particles with a diameter beyond `size` in 0.1 L of air.
+    fn record_count(&self, size: &str, value: f64);
+    /// AQI computed from the concentrations in `basis`.
+    fn record_aqi(&self, basis: ConcentrationBasis, size: &str, standard: &str, value: u32);
+}
+
+/// Records every concentration and count in `data`, and the PM2.5 and PM10
+/// AQI for each of `standards`, computed from both the CF=1 and the
+/// atmospheric concentrations. AQI values that are out of range are logged
+/// and skipped.
+pub fn record_reading(sink: &dyn MetricsSink, data: &PmsData, standards: &[AqiStandard]) {
+    for basis in ConcentrationBasis::ALL {
+        for pollutant in Pollutant::ALL {
+            sink.record_concentration(
+                basis,
+                pollutant.label(),
+                data.concentration(pollutant, basis),
+            );
+        }
+    }
+
+    let counts = [
+        ("0.3", data.pm0_3_count),
+        ("0.5", data.pm0_5_count),
+        ("1.0", data.pm1_0_count),
+        ("2.5", data.pm2_5_count),
+        ("5.0", data.pm5_0_count),
+        ("10.0", data.pm10_0_count),
+    ];
+    for (size, value) in counts {
+        sink.record_count(size, value.into());
+    }
+
+    for standard in standards {
+        for basis in ConcentrationBasis::ALL {
+            let concentration = |pollutant| data.concentration(pollutant, basis);
+            let sub_indices = [
+                (
+                    "2.5",
+                    calculate_aqi(standard.pm2_5(), concentration(Pollutant::Pm2_5)),
+                ),
+                (
+                    "10.0",
+                    calculate_aqi(standard.pm10(), concentration(Pollutant::Pm10)),
+                ),
+            ];
+            for (size, aqi) in sub_indices {
+                match aqi {
+                    Some(value) => sink.record_aqi(basis, size, standard.label(), value),
+                    None => error!("Could not compute AQI: value is out of range"),
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse;
+    use crate::GOLDEN_PACKET;
+    use std::cell::RefCell;
+
+    #[derive(Default)]
+    struct RecordingSink {
+        records: RefCell<Vec<String>>,
+    }
+
+    impl MetricsSink for RecordingSink {
+        fn record_concentration(&self, basis: ConcentrationBasis, size: &str, value: f64) {
+            self.records
+                .borrow_mut()
+                .push(format!("{:?} {} {}", basis, size, value));
+        }
+
+        fn record_count(&self, size: &str, value: f64) {
+            self.records
+                .borrow_mut()
+                .push(format!("count {} {}", size, value));
+        }
+
+        fn record_aqi(&self, basis: ConcentrationBasis, size: &str, standard: &str, value: u32) {
+            self.records.borrow_mut().push(format!(
+                "aqi {} {} {} {}",
+                basis.label(),
+                size,
+                standard,
+                value
+            ));
+        }
+    }
+
+    #[test]
+    fn test_record_reading() {
+        let mut data = parse(GOLDEN_PACKET).unwrap().1.unwrap();
+        data.pm2_5_atmo = 37;
+        let sink = RecordingSink::default();
+        record_reading(&sink, &data, &[AqiStandard::UsEpa, AqiStandard::EuCaqi]);
+        let records = sink.records.into_inner();
+        assert_eq!(records.len(), 20);
+        assert_eq!(records[1], "Standard 2.5 4");
+        assert_eq!(records[5], "Environment 10.0 7");
+        assert_eq!(records[6], "count 0.3 720");
+        assert_eq!(
+            &records[12..],
+            &[
+                "aqi standard 2.5 us_epa 17",
+                "aqi standard 10.0 us_epa 6",
+                "aqi environment 2.5 us_epa 105",
+                "aqi environment 10.0 us_epa 6",
+                "aqi standard 2.5 eu_caqi 7",
+                "aqi standard 10.0 eu_caqi 7",
+                "aqi environment 2.5 eu_caqi 57",
+                "aqi environment 10.0 eu_caqi 7",
+            ]
+        );
+    }
+}
@@ -0,0 +1,1292 @@
+//! Aggregations over a stream of timestamped readings.
+//!
+//! Everything here takes the reading time as an argument rather than reading
+//! the clock, so that the trackers can be fed recorded data and tested
+//! deterministically. With the `serde` feature they can be serialized, e.g.
+//! to keep them across restarts with `metrics::save_state`.
+
+use crate::PmsData;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Rolling mean over the most recent `size` readings.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Averager {
+    size: usize,
+    samples: VecDeque<f64>,
+    sum: f64,
+}
+
+impl Averager {
+    /// `size` must be non-zero.
+    pub fn new(size: usize) -> Self {
+        assert!(size > 0, "size must be non-zero");
+        Averager {
+            size,
+            samples: VecDeque::with_capacity(size),
+            sum: 0.0,
+        }
+    }
+
+    pub fn push(&mut self, value: f64) {
+        if self.samples.len() == self.size {
+            if let Some(oldest) = self.samples.pop_front() {
+                self.sum -= oldest;
+            }
+        }
+        self.samples.push_back(value);
+        self.sum += value;
+    }
+
+    /// Mean of the readings so far, even if the window isn't full yet.
+    pub fn mean(&self) -> Option<f64> {
+        if self.samples.is_empty() {
+            None
+        } else {
+            Some(self.sum / self.samples.len() as f64)
+        }
+    }
+
+    /// Whether the window is full, i.e. `mean` covers `size` readings.
+    pub fn is_warm(&self) -> bool {
+        self.samples_remaining() == 0
+    }
+
+    /// Number of readings still needed to fill the window.
+    pub fn samples_remaining(&self) -> usize {
+        self.size - self.samples.len()
+    }
+}
+
+/// What to do with a reading timestamped earlier than the latest one, e.g.
+/// when replaying recordings or merging streams from several sensors.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum OutOfOrder {
+    /// Add it as if it were in order.
+    #[default]
+    Accept,
+    /// Drop it and count it in `out_of_order_drops`.
+    Reject,
+    /// Insert it in timestamp order. Readings belonging to a period that has
+    /// already been completed can't be reordered, and are dropped as with
+    /// `Reject`.
+    Reorder,
+}
+
+/// The average of all readings within one clock-aligned period.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct BucketAverage {
+    pub start: SystemTime,
+    pub end: SystemTime,
+    pub mean: f64,
+    pub samples: usize,
+    /// Set for a period cut short by `flush`, e.g. on shutdown, and for the
+    /// rest of that period after it. `end` is still the end of the whole
+    /// period.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub partial: bool,
+}
+
+/// Averages readings over fixed periods aligned to the wall clock (UTC), e.g.
+/// 09:00–10:00 for an hourly period, matching how regulatory data is
+/// reported.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct ClockAlignedAverager {
+    period: Duration,
+    bucket_start: Option<SystemTime>,
+    sum: f64,
+    samples: usize,
+    out_of_order: OutOfOrder,
+    latest: Option<SystemTime>,
+    drops: u64,
+    /// Whether the period in progress was flushed.
+    #[cfg_attr(feature = "serde", serde(default))]
+    flushed: bool,
+}
+
+impl ClockAlignedAverager {
+    /// `period` must be non-zero. Buckets start at multiples of `period`
+    /// since the Unix epoch.
+    pub fn new(period: Duration) -> Self {
+        assert!(period > Duration::from_secs(0), "period must be non-zero");
+        ClockAlignedAverager {
+            period,
+            bucket_start: None,
+            sum: 0.0,
+            samples: 0,
+            out_of_order: OutOfOrder::default(),
+            latest: None,
+            drops: 0,
+            flushed: false,
+        }
+    }
+
+    pub fn hourly() -> Self {
+        Self::new(Duration::from_secs(3600))
+    }
+
+    pub fn with_out_of_order(mut self, out_of_order: OutOfOrder) -> Self {
+        self.out_of_order = out_of_order;
+        self
+    }
+
+    /// Number of readings dropped for being out of order.
+    pub fn out_of_order_drops(&self) -> u64 {
+        self.drops
+    }
+
+    fn bucket_of(&self, at: SystemTime) -> SystemTime {
+        let since_epoch = at.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let period = self.period.as_nanos();
+        let start = since_epoch.as_nanos() / period * period;
+        UNIX_EPOCH + Duration::from_nanos(start as u64)
+    }
+
+    /// Applies the `OutOfOrder` policy, returning false if the reading should
+    /// be dropped.
+    fn admit(&mut self, at: SystemTime) -> bool {
+        let late = match self.out_of_order {
+            OutOfOrder::Accept => false,
+            OutOfOrder::Reject => self.latest.is_some_and(|latest| at < latest),
+            OutOfOrder::Reorder => self
+                .bucket_start
+                .is_some_and(|start| self.bucket_of(at) < start),
+        };
+        if late {
+            self.drops += 1;
+            return false;
+        }
+        self.latest = self.latest.max(Some(at));
+        true
+    }
+
+    /// Adds a reading taken at `at`. When `at` falls in a later period than
+    /// the previous readings, returns the average of the completed period.
+    pub fn push(&mut self, at: SystemTime, value: f64) -> Option<BucketAverage> {
+        if !self.admit(at) {
+            return None;
+        }
+        self.push_admitted(at, value)
+    }
+
+    fn push_admitted(&mut self, at: SystemTime, value: f64) -> Option<BucketAverage> {
+        let bucket = self.bucket_of(at);
+        let completed = match self.bucket_start {
+            Some(start) if start != bucket => {
+                let completed = self.take(start);
+                self.flushed = false;
+                completed
+            }
+            _ => None,
+        };
+        self.bucket_start = Some(bucket);
+        self.sum += value;
+        self.samples += 1;
+        completed
+    }
+
+    fn take(&mut self, start: SystemTime) -> Option<BucketAverage> {
+        if self.samples == 0 {
+            return None;
+        }
+        let average = BucketAverage {
+            start,
+            end: start + self.period,
+            mean: self.sum / self.samples as f64,
+            samples: self.samples,
+            partial: self.flushed,
+        };
+        self.sum = 0.0;
+        self.samples = 0;
+        Some(average)
+    }
+
+    /// Returns the average of the period in progress, flagged as partial,
+    /// so that it isn't lost on shutdown. Later readings in the same period
+    /// start a new average, which is flagged as partial too.
+    pub fn flush(&mut self) -> Option<BucketAverage> {
+        let start = self.bucket_start?;
+        self.flushed = true;
+        self.take(start)
+    }
+}
+
+/// A statistic over completed periods, computed by `flush` with the period
+/// in progress included.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Flushed {
+    pub value: f64,
+    /// Set if any period it covers was cut short, as with
+    /// `BucketAverage::partial`.
+    pub partial: bool,
+}
+
+/// Cuts the period in progress short for the `flush` of a tracker built on
+/// completed periods. One that is already over at `now`, since no reading
+/// completed it, is complete.
+fn flush_bucket(buckets: &mut ClockAlignedAverager, now: SystemTime) -> Option<BucketAverage> {
+    let mut bucket = buckets.flush()?;
+    bucket.partial = now < bucket.end;
+    Some(bucket)
+}
+
+/// Appends `bucket` to `means`, merging it into the last one if it is the
+/// rest of a period that was flushed. `partial` says whether the merged
+/// period is still cut short; otherwise `bucket` keeps its own flag.
+fn push_bucket(means: &mut VecDeque<BucketAverage>, bucket: BucketAverage, partial: bool) {
+    match means.back_mut() {
+        Some(last) if last.start == bucket.start => {
+            let samples = last.samples + bucket.samples;
+            last.mean = (last.mean * last.samples as f64 + bucket.mean * bucket.samples as f64)
+                / samples as f64;
+            last.samples = samples;
+            last.partial = partial;
+        }
+        _ => means.push_back(bucket),
+    }
+}
+
+/// Like `ClockAlignedAverager`, but averages every concentration and count in
+/// a reading, e.g. to export one reading per minute while logging every
+/// frame.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct ReadingAverager {
+    buckets: ClockAlignedAverager,
+    sums: [f64; 14],
+    latest: Option<PmsData>,
+}
+
+impl ReadingAverager {
+    /// `period` must be non-zero.
+    pub fn new(period: Duration) -> Self {
+        ReadingAverager {
+            buckets: ClockAlignedAverager::new(period),
+            sums: [0.0; 14],
+            latest: None,
+        }
+    }
+
+    pub fn with_out_of_order(mut self, out_of_order: OutOfOrder) -> Self {
+        self.buckets = self.buckets.with_out_of_order(out_of_order);
+        self
+    }
+
+    /// Number of readings dropped for being out of order.
+    pub fn out_of_order_drops(&self) -> u64 {
+        self.buckets.out_of_order_drops()
+    }
+
+    /// Adds a reading taken at `at`. When `at` falls in a later period than
+    /// the previous readings, returns a reading holding the rounded means of
+    /// the completed period. The frame length and reserved word are taken
+    /// from the last reading in the period.
+    pub fn push(&mut self, at: SystemTime, data: &PmsData) -> Option<PmsData> {
+        self.push_flagged(at, data).map(|(mean, _)| mean)
+    }
+
+    /// Like `push`, but also returns whether the completed period was cut
+    /// short by `flush`, as with `BucketAverage::partial`.
+    pub fn push_flagged(&mut self, at: SystemTime, data: &PmsData) -> Option<(PmsData, bool)> {
+        if !self.buckets.admit(at) {
+            return None;
+        }
+        let completed = self
+            .buckets
+            .push_admitted(at, 0.0)
+            .and_then(|bucket| Some((self.take(bucket.samples)?, bucket.partial)));
+        for (sum, word) in self.sums.iter_mut().zip(data.words().iter()) {
+            *sum += f64::from(*word);
+        }
+        self.latest = Some(data.clone());
+        completed
+    }
+
+    /// Like `ClockAlignedAverager::flush`: returns the means of the period
+    /// in progress, which cover only part of it. The rest of the period is
+    /// flagged as partial by `push_flagged`.
+    pub fn flush(&mut self) -> Option<PmsData> {
+        let bucket = self.buckets.flush()?;
+        self.take(bucket.samples)
+    }
+
+    fn take(&mut self, samples: usize) -> Option<PmsData> {
+        let latest = self.latest.take()?;
+        let mut words = latest.words();
+        for (word, sum) in words.iter_mut().zip(&self.sums).skip(1).take(12) {
+            *word = (sum / samples as f64).round() as u16;
+        }
+        self.sums = [0.0; 14];
+        Some(PmsData::from_words(words))
+    }
+}
+
+/// Rate of change of the six particle counts between consecutive readings,
+/// in particles per second, in the order of `PmsData`'s count fields.
+#[derive(Debug, Clone, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct CountRate {
+    previous: Option<(SystemTime, [u16; 6])>,
+}
+
+impl CountRate {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a reading taken at `at` and returns the rate of each count since
+    /// the previous reading. A rate is `None` if the count went down, e.g.
+    /// after the sensor reset, and all are `None` for the first reading or if
+    /// no time has passed.
+    pub fn push(&mut self, at: SystemTime, data: &PmsData) -> [Option<f64>; 6] {
+        let counts = [
+            data.pm0_3_count,
+            data.pm0_5_count,
+            data.pm1_0_count,
+            data.pm2_5_count,
+            data.pm5_0_count,
+            data.pm10_0_count,
+        ];
+        let mut rates = [None; 6];
+        if let Some((previous_at, previous)) = self.previous {
+            let elapsed = at.duration_since(previous_at).unwrap_or_default();
+            if elapsed > Duration::from_secs(0) {
+                for (rate, (&count, &previous)) in
+                    rates.iter_mut().zip(counts.iter().zip(&previous))
+                {
+                    *rate = count
+                        .checked_sub(previous)
+                        .map(|delta| f64::from(delta) / elapsed.as_secs_f64());
+                }
+            }
+        }
+        self.previous = Some((at, counts));
+        rates
+    }
+}
+
+/// Mean over a long sliding `window`, e.g. 24 hours, kept small by storing
+/// one mean per clock-aligned `resolution` period instead of every reading.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct DownsampledWindow {
+    window: Duration,
+    buckets: ClockAlignedAverager,
+    means: VecDeque<BucketAverage>,
+}
+
+impl DownsampledWindow {
+    /// `resolution` must be non-zero.
+    pub fn new(window: Duration, resolution: Duration) -> Self {
+        DownsampledWindow {
+            window,
+            buckets: ClockAlignedAverager::new(resolution),
+            means: VecDeque::new(),
+        }
+    }
+
+    /// Adds a reading taken at `at`. It is included in `mean` once its
+    /// period is completed.
+    pub fn push(&mut self, at: SystemTime, value: f64) {
+        if let Some(completed) = self.buckets.push(at, value) {
+            self.add(completed, false);
+        }
+    }
+
+    /// Includes the period in progress, flagged as partial, e.g. on
+    /// shutdown, and returns the resulting `mean`.
+    pub fn flush(&mut self, now: SystemTime) -> Option<Flushed> {
+        if let Some(bucket) = flush_bucket(&mut self.buckets, now) {
+            let partial = bucket.partial;
+            self.add(bucket, partial);
+        }
+        Some(Flushed {
+            value: self.mean()?,
+            partial: self.means.iter().any(|bucket| bucket.partial),
+        })
+    }
+
+    fn add(&mut self, bucket: BucketAverage, partial: bool) {
+        let end = bucket.end;
+        push_bucket(&mut self.means, bucket, partial);
+        while let Some(oldest) = self.means.front() {
+            match end.duration_since(oldest.start) {
+                Ok(age) if age > self.window => {
+                    self.means.pop_front();
+                }
+                _ => break,
+            }
+        }
+    }
+
+    /// Mean of the completed periods in the window, each weighted equally.
+    pub fn mean(&self) -> Option<f64> {
+        if self.means.is_empty() {
+            return None;
+        }
+        let sum: f64 = self.means.iter().map(|bucket| bucket.mean).sum();
+        Some(sum / self.means.len() as f64)
+    }
+
+    /// Number of completed periods in the window.
+    pub fn len(&self) -> usize {
+        self.means.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.means.is_empty()
+    }
+}
+
+const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Direction of a `TrendTracker`'s readings. Higher concentrations are
+/// worse.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Trend {
+    Improving,
+    Worsening,
+    Stable,
+}
+
+/// The trend of the daily means (UTC days) over the last `days` completed
+/// days, from the slope of a least-squares line through them.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct TrendTracker {
+    days: usize,
+    stable_slope: f64,
+    buckets: ClockAlignedAverager,
+    means: VecDeque<BucketAverage>,
+}
+
+impl TrendTracker {
+    /// Slopes within this of zero, in units per day, are `Trend::Stable` by
+    /// default. For PM2.5, a change of 7 µg/m³ over a week.
+    pub const DEFAULT_STABLE_SLOPE: f64 = 1.0;
+
+    /// `days` must be at least 2.
+    pub fn new(days: usize) -> Self {
+        assert!(days >= 2, "days must be at least 2");
+        TrendTracker {
+            days,
+            stable_slope: Self::DEFAULT_STABLE_SLOPE,
+            buckets: ClockAlignedAverager::new(DAY),
+            means: VecDeque::with_capacity(days),
+        }
+    }
+
+    pub fn with_stable_slope(mut self, stable_slope: f64) -> Self {
+        self.stable_slope = stable_slope;
+        self
+    }
+
+    /// Adds a reading taken at `at`. It is included in the trend once its day
+    /// is completed.
+    pub fn push(&mut self, at: SystemTime, value: f64) {
+        if let Some(completed) = self.buckets.push(at, value) {
+            self.add(completed, false);
+        }
+    }
+
+    /// Includes the day in progress, flagged as partial, e.g. on shutdown,
+    /// and returns the resulting `slope`.
+    pub fn flush(&mut self, now: SystemTime) -> Option<Flushed> {
+        if let Some(bucket) = flush_bucket(&mut self.buckets, now) {
+            let partial = bucket.partial;
+            self.add(bucket, partial);
+        }
+        Some(Flushed {
+            value: self.slope()?,
+            partial: self.means.iter().any(|bucket| bucket.partial),
+        })
+    }
+
+    fn add(&mut self, bucket: BucketAverage, partial: bool) {
+        push_bucket(&mut self.means, bucket, partial);
+        if self.means.len() > self.days {
+            self.means.pop_front();
+        }
+    }
+
+    /// Change of the daily mean per day, or `None` until two days are
+    /// completed. Days without readings are left out of the fit.
+    pub fn slope(&self) -> Option<f64> {
+        if self.means.len() < 2 {
+            return None;
+        }
+        let first = self.means.front()?.start;
+        let points: Vec<(f64, f64)> = self
+            .means
+            .iter()
+            .map(|bucket| {
+                let day = bucket.start.duration_since(first).unwrap_or_default();
+                (day.as_secs_f64() / DAY.as_secs_f64(), bucket.mean)
+            })
+            .collect();
+        let n = points.len() as f64;
+        let mean_x = points.iter().map(|&(x, _)| x).sum::<f64>() / n;
+        let mean_y = points.iter().map(|&(_, y)| y).sum::<f64>() / n;
+        let covariance: f64 = points
+            .iter()
+            .map(|&(x, y)| (x - mean_x) * (y - mean_y))
+            .sum();
+        let variance: f64 = points.iter().map(|&(x, _)| (x - mean_x).powi(2)).sum();
+        Some(covariance / variance)
+    }
+
+    pub fn trend(&self) -> Option<Trend> {
+        self.slope().map(|slope| {
+            if slope > self.stable_slope {
+                Trend::Worsening
+            } else if slope < -self.stable_slope {
+                Trend::Improving
+            } else {
+                Trend::Stable
+            }
+        })
+    }
+
+    /// Number of completed days in the fit.
+    pub fn len(&self) -> usize {
+        self.means.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.means.is_empty()
+    }
+}
+
+/// Timestamped readings from the last `window`, oldest first unless the
+/// `OutOfOrder` policy is `Accept`.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+struct TimedSamples {
+    window: Duration,
+    samples: VecDeque<(SystemTime, f64)>,
+    out_of_order: OutOfOrder,
+    drops: u64,
+}
+
+impl TimedSamples {
+    fn new(window: Duration) -> Self {
+        TimedSamples {
+            window,
+            samples: VecDeque::new(),
+            out_of_order: OutOfOrder::default(),
+            drops: 0,
+        }
+    }
+
+    /// Adds a reading taken at `at` and drops readings older than the window.
+    fn push(&mut self, at: SystemTime, value: f64) {
+        if self.insert(at, value) {
+            self.evict(|_, _| ());
+        }
+    }
+
+    /// Adds a reading according to the `OutOfOrder` policy, returning false
+    /// if it was dropped.
+    fn insert(&mut self, at: SystemTime, value: f64) -> bool {
+        match (self.samples.back(), self.out_of_order) {
+            (Some(&(latest, _)), OutOfOrder::Reject) if at < latest => {
+                self.drops += 1;
+                return false;
+            }
+            (Some(&(latest, _)), OutOfOrder::Reorder) if at < latest => {
+                let index = self.samples.partition_point(|&(t, _)| t <= at);
+                self.samples.insert(index, (at, value));
+            }
+            _ => self.samples.push_back((at, value)),
+        }
+        true
+    }
+
+    /// Drops readings more than the window older than the newest one,
+    /// passing each to `on_evict` with the number of readings left.
+    fn evict(&mut self, mut on_evict: impl FnMut(f64, usize)) {
+        let Some(&(newest, _)) = self.samples.back() else {
+            return;
+        };
+        while let Some(&(oldest, value)) = self.samples.front() {
+            match newest.duration_since(oldest) {
+                Ok(age) if age > self.window => {
+                    self.samples.pop_front();
+                    on_evict(value, self.samples.len());
+                }
+                _ => break,
+            }
+        }
+    }
+}
+
+/// Keeps the readings from the last `window` and computes percentiles over
+/// them.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct PercentileWindow {
+    samples: TimedSamples,
+}
+
+impl PercentileWindow {
+    pub fn new(window: Duration) -> Self {
+        PercentileWindow {
+            samples: TimedSamples::new(window),
+        }
+    }
+
+    pub fn with_out_of_order(mut self, out_of_order: OutOfOrder) -> Self {
+        self.samples.out_of_order = out_of_order;
+        self
+    }
+
+    /// Number of readings dropped for being out of order.
+    pub fn out_of_order_drops(&self) -> u64 {
+        self.samples.drops
+    }
+
+    /// Adds a reading taken at `at` and drops readings older than the window.
+    pub fn push(&mut self, at: SystemTime, value: f64) {
+        self.samples.push(at, value);
+    }
+
+    pub fn len(&self) -> usize {
+        self.samples.samples.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.samples.samples.is_empty()
+    }
+
+    /// Nearest-rank percentile, with `quantile` in `[0, 1]` (e.g. 0.95).
+    /// Returns `None` if there are no readings in the window.
+    pub fn percentile(&self, quantile: f64) -> Option<f64> {
+        let mut values: Vec<f64> = self
+            .samples
+            .samples
+            .iter()
+            .map(|&(_, value)| value)
+            .collect();
+        if values.is_empty() {
+            return None;
+        }
+        values.sort_by(f64::total_cmp);
+        let rank = (quantile.clamp(0.0, 1.0) * values.len() as f64).ceil() as usize;
+        Some(values[rank.saturating_sub(1)])
+    }
+}
+
+/// Averages the readings from the last `window`, weighting each reading by
+/// the time until the next one. Unlike a plain mean, this isn't biased
+/// towards periods where readings arrive more often.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct TimeWeightedAverager {
+    samples: TimedSamples,
+}
+
+impl TimeWeightedAverager {
+    pub fn new(window: Duration) -> Self {
+        TimeWeightedAverager {
+            samples: TimedSamples::new(window),
+        }
+    }
+
+    pub fn with_out_of_order(mut self, out_of_order: OutOfOrder) -> Self {
+        self.samples.out_of_order = out_of_order;
+        self
+    }
+
+    /// Number of readings dropped for being out of order.
+    pub fn out_of_order_drops(&self) -> u64 {
+        self.samples.drops
+    }
+
+    /// Adds a reading taken at `at` and drops readings older than the window.
+    pub fn push(&mut self, at: SystemTime, value: f64) {
+        self.samples.push(at, value);
+    }
+
+    /// Returns `None` until two readings at different times are in the
+    /// window. The most recent reading carries no weight yet, since it isn't
+    /// known how long it lasts.
+    pub fn average(&self) -> Option<f64> {
+        let samples = &self.samples.samples;
+        let mut weighted_sum = 0.0;
+        let mut total = 0.0;
+        for (&(start, value), &(end, _)) in samples.iter().zip(samples.iter().skip(1)) {
+            let weight = end.duration_since(start).unwrap_or_default().as_secs_f64();
+            weighted_sum += value * weight;
+            total += weight;
+        }
+        if total > 0.0 {
+            Some(weighted_sum / total)
+        } else {
+            None
+        }
+    }
+}
+
+/// Standard deviation of the readings from the last `window`, each weighted
+/// equally, kept up to date with Welford's algorithm as readings enter and
+/// leave the window, so that it is numerically stable even for long windows
+/// of large, nearly equal values.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct RollingStdDev {
+    samples: TimedSamples,
+    mean: f64,
+    /// Sum of squared differences from `mean`.
+    m2: f64,
+}
+
+impl RollingStdDev {
+    pub fn new(window: Duration) -> Self {
+        RollingStdDev {
+            samples: TimedSamples::new(window),
+            mean: 0.0,
+            m2: 0.0,
+        }
+    }
+
+    pub fn with_out_of_order(mut self, out_of_order: OutOfOrder) -> Self {
+        self.samples.out_of_order = out_of_order;
+        self
+    }
+
+    /// Number of readings dropped for being out of order.
+    pub fn out_of_order_drops(&self) -> u64 {
+        self.samples.drops
+    }
+
+    /// Adds a reading taken at `at` and drops readings more than the window
+    /// older than the newest one.
+    pub fn push(&mut self, at: SystemTime, value: f64) {
+        if !self.samples.insert(at, value) {
+            return;
+        }
+        // The sums don't depend on the order of the readings.
+        let n = self.samples.samples.len() as f64;
+        let delta = value - self.mean;
+        self.mean += delta / n;
+        self.m2 += delta * (value - self.mean);
+        let RollingStdDev { samples, mean, m2 } = self;
+        samples.evict(|value, n| {
+            if n == 0 {
+                *mean = 0.0;
+                *m2 = 0.0;
+                return;
+            }
+            let delta = value - *mean;
+            *mean -= delta / n as f64;
+            // Rounding can leave it slightly negative.
+            *m2 = (*m2 - delta * (value - *mean)).max(0.0);
+        });
+    }
+
+    /// Mean of the readings in the window.
+    pub fn mean(&self) -> Option<f64> {
+        (!self.is_empty()).then_some(self.mean)
+    }
+
+    /// Population standard deviation of the readings in the window, 0 for a
+    /// single reading.
+    pub fn std_dev(&self) -> Option<f64> {
+        let n = self.len();
+        (n > 0).then(|| (self.m2 / n as f64).sqrt())
+    }
+
+    pub fn len(&self) -> usize {
+        self.samples.samples.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.samples.samples.is_empty()
+    }
+}
+
+/// Cumulative exposure: the concentration integrated over time, in
+/// µg·min/m³.
+///
+/// Each reading is taken to last until the next one, but for at most
+/// `max_gap`, so that an outage doesn't count as a long exposure to the
+/// reading before it.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct DoseTracker {
+    max_gap: Duration,
+    last: Option<(SystemTime, f64)>,
+    dose: f64,
+}
+
+impl DoseTracker {
+    pub fn new(max_gap: Duration) -> Self {
+        DoseTracker {
+            max_gap,
+            last: None,
+            dose: 0.0,
+        }
+    }
+
+    /// Adds a reading taken at `at`, returning the dose attributed to the
+    /// previous reading. Out of order readings add nothing.
+    pub fn push(&mut self, at: SystemTime, value: f64) -> f64 {
+        let added = match self.last {
+            Some((last_at, last_value)) => {
+                let elapsed = at.duration_since(last_at).unwrap_or_default();
+                last_value * elapsed.min(self.max_gap).as_secs_f64() / 60.0
+            }
+            None => 0.0,
+        };
+        if self.last.is_none_or(|(last_at, _)| at >= last_at) {
+            self.last = Some((at, value));
+        }
+        self.dose += added;
+        added
+    }
+
+    /// Attributes the dose of the last reading up to `at`, e.g. on shutdown,
+    /// and returns it. A later reading only adds the dose from `at` on.
+    pub fn flush(&mut self, at: SystemTime) -> f64 {
+        match self.last {
+            Some((_, value)) => self.push(at, value),
+            None => 0.0,
+        }
+    }
+
+    pub fn cumulative_dose(&self) -> f64 {
+        self.dose
+    }
+}
+
+/// The last `capacity` readings, for snapshots from other threads, e.g.
+/// for a sparkline or a quick export. Clones share the same readings, so
+/// one can be fed by the read loop, e.g. through `ring_buffer_callback`,
+/// while another is read elsewhere.
+#[derive(Debug, Clone)]
+pub struct RingBuffer {
+    capacity: usize,
+    readings: Arc<Mutex<VecDeque<(SystemTime, PmsData)>>>,
+}
+
+impl RingBuffer {
+    /// `capacity` must be non-zero.
+    pub fn new(capacity: usize) -> Self {
+        assert!(capacity > 0, "capacity must be non-zero");
+        RingBuffer {
+            capacity,
+            readings: Arc::new(Mutex::new(VecDeque::with_capacity(capacity))),
+        }
+    }
+
+    /// Adds a reading taken at `at`, dropping the oldest one if full.
+    pub fn push(&self, at: SystemTime, data: PmsData) {
+        let mut readings = self.readings.lock().unwrap();
+        if readings.len() == self.capacity {
+            readings.pop_front();
+        }
+        readings.push_back((at, data));
+    }
+
+    /// A copy of the readings, oldest first. The lock is only held for the
+    /// copy, so taking a snapshot doesn't hold up the read loop for long.
+    pub fn snapshot(&self) -> Vec<(SystemTime, PmsData)> {
+        self.readings.lock().unwrap().iter().cloned().collect()
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn len(&self) -> usize {
+        self.readings.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::at;
+
+    #[test]
+    fn test_averager() {
+        let mut averager = Averager::new(3);
+        assert_eq!(averager.mean(), None);
+        assert_eq!(averager.samples_remaining(), 3);
+        averager.push(1.0);
+        averager.push(2.0);
+        assert!(!averager.is_warm());
+        assert_eq!(averager.samples_remaining(), 1);
+        assert_eq!(averager.mean(), Some(1.5));
+        averager.push(3.0);
+        averager.push(7.0);
+        assert!(averager.is_warm());
+        assert_eq!(averager.samples_remaining(), 0);
+        assert_eq!(averager.mean(), Some(4.0));
+    }
+
+    #[test]
+    fn test_clock_aligned_averager() {
+        let mut averager = ClockAlignedAverager::hourly();
+        assert_eq!(averager.push(at(9 * 3600 + 10), 2.0), None);
+        assert_eq!(averager.push(at(9 * 3600 + 1800), 4.0), None);
+        assert_eq!(averager.push(at(10 * 3600 - 1), 6.0), None);
+        assert_eq!(
+            averager.push(at(10 * 3600), 100.0),
+            Some(BucketAverage {
+                start: at(9 * 3600),
+                end: at(10 * 3600),
+                mean: 4.0,
+                samples: 3,
+                partial: false,
+            })
+        );
+        averager.push(at(10 * 3600 + 60), 200.0);
+        assert_eq!(
+            averager.flush(),
+            Some(BucketAverage {
+                start: at(10 * 3600),
+                end: at(11 * 3600),
+                mean: 150.0,
+                samples: 2,
+                partial: true,
+            })
+        );
+        assert_eq!(averager.flush(), None);
+        assert_eq!(averager.push(at(10 * 3600 + 120), 1.0), None);
+        let rest = averager.push(at(11 * 3600), 1.0).unwrap();
+        assert_eq!((rest.samples, rest.partial), (1, true));
+        // Periods after it are complete again.
+        assert!(!averager.push(at(12 * 3600), 1.0).unwrap().partial);
+    }
+
+    #[test]
+    fn test_clock_aligned_averager_skips_empty_periods() {
+        let mut averager = ClockAlignedAverager::new(Duration::from_secs(60));
+        averager.push(at(30), 1.0);
+        let completed = averager.push(at(300), 5.0).unwrap();
+        assert_eq!(completed.start, at(0));
+        assert_eq!(completed.mean, 1.0);
+        assert_eq!(averager.push(at(359), 7.0), None);
+    }
+
+    #[test]
+    fn test_reading_averager() {
+        let data = |pm2_5| PmsData {
+            pm2_5_cf1: pm2_5,
+            pm0_3_count: pm2_5 * 10,
+            ..PmsData::from_words([28, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x9700])
+        };
+        let mut averager = ReadingAverager::new(Duration::from_secs(60));
+        assert_eq!(averager.push(at(0), &data(1)), None);
+        assert_eq!(averager.push(at(20), &data(2)), None);
+        assert_eq!(averager.push(at(40), &data(4)), None);
+        let mean = averager.push(at(60), &data(100)).unwrap();
+        assert_eq!(mean.pm2_5_cf1, 2);
+        assert_eq!(mean.pm0_3_count, 23);
+        assert_eq!(mean.pm10_cf1, 0);
+        assert_eq!(mean.version(), Some(0x97));
+        assert!(mean.has_valid_checksum());
+
+        averager.push(at(90), &data(300));
+        assert_eq!(averager.flush().unwrap().pm2_5_cf1, 200);
+        assert_eq!(averager.flush(), None);
+        averager.push(at(100), &data(5));
+        let (mean, partial) = averager.push_flagged(at(120), &data(7)).unwrap();
+        assert_eq!((mean.pm2_5_cf1, partial), (5, true));
+        let (mean, partial) = averager.push_flagged(at(180), &data(0)).unwrap();
+        assert_eq!((mean.pm2_5_cf1, partial), (7, false));
+    }
+
+    #[test]
+    fn test_downsampled_window() {
+        let mut window = DownsampledWindow::new(Duration::from_secs(180), Duration::from_secs(60));
+        window.push(at(0), 1.0);
+        window.push(at(30), 3.0);
+        assert_eq!(window.mean(), None);
+        window.push(at(60), 10.0);
+        assert_eq!(window.mean(), Some(2.0));
+        window.push(at(120), 30.0);
+        window.push(at(180), 0.0);
+        assert_eq!(window.len(), 3);
+        assert_eq!(window.mean(), Some(14.0));
+        window.push(at(240), 0.0);
+        assert_eq!(window.len(), 3);
+        assert_eq!(window.mean(), Some(40.0 / 3.0));
+    }
+
+    #[test]
+    fn test_downsampled_window_flush() {
+        let mut window = DownsampledWindow::new(Duration::from_secs(180), Duration::from_secs(60));
+        assert_eq!(window.flush(at(0)), None);
+        window.push(at(0), 1.0);
+        window.push(at(30), 3.0);
+        assert_eq!(
+            window.flush(at(45)),
+            Some(Flushed {
+                value: 2.0,
+                partial: true
+            })
+        );
+        // The rest of the minute completes it.
+        window.push(at(50), 5.0);
+        window.push(at(60), 10.0);
+        assert_eq!(window.len(), 1);
+        assert_eq!(window.mean(), Some(3.0));
+        // A minute that is over by the time of the flush is complete.
+        assert_eq!(
+            window.flush(at(120)),
+            Some(Flushed {
+                value: 6.5,
+                partial: false
+            })
+        );
+    }
+
+    #[test]
+    fn test_trend_tracker() {
+        let day = |day: u64, hour: u64| at(day * 86400 + hour * 3600);
+        let mut tracker = TrendTracker::new(7);
+        tracker.push(day(0, 12), 20.0);
+        tracker.push(day(1, 12), 20.0);
+        assert_eq!(tracker.trend(), None);
+        tracker.push(day(2, 0), 20.5);
+        assert_eq!(tracker.slope(), Some(0.0));
+        assert_eq!(tracker.trend(), Some(Trend::Stable));
+
+        // Falling by 3 a day, with a day missing.
+        let mut tracker = TrendTracker::new(3);
+        for (d, value) in [(0, 50.0), (1, 30.0), (2, 27.0), (3, 24.0), (5, 18.0)] {
+            tracker.push(day(d, 6), value);
+            tracker.push(day(d, 18), value);
+        }
+        tracker.push(day(6, 0), 0.0);
+        // Only the last 3 days are kept.
+        assert_eq!(tracker.len(), 3);
+        assert!((tracker.slope().unwrap() + 3.0).abs() < 1e-9);
+        assert_eq!(tracker.trend(), Some(Trend::Improving));
+
+        let mut tracker = TrendTracker::new(2).with_stable_slope(5.0);
+        tracker.push(day(0, 0), 10.0);
+        tracker.push(day(1, 0), 14.0);
+        tracker.push(day(2, 0), 0.0);
+        assert_eq!(tracker.trend(), Some(Trend::Stable));
+        tracker.push(day(3, 0), 0.0);
+        assert_eq!(tracker.trend(), Some(Trend::Improving));
+    }
+
+    #[test]
+    fn test_trend_tracker_flush() {
+        let day = |day: u64, hour: u64| at(day * 86400 + hour * 3600);
+        let mut tracker = TrendTracker::new(7);
+        tracker.push(day(0, 12), 20.0);
+        assert_eq!(tracker.flush(day(1, 0)), None);
+        tracker.push(day(1, 6), 10.0);
+        assert_eq!(
+            tracker.flush(day(1, 12)),
+            Some(Flushed {
+                value: -10.0,
+                partial: true
+            })
+        );
+        tracker.push(day(1, 18), 16.0);
+        tracker.push(day(2, 0), 0.0);
+        assert_eq!(tracker.len(), 2);
+        assert_eq!(tracker.slope(), Some(-7.0));
+    }
+
+    #[test]
+    fn test_count_rate() {
+        let data = |count| {
+            let mut words = [0; 13];
+            words[6] = count;
+            words[11] = 10;
+            PmsData::from(words)
+        };
+        let mut rate = CountRate::new();
+        assert_eq!(rate.push(at(0), &data(100)), [None; 6]);
+        let rates = rate.push(at(2), &data(300));
+        assert_eq!(rates[0], Some(100.0));
+        assert_eq!(rates[5], Some(0.0));
+        let rates = rate.push(at(3), &data(50));
+        assert_eq!(rates[0], None);
+        assert_eq!(rates[5], Some(0.0));
+        assert_eq!(rate.push(at(3), &data(60)), [None; 6]);
+    }
+
+    #[test]
+    fn test_percentile_window() {
+        let mut window = PercentileWindow::new(Duration::from_secs(100));
+        assert_eq!(window.percentile(0.5), None);
+        for i in 1..=100 {
+            window.push(at(i), i as f64);
+        }
+        assert_eq!(window.percentile(0.5), Some(50.0));
+        assert_eq!(window.percentile(0.95), Some(95.0));
+        assert_eq!(window.percentile(0.0), Some(1.0));
+        assert_eq!(window.percentile(1.0), Some(100.0));
+
+        // Readings older than the window are dropped.
+        window.push(at(150), 0.0);
+        assert_eq!(window.len(), 52);
+        assert_eq!(window.percentile(0.0), Some(0.0));
+    }
+
+    #[test]
+    fn test_out_of_order_clock_aligned() {
+        let mut rejecting = ClockAlignedAverager::new(Duration::from_secs(60))
+            .with_out_of_order(OutOfOrder::Reject);
+        let mut reordering = ClockAlignedAverager::new(Duration::from_secs(60))
+            .with_out_of_order(OutOfOrder::Reorder);
+        for averager in [&mut rejecting, &mut reordering] {
+            averager.push(at(10), 1.0);
+            averager.push(at(30), 2.0);
+            averager.push(at(20), 3.0);
+            averager.push(at(70), 0.0);
+            // Belongs to the completed period.
+            assert_eq!(averager.push(at(50), 100.0), None);
+        }
+        assert_eq!(rejecting.out_of_order_drops(), 2);
+        assert_eq!(rejecting.push(at(120), 0.0).unwrap().samples, 1);
+        assert_eq!(reordering.out_of_order_drops(), 1);
+    }
+
+    #[test]
+    fn test_out_of_order_window() {
+        let mut rejecting = TimeWeightedAverager::new(Duration::from_secs(3600))
+            .with_out_of_order(OutOfOrder::Reject);
+        let mut reordering = TimeWeightedAverager::new(Duration::from_secs(3600))
+            .with_out_of_order(OutOfOrder::Reorder);
+        for averager in [&mut rejecting, &mut reordering] {
+            averager.push(at(0), 10.0);
+            averager.push(at(100), 0.0);
+            averager.push(at(50), 20.0);
+        }
+        assert_eq!(rejecting.average(), Some(10.0));
+        assert_eq!(rejecting.out_of_order_drops(), 1);
+        assert_eq!(reordering.average(), Some(15.0));
+        assert_eq!(reordering.out_of_order_drops(), 0);
+
+        let mut window =
+            PercentileWindow::new(Duration::from_secs(100)).with_out_of_order(OutOfOrder::Reject);
+        window.push(at(10), 1.0);
+        window.push(at(5), 2.0);
+        assert_eq!(window.len(), 1);
+        assert_eq!(window.out_of_order_drops(), 1);
+    }
+
+    #[test]
+    fn test_time_weighted_averager() {
+        let mut averager = TimeWeightedAverager::new(Duration::from_secs(3600));
+        averager.push(at(0), 10.0);
+        assert_eq!(averager.average(), None);
+        // A brief spike to 100 barely moves the average.
+        averager.push(at(90), 100.0);
+        averager.push(at(100), 10.0);
+        averager.push(at(1000), 0.0);
+        assert_eq!(averager.average(), Some(10.9));
+
+        // Readings older than the window are dropped.
+        averager.push(at(3690), 0.0);
+        assert_eq!(averager.average(), Some(10000.0 / 3600.0));
+    }
+
+    #[test]
+    fn test_rolling_std_dev() {
+        let mut tracker = RollingStdDev::new(Duration::from_secs(60));
+        assert_eq!(tracker.std_dev(), None);
+        tracker.push(at(0), 10.0);
+        assert_eq!(tracker.std_dev(), Some(0.0));
+        for (i, value) in [2.0, 4.0, 4.0, 4.0, 5.0, 5.0, 7.0, 9.0].iter().enumerate() {
+            tracker.push(at(61 + i as u64), *value);
+        }
+        // The first reading has left the window.
+        assert_eq!(tracker.len(), 8);
+        assert_eq!(tracker.mean(), Some(5.0));
+        assert!((tracker.std_dev().unwrap() - 2.0).abs() < 1e-12);
+
+        // Large values close together, where the naive sum of squares loses
+        // all precision.
+        let mut tracker = RollingStdDev::new(Duration::from_secs(3600));
+        for i in 0..1000 {
+            tracker.push(at(i), 1e9 + (i % 2) as f64);
+        }
+        assert!((tracker.std_dev().unwrap() - 0.5).abs() < 1e-6);
+
+        tracker.push(at(10_000), 3.0);
+        assert_eq!(tracker.len(), 1);
+        assert_eq!(tracker.std_dev(), Some(0.0));
+    }
+
+    #[test]
+    fn test_rolling_std_dev_out_of_order() {
+        let mut tracker =
+            RollingStdDev::new(Duration::from_secs(60)).with_out_of_order(OutOfOrder::Reject);
+        tracker.push(at(100), 2.0);
+        tracker.push(at(50), 100.0);
+        assert_eq!(tracker.out_of_order_drops(), 1);
+        assert_eq!(tracker.mean(), Some(2.0));
+
+        let mut tracker =
+            RollingStdDev::new(Duration::from_secs(60)).with_out_of_order(OutOfOrder::Reorder);
+        tracker.push(at(100), 2.0);
+        tracker.push(at(140), 6.0);
+        tracker.push(at(120), 4.0);
+        // Too old for the window of the newest reading.
+        tracker.push(at(10), 100.0);
+        assert_eq!(tracker.len(), 3);
+        assert_eq!(tracker.mean(), Some(4.0));
+        tracker.push(at(170), 6.0);
+        // Only the reading at 100 has left the window.
+        assert_eq!(tracker.len(), 3);
+        assert!((tracker.mean().unwrap() - 16.0 / 3.0).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_dose_tracker() {
+        let mut dose = DoseTracker::new(Duration::from_secs(300));
+        assert_eq!(dose.push(at(0), 10.0), 0.0);
+        assert_eq!(dose.push(at(60), 20.0), 10.0);
+        assert_eq!(dose.push(at(90), 5.0), 10.0);
+        // An hour-long outage counts as five minutes.
+        assert_eq!(dose.push(at(3690), 0.0), 25.0);
+        assert_eq!(dose.push(at(100), 50.0), 0.0);
+        assert_eq!(dose.cumulative_dose(), 45.0);
+
+        assert_eq!(dose.flush(at(3720)), 0.0);
+        assert_eq!(dose.cumulative_dose(), 45.0);
+        dose.push(at(3780), 30.0);
+        assert_eq!(dose.flush(at(3810)), 15.0);
+        assert_eq!(dose.push(at(3840), 0.0), 15.0);
+        assert_eq!(dose.cumulative_dose(), 75.0);
+    }
+
+    #[test]
+    fn test_ring_buffer() {
+        let buffer = RingBuffer::new(3);
+        let reader = buffer.clone();
+        assert!(reader.is_empty());
+        for pm2_5 in 1..=5 {
+            let data = PmsData::from([0, pm2_5, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+            buffer.push(at(pm2_5.into()), data);
+        }
+        let snapshot = reader.snapshot();
+        assert_eq!(reader.len(), 3);
+        let readings: Vec<_> = snapshot
+            .iter()
+            .map(|(at, data)| (*at, data.pm2_5_cf1))
+            .collect();
+        assert_eq!(readings, [(at(3), 3), (at(4), 4), (at(5), 5)]);
+    }
+}